@@ -16,9 +16,17 @@ pub struct Assets {
     pub missile_tex: Rc<G2dTexture>,
     pub explode_tex: Rc<G2dTexture>,
     pub explode2_tex: Rc<G2dTexture>,
+    pub blood_tex: Rc<G2dTexture>,
     pub sphere_tex: Rc<G2dTexture>,
     pub power_tex: Rc<G2dTexture>,
     pub power2_tex: Rc<G2dTexture>,
+    pub bomb_tex: Rc<G2dTexture>,
+    pub hitbox_tex: Rc<G2dTexture>,
+    pub kamikaze_tex: Rc<G2dTexture>,
+    pub turret_tex: Rc<G2dTexture>,
+    pub splitter_tex: Rc<G2dTexture>,
+    pub mine_layer_tex: Rc<G2dTexture>,
+    pub mine_tex: Rc<G2dTexture>,
 }
 
 impl Assets {
@@ -39,7 +47,7 @@ impl Assets {
             Rc::new(
                 Texture::from_path(
                     &mut window.factory,
-                    &assets_loader.join(name),
+                    assets_loader.join(name),
                     Flip::None,
                     &TextureSettings::new(),
                 )
@@ -63,9 +71,17 @@ impl Assets {
                 missile_tex: load_texture("missile.png"),
                 explode_tex: load_texture("explode.png"),
                 explode2_tex: load_texture("explode2.png"),
+                blood_tex: load_texture("blood.png"),
                 sphere_tex: load_texture("sphere.png"),
                 power_tex: load_texture("power.png"),
                 power2_tex: load_texture("power2.png"),
+                bomb_tex: load_texture("bomb.png"),
+                hitbox_tex: load_texture("hitbox.png"),
+                kamikaze_tex: load_texture("kamikaze.png"),
+                turret_tex: load_texture("turret.png"),
+                splitter_tex: load_texture("splitter.png"),
+                mine_layer_tex: load_texture("mine-layer.png"),
+                mine_tex: load_texture("mine.png"),
             },
             glyphs,
         )
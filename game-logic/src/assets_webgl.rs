@@ -70,6 +70,13 @@ pub struct Assets {
     pub back_tex: Rc<WebGlTexture>,
     pub power_tex: Rc<WebGlTexture>,
     pub power2_tex: Rc<WebGlTexture>,
+    pub bomb_tex: Rc<WebGlTexture>,
+    pub hitbox_tex: Rc<WebGlTexture>,
+    pub kamikaze_tex: Rc<WebGlTexture>,
+    pub turret_tex: Rc<WebGlTexture>,
+    pub splitter_tex: Rc<WebGlTexture>,
+    pub mine_layer_tex: Rc<WebGlTexture>,
+    pub mine_tex: Rc<WebGlTexture>,
     pub sphere_tex: Rc<WebGlTexture>,
     pub weapons_tex: Rc<WebGlTexture>,
 
@@ -121,7 +128,7 @@ impl Assets {
             }) {
                 let array = js_sys::Array::from(&value).to_vec();
                 load_texture(
-                    &context,
+                    context,
                     &array
                         .get(1)
                         .ok_or_else(|| JsValue::from_str("Couldn't find texture"))?
@@ -162,6 +169,13 @@ impl Assets {
             back_tex: load_texture_local("back")?,
             power_tex: load_texture_local("power")?,
             power2_tex: load_texture_local("power2")?,
+            bomb_tex: load_texture_local("bomb")?,
+            hitbox_tex: load_texture_local("hitbox")?,
+            kamikaze_tex: load_texture_local("kamikaze")?,
+            turret_tex: load_texture_local("turret")?,
+            splitter_tex: load_texture_local("splitter")?,
+            mine_layer_tex: load_texture_local("mineLayer")?,
+            mine_tex: load_texture_local("mine")?,
             sphere_tex: load_texture_local("sphere")?,
             weapons_tex: load_texture_local("weapons")?,
             sprite_shader: None,
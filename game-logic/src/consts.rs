@@ -6,14 +6,52 @@ pub const FWIDTH: f64 = WIDTH as f64;
 pub const FHEIGHT: f64 = HEIGHT as f64;
 pub const SCREEN_RECT: [f64; 4] = [0., 0., FWIDTH, FHEIGHT];
 
+pub const DEFAULT_SEED: u32 = 3232132;
+/// Mixed into the date to derive the seed of the daily challenge
+pub const DAILY_SEED_SALT: u32 = 0x5eed_da17;
+/// Mixed into the seed to initialize the random number generator for visual effects
+pub const COSMETIC_SEED_SALT: u32 = 0xeffe_c7ed;
+
 pub const PLAYER_SPEED: f64 = 2.;
+pub const PLAYER_FOCUS_SPEED: f64 = 0.8;
+/// How much the spread of the bullets is narrowed in focus mode
+pub const FOCUS_BULLET_SPREAD: f64 = 0.4;
 pub const PLAYER_SIZE: f64 = 16.;
 pub const PLAYER_INVINCIBLE_TIME: u32 = 128;
 pub const PLAYER_LIVES: u32 = 3;
+/// The health of a life in HP mode. An enemy bullet takes away its own health.
+pub const PLAYER_HEALTH: i32 = 5;
+/// Damage per touch of an enemy body in HP mode
+pub const CONTACT_DAMAGE: i32 = 2;
+/// Invincibility time after losing some health in HP mode, so that a touching enemy does
+/// not drain it all at once
+pub const PLAYER_HIT_INVINCIBLE_TIME: u32 = 32;
+pub const PLAYER_BOMBS: u32 = 3;
+pub const MAX_BOMBS: u32 = 5;
+pub const BOMB_DAMAGE: i32 = 50;
+pub const BOMB_INVINCIBLE_TIME: u32 = 96;
+/// Frames after a kill until the chain starts to decay
+pub const CHAIN_TIME: u32 = 90;
+/// Kills in the chain that raise the score multiplier by one
+pub const CHAIN_STEP: u32 = 8;
+pub const MAX_MULTIPLIER: u32 = 8;
+/// Distance from the player within which an enemy bullet grazes it
+pub const GRAZE_DISTANCE: f64 = 32.;
+pub const GRAZE_SCORE: u32 = 1;
+/// A boss killed within this many frames from its spawn gives a bonus, which shrinks
+/// linearly from `BOSS_BONUS` over the time.
+pub const BOSS_BONUS_TIME: u32 = 1800;
+pub const BOSS_BONUS: u32 = 100;
+/// The difficulty level at which the rank of bullet patterns reaches the maximum
+pub const MAX_RANK_LEVEL: u32 = 16;
 pub const ENEMY_SIZE: f64 = 8.;
 pub const BOSS_SIZE: f64 = 16.;
 pub const CENTIPEDE_SIZE: f64 = 16.;
 pub const BULLET_SIZE: f64 = 8.;
+/// The radius of the hit circle of a round enemy bullet, smaller than its sprite
+pub const ENEMY_BULLET_RADIUS: f64 = 4.;
+/// The radius of the core of the player that enemy bullets have to hit
+pub const PLAYER_HITBOX_RADIUS: f64 = 2.;
 pub const LONG_BULLET_SIZE: [f64; 2] = [8., 4.];
 pub const BULLET_SPEED: f64 = 5.;
 pub const MISSILE_SPEED: f64 = 3.;
@@ -22,6 +60,8 @@ pub const EXPLODE_SIZE: f64 = 8.;
 pub const EXPLODE2_SIZE: f64 = 16.;
 pub const ITEM_SIZE: f64 = 6.;
 pub const ITEM2_SIZE: f64 = 12.;
+pub const BLOOD_SPEED: f64 = 2.;
+pub const BOMB_EFFECT_COUNT: usize = 16;
 
 pub const LIGHTNING_ACCEL: f64 = 8.0;
 pub const LIGHTNING_FEEDBACK: f64 = 0.1;
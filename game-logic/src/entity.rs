@@ -1,18 +1,33 @@
+mod behavior;
+mod collider;
 mod enemy;
 mod entity_id;
 mod entity_set;
+mod path;
 mod projectile;
 mod ref_option;
+mod spatial_grid;
 mod temp_entity;
 
 use core::f64;
 
 pub use self::{
-    enemy::{Enemy, EnemyBase, ShieldedBoss},
+    behavior::{Behavior, BehaviorAction, BehaviorRunner, BehaviorState, Condition, Transition},
+    collider::Collider,
+    enemy::{
+        BossPhase, CustomEnemy, Enemy, EnemyBase, EnemyBehavior, EnemyKind, EnemyRegistry, LoadFn,
+        PhaseBoss, ShieldedBoss, SpawnFn,
+    },
     entity_id::EntityId,
     entity_set::{EntityEntry, EntitySet},
-    projectile::{BulletBase, Projectile},
+    path::{Leg, Path, Route},
+    projectile::{
+        BulletBase, BulletKind, BulletML, BulletMLError, BulletMLRunner, BulletPattern, BulletSpec,
+        BulletSplit, PatternAction, PatternEmitter, PatternError, PatternLibrary, PatternRunner,
+        Projectile,
+    },
     ref_option::{RefMutOption, RefOption},
+    spatial_grid::SpatialGrid,
     temp_entity::{TempEntity, TempEntityType},
 };
 #[cfg(all(not(feature = "webgl"), feature = "piston"))]
@@ -29,14 +44,16 @@ use piston_window::{
     *,
 };
 use rotate_enum::RotateEnum;
+use serde::{Deserialize, Serialize};
 use std::ops::Deref;
 #[cfg(all(not(feature = "webgl"), feature = "piston"))]
 use std::ops::{Add, Mul};
-use vecmath::vec2_add;
+use vecmath::{vec2_add, vec2_len, vec2_scale};
 #[cfg(feature = "webgl")]
 use web_sys::{WebGlRenderingContext as GL, WebGlTexture};
 
 /// The base structure of all Entities.  Implements common methods.
+#[derive(Serialize, Deserialize)]
 pub struct Entity {
     pub pos: [f64; 2],
     pub velo: [f64; 2],
@@ -44,9 +61,34 @@ pub struct Entity {
     pub rotation: f32,
     pub angular_velocity: f32,
     #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    #[serde(with = "serde_blend")]
     pub blend: Option<Blend>,
 }
 
+/// Blend is an external type without serde support, so it is serialized as its
+/// discriminant.
+#[cfg(all(not(feature = "webgl"), feature = "piston"))]
+mod serde_blend {
+    use piston_window::draw_state::Blend;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(blend: &Option<Blend>, s: S) -> Result<S::Ok, S::Error> {
+        blend.map(|blend| blend as u8).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Blend>, D::Error> {
+        Option::<u8>::deserialize(d)?
+            .map(|v| match v {
+                0 => Ok(Blend::Alpha),
+                1 => Ok(Blend::Add),
+                2 => Ok(Blend::Multiply),
+                3 => Ok(Blend::Invert),
+                _ => Err(D::Error::custom("invalid blend mode")),
+            })
+            .transpose()
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum DeathReason {
     RangeOut,
@@ -171,19 +213,6 @@ impl Entity {
             g,
         );
     }
-
-    pub fn hits_player(&self, player: &Self) -> Option<DeathReason> {
-        let e = &player;
-        if self.pos[0] - BULLET_SIZE < e.pos[0] + ENEMY_SIZE
-            && e.pos[0] - ENEMY_SIZE < self.pos[0] + BULLET_SIZE
-            && self.pos[1] - BULLET_SIZE < e.pos[1] + ENEMY_SIZE
-            && e.pos[1] - ENEMY_SIZE < self.pos[1] + BULLET_SIZE
-        {
-            Some(DeathReason::HitPlayer)
-        } else {
-            None
-        }
-    }
 }
 
 #[cfg(feature = "webgl")]
@@ -196,7 +225,7 @@ pub fn draw_tex(
     scale: Option<[f64; 2]>,
 ) {
     let shader = assets.sprite_shader.as_ref().unwrap();
-    context.bind_texture(GL::TEXTURE_2D, Some(&texture));
+    context.bind_texture(GL::TEXTURE_2D, Some(texture));
     let translation = Matrix4::from_translation(Vector3::new(pos[0], pos[1], 0.));
     let rotation = Matrix4::from_angle_z(Rad(rotation));
     let scale = scale.unwrap_or([1., 1.]);
@@ -211,7 +240,7 @@ pub fn draw_tex(
     context.draw_arrays(GL::TRIANGLE_FAN, 0, 4);
 }
 
-#[derive(PartialEq, Clone, Copy, Debug, RotateEnum)]
+#[derive(PartialEq, Clone, Copy, Debug, RotateEnum, Serialize, Deserialize)]
 pub enum Weapon {
     Bullet,
     Light,
@@ -239,6 +268,27 @@ fn weapon_rotate() {
     }
 }
 
+#[test]
+fn player_movement() {
+    let mut player = Player::new(Entity::new([100., 100.], [0.; 2]));
+    player.move_toward([1., 1.]);
+    assert!(
+        (vec2_len(vecmath::vec2_sub(player.base.pos, [100., 100.])) - PLAYER_SPEED).abs() < 1e-9
+    );
+
+    player.focus = true;
+    let pos = player.base.pos;
+    player.move_toward([0., -1.]);
+    assert_eq!(player.base.pos, [pos[0], pos[1] - PLAYER_FOCUS_SPEED]);
+
+    // Stops at the border
+    player.focus = false;
+    for _ in 0..100 {
+        player.move_toward([-1., 0.]);
+    }
+    assert_eq!(player.base.pos[0], PLAYER_SIZE);
+}
+
 pub const WEAPON_SET: [(usize, Weapon, [f32; 3]); 4] = [
     (0, Weapon::Bullet, [1., 0.5, 0.]),
     (2, Weapon::Light, [1., 1., 1.]),
@@ -246,6 +296,7 @@ pub const WEAPON_SET: [(usize, Weapon, [f32; 3]); 4] = [
     (4, Weapon::Lightning, [1., 1., 0.]),
 ];
 
+#[derive(Serialize, Deserialize)]
 pub struct Player {
     pub base: Entity,
     pub score: u32,
@@ -256,6 +307,16 @@ pub struct Player {
     pub invtime: u32,
     pub weapon: Weapon,
     pub cooldown: u32,
+    /// Bombs left in stock. It is refilled when a life is lost.
+    pub bombs: u32,
+    /// Kills in the current chain, which raise the score multiplier
+    pub chain: u32,
+    /// Frames left until the chain decays
+    pub chain_time: u32,
+    /// Enemy bullets that passed close to the player
+    pub graze: u32,
+    /// Moving slowly to dodge precisely, with the hitbox shown
+    pub focus: bool,
 }
 
 impl Player {
@@ -269,40 +330,88 @@ impl Player {
             invtime: 0,
             weapon: Weapon::Bullet,
             cooldown: 0,
+            bombs: PLAYER_BOMBS,
+            chain: 0,
+            chain_time: 0,
+            graze: 0,
+            focus: false,
         }
     }
 
-    pub fn move_up(&mut self) {
-        if PLAYER_SIZE <= self.base.pos[1] - PLAYER_SPEED {
-            self.base.pos[1] -= PLAYER_SPEED;
-        }
-    }
-
-    pub fn move_down(&mut self) {
-        if self.base.pos[1] + PLAYER_SPEED < HEIGHT as f64 - PLAYER_SIZE {
-            self.base.pos[1] += PLAYER_SPEED;
-        }
-    }
-
-    pub fn move_left(&mut self) {
-        if PLAYER_SIZE <= self.base.pos[0] - PLAYER_SPEED {
-            self.base.pos[0] -= PLAYER_SPEED;
-        }
-    }
-
-    pub fn move_right(&mut self) {
-        if self.base.pos[0] + PLAYER_SPEED < WIDTH as f64 - PLAYER_SIZE {
-            self.base.pos[0] += PLAYER_SPEED;
+    /// Move toward `dir`, at the same speed in diagonals as along the axes. The speed is
+    /// lower in focus mode.
+    pub fn move_toward(&mut self, dir: [f64; 2]) {
+        let len = vec2_len(dir);
+        if len == 0. {
+            return;
         }
+        let speed = if self.focus {
+            PLAYER_FOCUS_SPEED
+        } else {
+            PLAYER_SPEED
+        };
+        let pos = vec2_add(self.base.pos, vec2_scale(dir, speed / len));
+        self.base.pos = [
+            pos[0].clamp(PLAYER_SIZE, WIDTH as f64 - PLAYER_SIZE),
+            pos[1].clamp(PLAYER_SIZE, HEIGHT as f64 - PLAYER_SIZE),
+        ];
     }
 
     pub fn reset(&mut self) {
         self.base.pos = [240., 400.];
+        self.base.velo = [0.; 2];
+        self.base.health = PLAYER_HEALTH;
         self.score = 0;
         self.kills = 0;
         self.power = 0;
         self.lives = PLAYER_LIVES;
         self.invtime = 0;
+        // A restarted game must not depend on the previous one, or replays would not
+        // play back the same.
+        self.weapon = Weapon::Bullet;
+        self.cooldown = 0;
+        self.bombs = PLAYER_BOMBS;
+        self.chain = 0;
+        self.chain_time = 0;
+        self.graze = 0;
+        self.focus = false;
+    }
+
+    pub fn multiplier(&self) -> u32 {
+        (1 + self.chain / CHAIN_STEP).min(MAX_MULTIPLIER)
+    }
+
+    /// Extend the chain with a kill and add its `points` times the multiplier to the score.
+    pub fn add_kill_score(&mut self, points: u32) {
+        self.chain += 1;
+        self.chain_time = CHAIN_TIME;
+        self.score += points * self.multiplier();
+    }
+
+    /// Count down the chain timer. Once it runs out, the chain loses half of its kills
+    /// every `CHAIN_TIME` frames until another kill.
+    pub fn decay_chain(&mut self) {
+        if 0 < self.chain_time {
+            self.chain_time -= 1;
+        } else if 0 < self.chain {
+            self.chain /= 2;
+            self.chain_time = CHAIN_TIME;
+        }
+    }
+
+    /// The tiny core that enemy bullets have to hit, much smaller than the sprite.
+    pub fn collider(&self) -> Collider {
+        Collider::circle(self.base.pos, PLAYER_HITBOX_RADIUS)
+    }
+
+    /// The area to pick up items with, which is the whole sprite.
+    pub fn pickup_collider(&self) -> Collider {
+        Collider::circle(self.base.pos, PLAYER_SIZE)
+    }
+
+    /// The remaining health of the life in HP mode in the range of 0 to 1.
+    pub fn health_ratio(&self) -> f64 {
+        self.base.health.max(0) as f64 / PLAYER_HEALTH as f64
     }
 
     pub fn power_level(&self) -> u32 {
@@ -312,18 +421,34 @@ impl Player {
     pub fn difficulty_level(&self) -> u32 {
         self.score / 256
     }
+
+    /// The difficulty from 0 to 1 for bullet patterns, which is the `$rank` of BulletML.
+    pub fn rank(&self) -> f64 {
+        (self.difficulty_level() as f64 / MAX_RANK_LEVEL as f64).min(1.)
+    }
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum Item {
     PowerUp(Entity),
     PowerUp10(Entity),
+    /// Adds a bomb to the stock
+    Bomb(Entity),
+}
+
+/// The type of an item, without its state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ItemKind {
+    PowerUp,
+    PowerUp10,
+    Bomb,
 }
 
 impl Deref for Item {
     type Target = Entity;
     fn deref(&self) -> &Entity {
         match self {
-            Item::PowerUp(ent) | Item::PowerUp10(ent) => ent,
+            Item::PowerUp(ent) | Item::PowerUp10(ent) | Item::Bomb(ent) => ent,
         }
     }
 }
@@ -333,11 +458,12 @@ impl Item {
     pub fn draw(&self, gl: &GL, assets: &Assets) {
         match self {
             Item::PowerUp(item) => {
-                item.draw_tex(&assets, gl, &assets.power_tex, Some([ITEM_SIZE; 2]))
+                item.draw_tex(assets, gl, &assets.power_tex, Some([ITEM_SIZE; 2]))
             }
             Item::PowerUp10(item) => {
-                item.draw_tex(&assets, gl, &assets.power2_tex, Some([ITEM2_SIZE; 2]))
+                item.draw_tex(assets, gl, &assets.power2_tex, Some([ITEM2_SIZE; 2]))
             }
+            Item::Bomb(item) => item.draw_tex(assets, gl, &assets.bomb_tex, Some([ITEM2_SIZE; 2])),
         }
     }
 
@@ -346,6 +472,15 @@ impl Item {
         match self {
             Item::PowerUp(item) => item.draw_tex(c, g, &assets.power_tex, None),
             Item::PowerUp10(item) => item.draw_tex(c, g, &assets.power2_tex, None),
+            Item::Bomb(item) => item.draw_tex(c, g, &assets.bomb_tex, None),
+        }
+    }
+
+    pub fn kind(&self) -> ItemKind {
+        match self {
+            Item::PowerUp(_) => ItemKind::PowerUp,
+            Item::PowerUp10(_) => ItemKind::PowerUp10,
+            Item::Bomb(_) => ItemKind::Bomb,
         }
     }
 
@@ -353,14 +488,18 @@ impl Item {
         match self {
             Item::PowerUp(_) => 1,
             Item::PowerUp10(_) => 10,
+            Item::Bomb(_) => 0,
         }
     }
 
     pub fn animate(&mut self, player: &mut Player) -> Option<DeathReason> {
         match self {
-            Item::PowerUp(ent) | Item::PowerUp10(ent) => {
-                if ent.hits_player(&player.base).is_some() {
+            Item::PowerUp(ent) | Item::PowerUp10(ent) | Item::Bomb(ent) => {
+                if Collider::circle(ent.pos, ITEM_SIZE).intersects(&player.pickup_collider()) {
                     player.power += self.power_value();
+                    if let Item::Bomb(_) = self {
+                        player.bombs = (player.bombs + 1).min(MAX_BOMBS);
+                    }
                     return Some(DeathReason::Killed);
                 }
                 ent.animate()
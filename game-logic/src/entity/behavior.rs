@@ -0,0 +1,255 @@
+//! State machines driving enemies, as an alternative to coding the AI of each kind.
+//!
+//! A behavior is a list of states, each of which runs actions when entered and every
+//! frame, and moves to another state when the condition of one of its transitions is met.
+
+use serde::{Deserialize, Serialize};
+use vecmath::{vec2_add, vec2_len, vec2_scale, vec2_sub};
+
+use super::{EnemyBase, EnemyKind, Leg, PatternRunner, Route};
+use crate::ShooterState;
+
+/// The states of a behavior, starting in the first one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Behavior {
+    pub states: Vec<BehaviorState>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BehaviorState {
+    pub name: String,
+    /// Run when the state is entered
+    #[serde(default)]
+    pub enter: Vec<BehaviorAction>,
+    /// Run every frame in the state
+    #[serde(default)]
+    pub each_frame: Vec<BehaviorAction>,
+    /// Checked in order every frame, and the first one met is taken
+    #[serde(default)]
+    pub transitions: Vec<Transition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    pub when: Condition,
+    /// The states to move to, one of which is picked at random.
+    /// Repeat a state to make it more likely.
+    pub to: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    /// The state has lasted the given number of frames
+    After(u32),
+    /// The enemy has been on the screen for the given number of frames
+    Age(u32),
+    /// The player is closer than the distance
+    PlayerWithin(f64),
+    /// The player is further than the distance
+    PlayerBeyond(f64),
+    /// The health has gone below the fraction of the health at the start of the behavior
+    HealthBelow(f64),
+    /// All of the conditions are met
+    All(Vec<Condition>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BehaviorAction {
+    /// Turn the velocity by the given radians, keeping the speed
+    Turn(f64),
+    /// Set the velocity
+    Velocity([f64; 2]),
+    /// Head for the player at a speed
+    Aim { speed: f64 },
+    /// Move along the legs from the current position, or stop following a route if empty
+    Move(Vec<Leg>),
+    /// Fire the named bullet pattern over and over, instead of the one fired so far
+    Fire(String),
+    /// Stop firing
+    HoldFire,
+    /// Spawn an enemy at an offset from the position
+    Spawn {
+        enemy: EnemyKind,
+        /// The name of the registered enemy, if `enemy` is `Custom`
+        #[serde(default)]
+        custom: Option<String>,
+        #[serde(default)]
+        offset: [f64; 2],
+        velo: [f64; 2],
+    },
+}
+
+impl Behavior {
+    /// The name of a state that a transition refers to but does not exist, if any.
+    pub fn unknown_state(&self) -> Option<&str> {
+        self.states
+            .iter()
+            .flat_map(|state| &state.transitions)
+            .flat_map(|transition| &transition.to)
+            .find(|name| self.state_index(name).is_none())
+            .map(|name| name.as_str())
+    }
+
+    /// Make sure that the enemies spawned by the states name a registered enemy only if
+    /// they are `Custom`.
+    pub fn check_spawns(&self) -> Result<(), &'static str> {
+        for state in &self.states {
+            for action in state.enter.iter().chain(&state.each_frame) {
+                if let BehaviorAction::Spawn { enemy, custom, .. } = action {
+                    enemy.check_custom(custom.as_deref())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure that the paths the states move along are valid.
+    pub fn check_paths(&self) -> Result<(), &'static str> {
+        for state in &self.states {
+            for action in state.enter.iter().chain(&state.each_frame) {
+                if let BehaviorAction::Move(legs) = action {
+                    for leg in legs {
+                        if let Leg::Follow(path) = leg {
+                            path.check()?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn state_index(&self, name: &str) -> Option<usize> {
+        self.states.iter().position(|state| state.name == name)
+    }
+}
+
+impl Condition {
+    fn is_met(&self, runner: &BehaviorRunner, enemy: &EnemyBase, player: [f64; 2]) -> bool {
+        let player_dist = || vec2_len(vec2_sub(player, enemy.pos));
+        match self {
+            Condition::After(frames) => *frames <= runner.state_time,
+            Condition::Age(frames) => *frames <= enemy.age,
+            Condition::PlayerWithin(dist) => player_dist() < *dist,
+            Condition::PlayerBeyond(dist) => *dist < player_dist(),
+            Condition::HealthBelow(fraction) => {
+                (enemy.health as f64) < runner.start_health as f64 * fraction
+            }
+            Condition::All(conditions) => conditions
+                .iter()
+                .all(|condition| condition.is_met(runner, enemy, player)),
+        }
+    }
+}
+
+impl BehaviorAction {
+    fn run(&self, enemy: &mut EnemyBase, state: &mut ShooterState) {
+        match self {
+            BehaviorAction::Turn(angle) => {
+                let (s, c) = angle.sin_cos();
+                let velo = enemy.velo;
+                enemy.velo = [velo[0] * c - velo[1] * s, velo[0] * s + velo[1] * c];
+            }
+            BehaviorAction::Velocity(velo) => enemy.velo = *velo,
+            BehaviorAction::Aim { speed } => {
+                let delta = vec2_sub(state.player.base.pos, enemy.pos);
+                let dist = vec2_len(delta);
+                if 0. < dist {
+                    enemy.velo = vec2_scale(delta, speed / dist);
+                }
+            }
+            BehaviorAction::Move(legs) => {
+                enemy.route = if legs.is_empty() {
+                    None
+                } else {
+                    Some(Route::new(legs.clone(), enemy.pos))
+                };
+            }
+            BehaviorAction::Fire(pattern) => enemy.fire = Some(PatternRunner::new(pattern)),
+            BehaviorAction::HoldFire => enemy.fire = None,
+            BehaviorAction::Spawn {
+                enemy: kind,
+                custom,
+                offset,
+                velo,
+            } => {
+                // The enemies being animated are out of the set, and this one joins them
+                // after the frame
+                let spawned = state.enemy_registry.spawn_kind(
+                    *kind,
+                    custom.as_deref(),
+                    vec2_add(enemy.pos, *offset),
+                    *velo,
+                );
+                state.enemies.insert(spawned);
+            }
+        }
+    }
+}
+
+/// The progress of a behavior of an enemy.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BehaviorRunner {
+    behavior: Behavior,
+    /// The index of the current state
+    state: usize,
+    /// Frames since the current state was entered
+    state_time: u32,
+    /// The health at the start, which the health conditions are relative to
+    start_health: i32,
+    /// The enter actions of the first state have run
+    started: bool,
+}
+
+impl BehaviorRunner {
+    pub fn new(behavior: Behavior, start_health: i32) -> Self {
+        Self {
+            behavior,
+            state: 0,
+            state_time: 0,
+            start_health,
+            started: false,
+        }
+    }
+
+    /// The name of the current state.
+    pub fn state(&self) -> Option<&str> {
+        Some(&self.behavior.states.get(self.state)?.name)
+    }
+
+    /// Take a transition if any is met and run the actions of the state for a frame.
+    pub fn step(&mut self, enemy: &mut EnemyBase, state: &mut ShooterState) {
+        let Some(current) = self.behavior.states.get(self.state) else {
+            return;
+        };
+        if !self.started {
+            self.started = true;
+            for action in &current.enter {
+                action.run(enemy, state);
+            }
+        }
+        let player = state.player.base.pos;
+        let next = current
+            .transitions
+            .iter()
+            .find(|transition| transition.when.is_met(self, enemy, player))
+            .filter(|transition| !transition.to.is_empty())
+            .map(|transition| {
+                let i = state.rng.gen_range(0, transition.to.len() as u32);
+                &transition.to[i as usize]
+            })
+            .and_then(|name| self.behavior.state_index(name));
+        if let Some(next) = next {
+            self.state = next;
+            self.state_time = 0;
+            console_log!("Behavior state: {}", self.behavior.states[next].name);
+            for action in &self.behavior.states[next].enter {
+                action.run(enemy, state);
+            }
+        }
+        for action in &self.behavior.states[self.state].each_frame {
+            action.run(enemy, state);
+        }
+        self.state_time += 1;
+    }
+}
@@ -0,0 +1,281 @@
+//! Collision shapes for the hit tests between entities.
+//!
+//! Every shape is reduced to a core of a point, a segment or a box, inflated by a radius.
+//! Two shapes intersect when the distance between their cores is less than the sum of the
+//! radii, so that one distance function per pair of cores covers all the combinations.
+
+use vecmath::{vec2_add, vec2_dot, vec2_len, vec2_scale, vec2_sub};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collider {
+    Circle {
+        center: [f64; 2],
+        radius: f64,
+    },
+    /// An axis-aligned box of `[left, top, right, bottom]`
+    Aabb([f64; 4]),
+    /// A box with half extents `half`, rotated by `angle` radians around `center`
+    Obb {
+        center: [f64; 2],
+        half: [f64; 2],
+        angle: f64,
+    },
+    /// A segment from `a` to `b` swept by a circle of `radius`
+    Capsule {
+        a: [f64; 2],
+        b: [f64; 2],
+        radius: f64,
+    },
+}
+
+enum Core {
+    Point([f64; 2]),
+    Segment([f64; 2], [f64; 2]),
+    Box {
+        center: [f64; 2],
+        half: [f64; 2],
+        angle: f64,
+    },
+}
+
+impl Collider {
+    pub fn circle(center: [f64; 2], radius: f64) -> Self {
+        Self::Circle { center, radius }
+    }
+
+    /// A square box of `2 * size` on a side, like the bounding boxes of sprites.
+    pub fn square(center: [f64; 2], size: f64) -> Self {
+        Self::Aabb([
+            center[0] - size,
+            center[1] - size,
+            center[0] + size,
+            center[1] + size,
+        ])
+    }
+
+    /// A capsule of `half_length` from `center` to each end, along the direction of `angle`.
+    pub fn capsule(center: [f64; 2], angle: f64, half_length: f64, radius: f64) -> Self {
+        let d = [half_length * angle.cos(), half_length * angle.sin()];
+        Self::Capsule {
+            a: vec2_sub(center, d),
+            b: vec2_add(center, d),
+            radius,
+        }
+    }
+
+    /// The smallest axis-aligned box containing the shape.
+    pub fn bounding_box(&self) -> [f64; 4] {
+        match *self {
+            Self::Circle { center, radius } => [
+                center[0] - radius,
+                center[1] - radius,
+                center[0] + radius,
+                center[1] + radius,
+            ],
+            Self::Aabb(rect) => rect,
+            Self::Obb {
+                center,
+                half,
+                angle,
+            } => {
+                let (sin, cos) = angle.sin_cos();
+                let ex = half[0] * cos.abs() + half[1] * sin.abs();
+                let ey = half[0] * sin.abs() + half[1] * cos.abs();
+                [
+                    center[0] - ex,
+                    center[1] - ey,
+                    center[0] + ex,
+                    center[1] + ey,
+                ]
+            }
+            Self::Capsule { a, b, radius } => [
+                a[0].min(b[0]) - radius,
+                a[1].min(b[1]) - radius,
+                a[0].max(b[0]) + radius,
+                a[1].max(b[1]) + radius,
+            ],
+        }
+    }
+
+    /// Whether the shapes overlap or touch.
+    pub fn intersects(&self, other: &Collider) -> bool {
+        let (core, radius) = self.core();
+        let (other_core, other_radius) = other.core();
+        core.distance(&other_core) <= radius + other_radius
+    }
+
+    fn core(&self) -> (Core, f64) {
+        match *self {
+            Self::Circle { center, radius } => (Core::Point(center), radius),
+            Self::Aabb(rect) => (
+                Core::Box {
+                    center: [(rect[0] + rect[2]) / 2., (rect[1] + rect[3]) / 2.],
+                    half: [(rect[2] - rect[0]) / 2., (rect[3] - rect[1]) / 2.],
+                    angle: 0.,
+                },
+                0.,
+            ),
+            Self::Obb {
+                center,
+                half,
+                angle,
+            } => (
+                Core::Box {
+                    center,
+                    half,
+                    angle,
+                },
+                0.,
+            ),
+            Self::Capsule { a, b, radius } => (Core::Segment(a, b), radius),
+        }
+    }
+}
+
+impl Core {
+    /// The distance between the cores, which is 0 if they overlap.
+    fn distance(&self, other: &Core) -> f64 {
+        use Core::*;
+        match (self, other) {
+            (Point(p), Point(q)) => vec2_len(vec2_sub(*p, *q)),
+            (Point(p), Segment(a, b)) | (Segment(a, b), Point(p)) => point_segment(*p, *a, *b),
+            (Segment(a, b), Segment(c, d)) => segment_segment(*a, *b, *c, *d),
+            (Point(p), Box { .. }) | (Box { .. }, Point(p)) => {
+                let bx = if let Box { .. } = self { self } else { other };
+                bx.box_point(*p)
+            }
+            (Segment(a, b), Box { .. }) | (Box { .. }, Segment(a, b)) => {
+                let bx = if let Box { .. } = self { self } else { other };
+                if bx.box_point(*a) == 0. || bx.box_point(*b) == 0. {
+                    return 0.;
+                }
+                bx.box_edges()
+                    .iter()
+                    .map(|edge| segment_segment(*a, *b, edge[0], edge[1]))
+                    .fold(f64::INFINITY, f64::min)
+            }
+            (Box { .. }, Box { .. }) => {
+                let corners = |bx: &Core| bx.box_edges().map(|edge| edge[0]);
+                if corners(self).iter().any(|p| other.box_point(*p) == 0.)
+                    || corners(other).iter().any(|p| self.box_point(*p) == 0.)
+                {
+                    return 0.;
+                }
+                let mut ret = f64::INFINITY;
+                for edge in self.box_edges() {
+                    for edge2 in other.box_edges() {
+                        ret = ret.min(segment_segment(edge[0], edge[1], edge2[0], edge2[1]));
+                    }
+                }
+                ret
+            }
+        }
+    }
+
+    /// The distance from a point to the box, which is 0 inside it.
+    fn box_point(&self, p: [f64; 2]) -> f64 {
+        let Core::Box {
+            center,
+            half,
+            angle,
+        } = self
+        else {
+            unreachable!()
+        };
+        let (sin, cos) = angle.sin_cos();
+        let d = vec2_sub(p, *center);
+        let local = [d[0] * cos + d[1] * sin, -d[0] * sin + d[1] * cos];
+        let outside = [
+            (local[0].abs() - half[0]).max(0.),
+            (local[1].abs() - half[1]).max(0.),
+        ];
+        vec2_len(outside)
+    }
+
+    /// The edges of the box, each starting at a corner.
+    fn box_edges(&self) -> [[[f64; 2]; 2]; 4] {
+        let Core::Box {
+            center,
+            half,
+            angle,
+        } = self
+        else {
+            unreachable!()
+        };
+        let (sin, cos) = angle.sin_cos();
+        let corner = |sx: f64, sy: f64| {
+            let x = sx * half[0];
+            let y = sy * half[1];
+            [center[0] + x * cos - y * sin, center[1] + x * sin + y * cos]
+        };
+        let corners = [
+            corner(-1., -1.),
+            corner(1., -1.),
+            corner(1., 1.),
+            corner(-1., 1.),
+        ];
+        [0, 1, 2, 3].map(|i| [corners[i], corners[(i + 1) % 4]])
+    }
+}
+
+fn point_segment(p: [f64; 2], a: [f64; 2], b: [f64; 2]) -> f64 {
+    let ab = vec2_sub(b, a);
+    let len2 = vec2_dot(ab, ab);
+    let t = if len2 == 0. {
+        0.
+    } else {
+        (vec2_dot(vec2_sub(p, a), ab) / len2).clamp(0., 1.)
+    };
+    vec2_len(vec2_sub(p, vec2_add(a, vec2_scale(ab, t))))
+}
+
+fn segment_segment(a: [f64; 2], b: [f64; 2], c: [f64; 2], d: [f64; 2]) -> f64 {
+    let cross = |o: [f64; 2], p: [f64; 2], q: [f64; 2]| {
+        let op = vec2_sub(p, o);
+        let oq = vec2_sub(q, o);
+        op[0] * oq[1] - op[1] * oq[0]
+    };
+    let (d1, d2) = (cross(a, b, c), cross(a, b, d));
+    let (d3, d4) = (cross(c, d, a), cross(c, d, b));
+    if d1 * d2 < 0. && d3 * d4 < 0. {
+        return 0.;
+    }
+    point_segment(a, c, d)
+        .min(point_segment(b, c, d))
+        .min(point_segment(c, a, b))
+        .min(point_segment(d, a, b))
+}
+
+#[test]
+fn collider_pairs() {
+    let circle = Collider::circle([0., 0.], 2.);
+    assert!(circle.intersects(&Collider::circle([3., 0.], 1.5)));
+    assert!(!circle.intersects(&Collider::circle([3., 0.], 0.5)));
+
+    // A corner of the square is further than its side
+    let square = Collider::square([4., 4.], 1.);
+    assert!(!circle.intersects(&square));
+    assert!(circle.intersects(&Collider::square([2.5, 0.], 1.)));
+
+    // Rotated by 45 degrees, the corner reaches toward the circle
+    let diamond = Collider::Obb {
+        center: [3.2, 0.],
+        half: [1., 1.],
+        angle: std::f64::consts::FRAC_PI_4,
+    };
+    assert!(!circle.intersects(&Collider::square([3.2, 0.], 1.)));
+    assert!(circle.intersects(&diamond));
+
+    let capsule = Collider::capsule([0., 5.], 0., 4., 1.);
+    assert!(capsule.intersects(&Collider::circle([4.5, 5.], 0.1)));
+    assert!(!capsule.intersects(&Collider::circle([0., 7.], 0.5)));
+    // Crossing without the ends inside
+    assert!(capsule.intersects(&Collider::Capsule {
+        a: [0., 0.],
+        b: [0., 10.],
+        radius: 0.,
+    }));
+    assert!(capsule.intersects(&Collider::Aabb([-10., 5.5, 10., 6.])));
+    assert!(Collider::square([4.5, 0.5], 1.).intersects(&diamond));
+    assert!(!Collider::square([10., 10.], 1.).intersects(&diamond));
+}
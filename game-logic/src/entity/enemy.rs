@@ -1,6 +1,7 @@
 use std::ops::{Deref, DerefMut};
 
-use vecmath::{vec2_add, vec2_len, vec2_scale, vec2_sub};
+use serde::{Deserialize, Serialize};
+use vecmath::{vec2_add, vec2_len, vec2_scale, vec2_square_len, vec2_sub};
 #[cfg(feature = "webgl")]
 use web_sys::WebGlRenderingContext as GL;
 
@@ -18,23 +19,59 @@ use piston_window::{
 #[cfg(all(not(feature = "webgl"), feature = "piston"))]
 use super::Matrix;
 
-use crate::{xor128::Xor128, ShooterState};
+use crate::{
+    consts::{FHEIGHT, FWIDTH},
+    event::GameEvent,
+    ShooterState,
+};
 
 #[cfg(feature = "webgl")]
 use super::draw_tex;
 use super::{
-    bbox_intersects, bounding_box, BulletBase, DeathReason, Entity, EntitySet, Item, Projectile,
-    ENEMY_SIZE, SCREEN_RECT,
+    bbox_intersects, bounding_box, Behavior, BehaviorAction, BehaviorRunner, BehaviorState,
+    BulletBase, Collider, Condition, DeathReason, Entity, EntitySet, Item, PatternRunner,
+    Projectile, Route, Transition, ENEMY_SIZE, SCREEN_RECT,
 };
 
+mod registry;
+
+pub use self::registry::{CustomEnemy, EnemyRegistry, LoadFn, SpawnFn};
+
 const JOINT_LENGTH: f64 = 20.;
 const CENTIPEDE_STRAIGHT_TASK_TIME: u32 = 100;
 const CENTIPEDE_TURN_TASK_TIME: u32 = 100;
 const TURN_RATE: f64 = 0.4 * std::f64::consts::PI / CENTIPEDE_TURN_TASK_TIME as f64;
+const PHASE_BOSS_HEALTH: i32 = 1024;
+const PHASE_BOSS_SIZE: f64 = 32.;
+const PHASE_BOSS_SPEED: f64 = 0.5;
+/// The boss stops its entry at this height and starts attacking
+const PHASE_BOSS_ENTRY_Y: f64 = FHEIGHT / 4.;
+const KAMIKAZE_ACCEL: f64 = 0.04;
+const KAMIKAZE_MAX_SPEED: f64 = 3.;
+/// After this many frames, a kamikaze stops turning and dives straight ahead
+const KAMIKAZE_HOMING_TIME: u32 = 120;
+const TURRET_SIZE: f64 = 12.;
+/// A turret moves into the screen until this age and stays there until it leaves
+const TURRET_STOP_TIME: u32 = 90;
+const TURRET_LEAVE_TIME: u32 = 1200;
+const SPLITTER_GENERATIONS: u32 = 2;
+/// The angle between the velocities of the two halves of a splitter
+const SPLITTER_SPREAD: f64 = 1.;
+const MINE_LAYER_SIZE: f64 = 16.;
 
+#[derive(Serialize, Deserialize)]
 pub struct EnemyBase {
     pub base: Entity,
     pub predicted_damage: i32,
+    /// The bullet pattern being fired, started at the first frame
+    pub fire: Option<PatternRunner>,
+    /// Frames since spawned
+    pub age: u32,
+    /// The route to move along instead of keeping the velocity. Enemies which steer
+    /// themselves, like kamikazes and turrets, may not follow it.
+    pub route: Option<Route>,
+    /// The state machine driving the enemy, if any
+    pub behavior: Option<BehaviorRunner>,
 }
 
 impl Deref for EnemyBase {
@@ -55,6 +92,10 @@ impl EnemyBase {
         Self {
             base: Entity::new(pos, velo).health(64),
             predicted_damage: 0,
+            fire: None,
+            age: 0,
+            route: None,
+            behavior: None,
         }
     }
 
@@ -62,8 +103,68 @@ impl EnemyBase {
         self.base.health = health;
         self
     }
+
+    /// Drive the enemy by `behavior`, with the health conditions relative to the current
+    /// health.
+    pub fn behavior(mut self, behavior: Behavior) -> Self {
+        self.behavior = Some(BehaviorRunner::new(behavior, self.base.health));
+        self
+    }
+}
+
+/// Implement the accessors of [`EnemyBehavior`] for an enemy with the `base` field.
+macro_rules! enemy_base {
+    () => {
+        fn base(&self) -> &EnemyBase {
+            &self.base
+        }
+
+        fn base_mut(&mut self) -> &mut EnemyBase {
+            &mut self.base
+        }
+    };
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Enemy1 {
+    base: EnemyBase,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Boss {
+    base: EnemyBase,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SpiralEnemy {
+    base: EnemyBase,
+}
+
+/// Accelerates toward the player and dives through
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Kamikaze {
+    base: EnemyBase,
+}
+
+/// Stops in the screen for a while and fires bursts at the player
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Turret {
+    base: EnemyBase,
+}
+
+/// Drops mines with its bullet pattern
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MineLayer {
+    base: EnemyBase,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct ShieldedBoss {
     pub base: EnemyBase,
     pub shield_health: i32,
@@ -72,415 +173,1124 @@ pub struct ShieldedBoss {
 impl ShieldedBoss {
     pub fn new(pos: [f64; 2], velo: [f64; 2]) -> Self {
         Self {
-            base: EnemyBase {
-                base: Entity::new(pos, velo).health(64),
-                predicted_damage: 0,
-            },
+            base: EnemyBase::new(pos, velo),
             shield_health: 64,
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-struct CentipedeJoint([f64; 2], i32);
+fn splitter_size(generation: u32) -> f64 {
+    ENEMY_SIZE * (1. + generation as f64 / 2.)
+}
 
-#[derive(Debug)]
-#[repr(C)]
-enum CentipedeTask {
-    Straight,
-    TurnLeft,
-    TurnRight,
+/// An enemy which splits in two smaller ones when killed, until it is small enough.
+#[derive(Serialize, Deserialize)]
+pub struct Splitter {
+    base: EnemyBase,
+    /// The number of times left to split
+    generation: u32,
 }
 
+impl Splitter {
+    fn new(pos: [f64; 2], velo: [f64; 2], generation: u32) -> Self {
+        Self {
+            base: EnemyBase::new(pos, velo).health(4 << generation),
+            generation,
+        }
+    }
+
+    fn size(&self) -> f64 {
+        splitter_size(self.generation)
+    }
+
+    /// Turn into the smaller half spreading to one side, and return the other half.
+    fn split(&mut self) -> Splitter {
+        let pos = self.base.pos;
+        let speed = vec2_len(self.base.velo);
+        let heading = self.base.velo[1].atan2(self.base.velo[0]);
+        let generation = self.generation - 1;
+        let size = splitter_size(generation);
+        let half = |sign: f64| {
+            let angle = heading + sign * SPLITTER_SPREAD / 2.;
+            // Start apart so that the same shot does not hit both
+            let side = heading + sign * std::f64::consts::FRAC_PI_2;
+            Splitter::new(
+                vec2_add(pos, [side.cos() * size, side.sin() * size]),
+                [angle.cos() * speed, angle.sin() * speed],
+                generation,
+            )
+        };
+        *self = half(-1.);
+        half(1.)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct CentipedeJoint([f64; 2], i32);
+
+#[derive(Serialize, Deserialize)]
 pub struct CentipedeEnemy {
     base: EnemyBase,
     joints: Vec<CentipedeJoint>,
-    task: CentipedeTask,
-    task_time: u32,
-    heading: f64,
 }
 
-pub enum Enemy {
-    Enemy1(EnemyBase),
-    Boss(EnemyBase),
-    ShieldedBoss(ShieldedBoss),
-    SpiralEnemy(EnemyBase),
-    Centipede(CentipedeEnemy),
+/// Go straight or turn to either side for a while, picked at random.
+fn centipede_behavior() -> Behavior {
+    let state = |name: &str, turn: f64, frames| BehaviorState {
+        name: name.to_string(),
+        enter: vec![],
+        each_frame: if turn == 0. {
+            vec![]
+        } else {
+            vec![BehaviorAction::Turn(turn)]
+        },
+        transitions: vec![Transition {
+            when: Condition::After(frames),
+            to: ["straight", "straight", "left", "right"]
+                .map(String::from)
+                .to_vec(),
+        }],
+    };
+    Behavior {
+        states: vec![
+            state("straight", 0., CENTIPEDE_STRAIGHT_TASK_TIME),
+            state("left", TURN_RATE, CENTIPEDE_TURN_TASK_TIME),
+            state("right", -TURN_RATE, CENTIPEDE_TURN_TASK_TIME),
+        ],
+    }
 }
 
-impl Deref for Enemy {
-    type Target = EnemyBase;
-    fn deref(&self) -> &EnemyBase {
-        match self {
-            Enemy::Enemy1(base) | Enemy::Boss(base) | Enemy::SpiralEnemy(base) => &base,
-            Enemy::ShieldedBoss(boss) => &boss.base,
-            Enemy::Centipede(centipede) => &centipede.base,
+/// The attack phase of a [`PhaseBoss`], which advances as its health goes down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BossPhase {
+    /// Descending into the screen, invulnerable
+    Entry,
+    /// Rings of phase bullets
+    Rings,
+    /// Rotating streams of spiral bullets
+    Spirals,
+    /// Both of them, faster
+    Frenzy,
+}
+
+/// A boss fought in several phases, which regular spawns pause for.
+#[derive(Serialize, Deserialize)]
+pub struct PhaseBoss {
+    base: EnemyBase,
+    max_health: i32,
+    phase: BossPhase,
+    /// Frames since the current phase started
+    phase_time: u32,
+    /// The direction of the rotating bullet streams
+    stream_angle: f64,
+}
+
+impl PhaseBoss {
+    pub fn new(pos: [f64; 2]) -> Self {
+        Self {
+            base: EnemyBase::new(pos, [0., PHASE_BOSS_SPEED]).health(PHASE_BOSS_HEALTH),
+            max_health: PHASE_BOSS_HEALTH,
+            phase: BossPhase::Entry,
+            phase_time: 0,
+            stream_angle: 0.,
+        }
+    }
+
+    pub fn phase(&self) -> BossPhase {
+        self.phase
+    }
+
+    /// The remaining health in the range of 0 to 1.
+    pub fn health_ratio(&self) -> f64 {
+        (self.base.health as f64 / self.max_health as f64).clamp(0., 1.)
+    }
+
+    fn phase_by_health(&self) -> BossPhase {
+        let health = self.base.health;
+        if self.max_health * 2 / 3 < health {
+            BossPhase::Rings
+        } else if self.max_health / 3 < health {
+            BossPhase::Spirals
+        } else {
+            BossPhase::Frenzy
+        }
+    }
+
+    fn fire(
+        &self,
+        bullets: &mut EntitySet<Projectile>,
+        angle: f64,
+        speed: f64,
+        create_fn: impl Fn(BulletBase) -> Projectile,
+    ) {
+        let velo = vec2_scale([angle.cos(), angle.sin()], speed);
+        bullets.insert(create_fn(BulletBase(
+            Entity::new(self.base.pos, velo).rotation(angle as f32),
+        )));
+    }
+
+    fn fire_ring(&self, bullets: &mut EntitySet<Projectile>, count: u32, speed: f64) {
+        use std::f64::consts::PI;
+        for i in 0..count {
+            let angle = 2. * PI * i as f64 / count as f64 + self.stream_angle;
+            self.fire(bullets, angle, speed, Projectile::new_phase);
+        }
+    }
+
+    fn fire_streams(&self, bullets: &mut EntitySet<Projectile>, count: u32, speed: f64) {
+        use std::f64::consts::PI;
+        for i in 0..count {
+            let angle = 2. * PI * i as f64 / count as f64 + self.stream_angle;
+            self.fire(bullets, angle, speed, Projectile::new_spiral);
         }
     }
 }
 
-impl DerefMut for Enemy {
-    fn deref_mut(&mut self) -> &mut EnemyBase {
-        match self {
-            Enemy::Enemy1(ref mut base)
-            | Enemy::Boss(ref mut base)
-            | Enemy::SpiralEnemy(ref mut base) => base,
-            Enemy::ShieldedBoss(ref mut boss) => &mut boss.base,
-            Enemy::Centipede(ref mut centipede) => &mut centipede.base,
+/// The type of an enemy, without its state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum EnemyKind {
+    Enemy1,
+    Boss,
+    ShieldedBoss,
+    SpiralEnemy,
+    Centipede,
+    PhaseBoss,
+    Kamikaze,
+    Turret,
+    Splitter,
+    MineLayer,
+    /// Any enemy registered in an [`EnemyRegistry`], which is spawned by its name
+    /// rather than by its kind. Data files give the name in `custom`.
+    Custom,
+}
+
+impl EnemyKind {
+    /// Make sure that the name of a custom enemy is given with the custom kind, and
+    /// only with it.
+    pub fn check_custom(self, custom: Option<&str>) -> Result<(), &'static str> {
+        match (self, custom) {
+            (EnemyKind::Custom, None) => Err("a Custom enemy needs its name in custom"),
+            (EnemyKind::Custom, Some(_)) | (_, None) => Ok(()),
+            (_, Some(_)) => Err("custom is only for the Custom enemy kind"),
         }
     }
 }
 
-impl Enemy {
+/// The logic of a type of enemy. Every built-in enemy implements it, and so can the
+/// game to add its own enemies through the [`EnemyRegistry`].
+///
+/// The age, the bullet pattern, the behavior and the route are advanced by
+/// [`Enemy::animate`] for every enemy before [`EnemyBehavior::animate`] is called.
+pub trait EnemyBehavior {
+    /// The name in the logs, and in the registry for custom enemies
+    fn name(&self) -> &str;
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::Custom
+    }
+
+    fn base(&self) -> &EnemyBase;
+
+    fn base_mut(&mut self) -> &mut EnemyBase;
+
+    /// Move for a frame, and return the reason to remove the enemy, if any.
+    fn animate(&mut self, _state: &mut ShooterState) -> Option<DeathReason> {
+        self.base_mut().animate()
+    }
+
     /// Apply damage to this enemy, within specified rectangle area.
-    /// The area can be important for patial damages.
-    pub fn damage(&mut self, val: i32, rect: &[f64; 4]) -> Option<Enemy> {
-        match self {
-            Enemy::Enemy1(ref mut base)
-            | Enemy::Boss(ref mut base)
-            | Enemy::SpiralEnemy(ref mut base) => {
-                base.base.health -= val;
-                console_log!("damaged: {}", base.health);
-            }
-            Enemy::ShieldedBoss(ref mut boss) => {
-                if boss.shield_health < 16 {
-                    boss.base.health -= val
-                } else {
-                    boss.shield_health -= val
-                }
-            }
-            Enemy::Centipede(ref mut centipede) => {
-                let self_velo = centipede.base.velo;
-
-                let damaged_joint = centipede.joints.iter_mut().enumerate().find(|(_, joint)| {
-                    let rect2 = bounding_box(&joint.0, ENEMY_SIZE);
-                    bbox_intersects(rect, &rect2)
-                });
-
-                if let Some((i, joint)) = damaged_joint {
-                    joint.1 -= val;
-                    if joint.1 <= 0 {
-                        let joint_pos = joint.0;
-                        if centipede.joints.len() == 1 {
-                            centipede.base.health = -1;
-                        } else {
-                            let heading =
-                                self_velo[1].atan2(self_velo[0]) + std::f64::consts::PI / 2.;
-                            let speed = vec2_len(self_velo);
-                            let velo = [speed * heading.cos(), speed * heading.sin()];
-                            let back_joints = if i + 1 < centipede.joints.len() {
-                                Some(centipede.joints[i + 1..].to_vec())
-                            } else {
-                                None
-                            };
-                            centipede.joints.resize(i, CentipedeJoint::default());
-                            return back_joints.map(|back_joints| {
-                                Enemy::new_centipede_joints(joint_pos, velo, back_joints)
-                            });
-                        }
-                    }
-                } else {
-                    centipede.base.health -= 1;
-                }
-            }
+    /// Returns an enemy which broke off by the damage, if any.
+    fn damage(&mut self, val: i32, _rect: &[f64; 4]) -> Option<Enemy> {
+        let base = self.base_mut();
+        base.health -= val;
+        console_log!("damaged: {}", base.health);
+        None
+    }
+
+    /// The shape of the body.
+    fn collider(&self) -> Collider {
+        Collider::circle(self.base().pos, ENEMY_SIZE)
+    }
+
+    /// Whether `collider` hits the enemy.
+    fn test_hit(&self, collider: &Collider) -> bool {
+        self.collider().intersects(collider)
+    }
+
+    /// Whether the body touches `collider`, even where it cannot be hit.
+    fn touches(&self, collider: &Collider) -> bool {
+        self.collider().intersects(collider) || self.test_hit(collider)
+    }
+
+    /// The bounding boxes of the whole body, which the broadphase registers.
+    fn body_bbs(&self) -> Vec<[f64; 4]> {
+        vec![self.collider().bounding_box()]
+    }
+
+    fn is_boss(&self) -> bool {
+        false
+    }
+
+    /// The points for killing the enemy, before the chain multiplier.
+    fn score(&self) -> u32 {
+        if self.is_boss() {
+            10
+        } else {
+            1
         }
+    }
+
+    /// The chance in percent to drop an item when killed.
+    fn drop_chance(&self) -> u32 {
+        20
+    }
+
+    fn drop_item(&self, ent: Entity) -> Item {
+        Item::PowerUp10(ent)
+    }
+
+    /// The chance in percent to drop a bomb item when killed, rolled apart from the
+    /// other item.
+    fn bomb_drop_chance(&self) -> u32 {
+        0
+    }
+
+    /// The remaining health in the range of 0 to 1, if the enemy is a boss whose fight
+    /// pauses regular spawns.
+    fn boss_health(&self) -> Option<f64> {
         None
     }
 
-    pub fn predicted_damage(&self) -> i32 {
-        match self {
-            Enemy::Enemy1(base) | Enemy::Boss(base) | Enemy::SpiralEnemy(base) => {
-                base.predicted_damage
-            }
-            Enemy::ShieldedBoss(boss) => boss.base.predicted_damage,
-            Enemy::Centipede(centipede) => centipede.base.predicted_damage,
+    /// The state other than the base to put in a snapshot, which is given back to the
+    /// load function of the registry. Only custom enemies are saved this way.
+    fn save(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        self.base()
+            .draw_tex(assets, gl, &assets.enemy_tex, Some([ENEMY_SIZE; 2]));
+    }
+
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        self.base().draw_tex(context, g, &assets.enemy_tex, None);
+    }
+}
+
+impl EnemyBehavior for Enemy1 {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "enemy"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::Enemy1
+    }
+
+    fn drop_item(&self, ent: Entity) -> Item {
+        Item::PowerUp(ent)
+    }
+}
+
+impl EnemyBehavior for Boss {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "boss"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::Boss
+    }
+
+    fn is_boss(&self) -> bool {
+        true
+    }
+
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        use crate::BOSS_SIZE;
+        self.base
+            .draw_tex(assets, gl, &assets.boss_tex, Some([BOSS_SIZE; 2]));
+    }
+
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        self.base.draw_tex(context, g, &assets.boss_tex, None);
+    }
+}
+
+impl EnemyBehavior for ShieldedBoss {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "ShieldedBoss"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::ShieldedBoss
+    }
+
+    fn animate(&mut self, state: &mut ShooterState) -> Option<DeathReason> {
+        if self.shield_health < 64 && state.time % 8 == 0 {
+            self.shield_health += 1;
         }
+        self.base.animate()
     }
 
-    pub fn add_predicted_damage(&mut self, val: i32) {
-        self.predicted_damage += val;
+    fn damage(&mut self, val: i32, _rect: &[f64; 4]) -> Option<Enemy> {
+        if self.shield_health < 16 {
+            self.base.health -= val
+        } else {
+            self.shield_health -= val
+        }
+        None
     }
 
-    pub fn total_health(&self) -> i32 {
-        self.health
+    fn collider(&self) -> Collider {
+        Collider::circle(self.base.pos, self.shield_health as f64)
     }
 
-    pub fn drop_item(&self, ent: Entity) -> Item {
-        match self {
-            Enemy::Enemy1(_) => Item::PowerUp(ent),
-            _ => Item::PowerUp10(ent),
+    fn is_boss(&self) -> bool {
+        true
+    }
+
+    fn bomb_drop_chance(&self) -> u32 {
+        20
+    }
+
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        use crate::BOSS_SIZE;
+        self.base
+            .draw_tex(assets, gl, &assets.boss_tex, Some([BOSS_SIZE; 2]));
+        self.base.draw_tex(
+            assets,
+            gl,
+            &assets.shield_tex,
+            Some([self.shield_health as f64; 2]),
+        );
+    }
+
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        self.base.draw_tex(context, g, &assets.boss_tex, None);
+
+        let pos = &self.base.pos;
+        let tex2 = &*assets.shield_tex;
+        let centerize = translate([
+            -(tex2.get_width() as f64 / 2.),
+            -(tex2.get_height() as f64 / 2.),
+        ]);
+        let rotmat = rotate_radians(0 as f64);
+        let scalemat = scale(
+            self.shield_health as f64 / 64.,
+            self.shield_health as f64 / 64.,
+        );
+        let translate = translate(*pos);
+        let draw_state = context.draw_state;
+        let image = Image::new().rect([0., 0., tex2.get_width() as f64, tex2.get_height() as f64]);
+        image.draw(
+            tex2,
+            &draw_state,
+            (Matrix(context.transform)
+                * Matrix(translate)
+                * Matrix(scalemat)
+                * Matrix(rotmat)
+                * Matrix(centerize))
+            .0,
+            g,
+        );
+    }
+}
+
+impl EnemyBehavior for SpiralEnemy {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "SpiralEnemy"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::SpiralEnemy
+    }
+
+    fn animate(&mut self, _state: &mut ShooterState) -> Option<DeathReason> {
+        self.base.rotation -= std::f32::consts::PI * 0.01;
+        self.base.animate()
+    }
+
+    fn collider(&self) -> Collider {
+        Collider::Obb {
+            center: self.base.pos,
+            half: [ENEMY_SIZE; 2],
+            angle: self.base.rotation as f64,
         }
     }
 
-    fn gen_bullets(
-        &mut self,
-        bullets: &mut EntitySet<Projectile>,
-        rng: &mut Xor128,
-        create_fn: impl Fn(BulletBase) -> Projectile,
-    ) {
-        let x = rng.gen_range(0, 256);
-        if x == 0 {
-            use std::f64::consts::PI;
-            let bullet_count = 10;
-            let phase_offset = rng.gen() * PI;
-            for i in 0..bullet_count {
-                let angle = 2. * PI * i as f64 / bullet_count as f64 + phase_offset;
-                let eb = create_fn(BulletBase(
-                    Entity::new(self.pos, vec2_scale([angle.cos(), angle.sin()], 1.))
-                        .rotation(angle as f32),
-                ));
-                bullets.insert(eb);
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        use crate::BOSS_SIZE;
+        self.base
+            .draw_tex(assets, gl, &assets.spiral_enemy_tex, Some([BOSS_SIZE; 2]));
+    }
+
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        self.base
+            .draw_tex(context, g, &assets.spiral_enemy_tex, Some(0.5));
+    }
+}
+
+impl EnemyBehavior for CentipedeEnemy {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "Centipede"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::Centipede
+    }
+
+    fn animate(&mut self, _state: &mut ShooterState) -> Option<DeathReason> {
+        let death = self.base.animate();
+        if let Some(DeathReason::Killed) = death {
+            return death;
+        }
+        let mut prev = self.base.pos;
+        let Some(first_joint) = self.joints.first_mut() else {
+            return Some(DeathReason::Killed);
+        };
+
+        first_joint.0 = self.base.pos;
+        let mut ret = false;
+        for joint in self.joints.iter_mut().skip(1) {
+            let delta = vec2_sub(joint.0, prev);
+            let dist = vec2_len(delta);
+            if JOINT_LENGTH < dist {
+                let normalized = vec2_scale(delta, JOINT_LENGTH / dist);
+                joint.0 = vec2_add(prev, normalized);
+            }
+            prev = joint.0;
+            let joint_rect = bounding_box(&joint.0, ENEMY_SIZE);
+            if bbox_intersects(&joint_rect, &SCREEN_RECT) {
+                ret = true;
             }
         }
+        if !ret {
+            Some(DeathReason::RangeOut)
+        } else {
+            None
+        }
     }
 
-    pub fn animate(&mut self, state: &mut ShooterState) -> Option<DeathReason> {
-        if self.is_boss() {
-            self.gen_bullets(&mut state.bullets, &mut state.rng, Projectile::new_phase);
-        } else if let Enemy::SpiralEnemy(_) = self {
-            self.gen_bullets(&mut state.bullets, &mut state.rng, Projectile::new_spiral);
+    fn damage(&mut self, val: i32, rect: &[f64; 4]) -> Option<Enemy> {
+        let self_velo = self.base.velo;
+
+        let damaged_joint = self.joints.iter_mut().enumerate().find(|(_, joint)| {
+            let rect2 = bounding_box(&joint.0, ENEMY_SIZE);
+            bbox_intersects(rect, &rect2)
+        });
+
+        if let Some((i, joint)) = damaged_joint {
+            joint.1 -= val;
+            if joint.1 <= 0 {
+                let joint_pos = joint.0;
+                if self.joints.len() == 1 {
+                    self.base.health = -1;
+                } else {
+                    let heading = self_velo[1].atan2(self_velo[0]) + std::f64::consts::PI / 2.;
+                    let speed = vec2_len(self_velo);
+                    let velo = [speed * heading.cos(), speed * heading.sin()];
+                    let back_joints = if i + 1 < self.joints.len() {
+                        Some(self.joints[i + 1..].to_vec())
+                    } else {
+                        None
+                    };
+                    self.joints.resize(i, CentipedeJoint::default());
+                    return back_joints.map(|back_joints| {
+                        Enemy::new_centipede_joints(joint_pos, velo, back_joints)
+                    });
+                }
+            }
         } else {
-            let x: u32 = state.rng.gen_range(0, 64);
-            if x == 0 {
-                let eb = Projectile::EnemyBullet(BulletBase(Entity::new(
-                    self.pos,
-                    [state.rng.gen() - 0.5, state.rng.gen() - 0.5],
-                )));
-                state.bullets.insert(eb);
+            self.base.health -= 1;
+        }
+        None
+    }
+
+    /// Only the joints can be hit.
+    fn test_hit(&self, collider: &Collider) -> bool {
+        self.joints
+            .iter()
+            .any(|joint| Collider::circle(joint.0, ENEMY_SIZE).intersects(collider))
+    }
+
+    fn body_bbs(&self) -> Vec<[f64; 4]> {
+        std::iter::once(self.collider().bounding_box())
+            .chain(
+                self.joints
+                    .iter()
+                    .map(|joint| bounding_box(&joint.0, ENEMY_SIZE)),
+            )
+            .collect()
+    }
+
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        use crate::CENTIPEDE_SIZE;
+
+        // Draw tails behind
+        let mut last_pos = None;
+        for (i, joint) in self.joints.iter().enumerate() {
+            let f = i as f64 / self.joints.len() as f64;
+            let rotation = if let Some(last_pos) = last_pos {
+                let delta = vec2_sub(joint.0, last_pos);
+                delta[1].atan2(delta[0])
+            } else {
+                self.base.velo[1].atan2(self.base.velo[0])
+            };
+            last_pos = Some(joint.0);
+            draw_tex(
+                &joint.0,
+                rotation,
+                assets,
+                gl,
+                if i == 0 {
+                    &assets.centipede_head_tex
+                } else {
+                    &assets.centipede_segment_tex
+                },
+                Some([(CENTIPEDE_SIZE * (1. - f) + ENEMY_SIZE * f); 2]),
+            );
+        }
+    }
+}
+
+impl EnemyBehavior for PhaseBoss {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "PhaseBoss"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::PhaseBoss
+    }
+
+    fn animate(&mut self, state: &mut ShooterState) -> Option<DeathReason> {
+        if self.phase == BossPhase::Entry {
+            if PHASE_BOSS_ENTRY_Y <= self.base.pos[1] {
+                self.base.velo = [0., 0.];
+                self.phase = BossPhase::Rings;
+                self.phase_time = 0;
+                state
+                    .events
+                    .push(GameEvent::BossPhaseChanged { phase: self.phase });
             }
+            return self.base.animate();
         }
 
-        match self {
-            Enemy::Enemy1(ref mut base) | Enemy::Boss(ref mut base) => base.animate(),
-            Enemy::ShieldedBoss(ref mut boss) => {
-                if boss.shield_health < 64 && state.time % 8 == 0 {
-                    boss.shield_health += 1;
+        let phase = self.phase_by_health();
+        if self.phase != phase {
+            self.phase = phase;
+            self.phase_time = 0;
+            state.events.push(GameEvent::BossPhaseChanged { phase });
+        }
+
+        // Sway from side to side around the center
+        self.base.velo[0] = (self.phase_time as f64 * 0.01).sin() * PHASE_BOSS_SPEED;
+        self.base.pos[0] = self.base.pos[0].clamp(PHASE_BOSS_SIZE, FWIDTH - PHASE_BOSS_SIZE);
+
+        let bullets = &mut state.bullets;
+        match self.phase {
+            BossPhase::Entry => unreachable!(),
+            BossPhase::Rings => {
+                if self.phase_time % 60 == 0 {
+                    self.fire_ring(bullets, 16, 1.);
+                    self.stream_angle += 0.3;
                 }
-                boss.base.animate()
             }
-            Enemy::SpiralEnemy(ref mut base) => {
-                base.rotation -= std::f32::consts::PI * 0.01;
-                base.animate()
+            BossPhase::Spirals => {
+                if self.phase_time % 6 == 0 {
+                    self.fire_streams(bullets, 4, 1.5);
+                    self.stream_angle += 0.1;
+                }
             }
-            Enemy::Centipede(centipede) => {
-                let death = centipede.base.animate();
-                if let Some(DeathReason::Killed) = death {
-                    return death;
+            BossPhase::Frenzy => {
+                if self.phase_time % 90 == 0 {
+                    self.fire_ring(bullets, 24, 1.2);
                 }
-                let mut prev = centipede.base.pos;
-                let Some(first_joint) = centipede.joints.first_mut() else {
-                    return Some(DeathReason::Killed);
-                };
-
-                if centipede.task_time < 1 {
-                    centipede.task = match state.rng.gen_range(0, 4) {
-                        0..2 => CentipedeTask::Straight,
-                        2 => CentipedeTask::TurnLeft,
-                        3 => CentipedeTask::TurnRight,
-                        _ => unreachable!(),
-                    };
-                    centipede.task_time = match centipede.task {
-                        CentipedeTask::Straight => CENTIPEDE_STRAIGHT_TASK_TIME,
-                        _ => CENTIPEDE_TURN_TASK_TIME,
-                    };
-                    console_log!("Centipede task: {:?}", centipede.task);
-                } else {
-                    centipede.task_time -= 1;
+                if self.phase_time % 8 == 0 {
+                    self.fire_streams(bullets, 5, 2.);
+                    self.stream_angle -= 0.13;
                 }
+            }
+        }
+        self.phase_time += 1;
 
-                match centipede.task {
-                    CentipedeTask::Straight => {}
-                    CentipedeTask::TurnLeft => {
-                        centipede.heading =
-                            (centipede.heading + TURN_RATE).rem_euclid(2. * std::f64::consts::PI);
-                    }
-                    CentipedeTask::TurnRight => {
-                        centipede.heading =
-                            (centipede.heading - TURN_RATE).rem_euclid(2. * std::f64::consts::PI);
-                    }
-                }
+        self.base.animate()
+    }
 
-                let speed = vec2_len(centipede.base.velo);
-                centipede.base.velo[0] = centipede.heading.cos() * speed;
-                centipede.base.velo[1] = centipede.heading.sin() * speed;
-
-                first_joint.0 = centipede.base.pos;
-                let mut ret = false;
-                for joint in centipede.joints.iter_mut().skip(1) {
-                    let delta = vec2_sub(joint.0, prev);
-                    let dist = vec2_len(delta);
-                    if JOINT_LENGTH < dist {
-                        let normalized = vec2_scale(delta, JOINT_LENGTH / dist);
-                        joint.0 = vec2_add(prev, normalized);
-                    }
-                    prev = joint.0;
-                    let joint_rect = bounding_box(&joint.0, ENEMY_SIZE);
-                    if bbox_intersects(&joint_rect, &SCREEN_RECT) {
-                        ret = true;
-                    }
-                }
-                if !ret {
-                    Some(DeathReason::RangeOut)
-                } else {
-                    None
-                }
-            }
+    /// Invulnerable during the entry.
+    fn damage(&mut self, val: i32, _rect: &[f64; 4]) -> Option<Enemy> {
+        if self.phase != BossPhase::Entry {
+            self.base.health -= val;
         }
+        None
+    }
+
+    fn collider(&self) -> Collider {
+        Collider::square(self.base.pos, PHASE_BOSS_SIZE)
+    }
+
+    fn is_boss(&self) -> bool {
+        true
+    }
+
+    /// Bosses with phases always drop a bomb
+    fn bomb_drop_chance(&self) -> u32 {
+        100
+    }
+
+    fn boss_health(&self) -> Option<f64> {
+        Some(self.health_ratio())
     }
 
     #[cfg(feature = "webgl")]
-    pub fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
-        use crate::{BOSS_SIZE, CENTIPEDE_SIZE};
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        self.base
+            .draw_tex(assets, gl, &assets.boss_tex, Some([PHASE_BOSS_SIZE; 2]));
+    }
 
-        use super::ENEMY_SIZE;
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        use crate::BOSS_SIZE;
+        self.base.draw_tex(
+            context,
+            g,
+            &assets.boss_tex,
+            Some(PHASE_BOSS_SIZE / BOSS_SIZE),
+        );
+    }
+}
 
-        // Draw tails behind
-        if let Enemy::Centipede(centipede) = self {
-            let mut last_pos = None;
-            for (i, joint) in centipede.joints.iter().enumerate() {
-                let f = i as f64 / centipede.joints.len() as f64;
-                let rotation = if let Some(last_pos) = last_pos {
-                    let delta = vec2_sub(joint.0, last_pos);
-                    delta[1].atan2(delta[0])
-                } else {
-                    self.velo[1].atan2(self.velo[0])
-                };
-                last_pos = Some(joint.0);
-                draw_tex(
-                    &joint.0,
-                    rotation,
-                    assets,
-                    gl,
-                    if i == 0 {
-                        &assets.centipede_head_tex
-                    } else {
-                        &assets.centipede_segment_tex
-                    },
-                    Some([(CENTIPEDE_SIZE * (1. - f) + ENEMY_SIZE * f); 2]),
-                );
-            }
-            return;
+impl EnemyBehavior for Kamikaze {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "Kamikaze"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::Kamikaze
+    }
+
+    fn animate(&mut self, state: &mut ShooterState) -> Option<DeathReason> {
+        let base = &mut self.base;
+        let delta = vec2_sub(state.player.base.pos, base.pos);
+        let accel = if base.age < KAMIKAZE_HOMING_TIME && f64::EPSILON < vec2_square_len(delta) {
+            vec2_scale(delta, KAMIKAZE_ACCEL / vec2_len(delta))
+        } else {
+            vec2_scale(
+                base.velo,
+                KAMIKAZE_ACCEL / vec2_len(base.velo).max(f64::EPSILON),
+            )
+        };
+        base.velo = vec2_add(base.velo, accel);
+        let speed = vec2_len(base.velo);
+        if KAMIKAZE_MAX_SPEED < speed {
+            base.velo = vec2_scale(base.velo, KAMIKAZE_MAX_SPEED / speed);
         }
+        base.rotation = base.velo[1].atan2(base.velo[0]) as f32;
+        base.animate()
+    }
 
-        self.draw_tex(
-            assets,
-            gl,
-            match self {
-                Enemy::Enemy1(_) => &assets.enemy_tex,
-                Enemy::Boss(_) | Enemy::ShieldedBoss(_) => &assets.boss_tex,
-                Enemy::SpiralEnemy(_) => &assets.spiral_enemy_tex,
-                _ => unreachable!(),
-            },
-            Some(match self {
-                Enemy::Enemy1(_) => [ENEMY_SIZE; 2],
-                Enemy::Boss(_) | Enemy::ShieldedBoss(_) | Enemy::SpiralEnemy(_) => [BOSS_SIZE; 2],
-                _ => unreachable!(),
-            }),
-        );
+    fn drop_item(&self, ent: Entity) -> Item {
+        Item::PowerUp(ent)
+    }
 
-        if let Enemy::ShieldedBoss(boss) = self {
-            self.draw_tex(
-                assets,
-                gl,
-                &assets.shield_tex,
-                Some([boss.shield_health as f64; 2]),
-            );
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        self.base
+            .draw_tex(assets, gl, &assets.kamikaze_tex, Some([ENEMY_SIZE; 2]));
+    }
+
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        self.base.draw_tex(context, g, &assets.kamikaze_tex, None);
+    }
+}
+
+impl EnemyBehavior for Turret {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "Turret"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::Turret
+    }
+
+    fn animate(&mut self, state: &mut ShooterState) -> Option<DeathReason> {
+        let base = &mut self.base;
+        if base.age == TURRET_STOP_TIME {
+            base.velo = [0., 0.];
+        } else if base.age == TURRET_LEAVE_TIME {
+            base.velo = [0., 0.5];
         }
+        let delta = vec2_sub(state.player.base.pos, base.pos);
+        base.rotation = delta[1].atan2(delta[0]) as f32;
+        base.animate()
+    }
+
+    fn collider(&self) -> Collider {
+        Collider::circle(self.base.pos, TURRET_SIZE)
+    }
+
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        self.base
+            .draw_tex(assets, gl, &assets.turret_tex, Some([TURRET_SIZE; 2]));
     }
 
     #[cfg(all(not(feature = "webgl"), feature = "piston"))]
-    pub fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
-        self.draw_tex(
+    fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        self.base.draw_tex(context, g, &assets.turret_tex, None);
+    }
+}
+
+impl EnemyBehavior for Splitter {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "Splitter"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::Splitter
+    }
+
+    fn damage(&mut self, val: i32, _rect: &[f64; 4]) -> Option<Enemy> {
+        self.base.health -= val;
+        if self.base.health <= 0 && 0 < self.generation {
+            return Some(Enemy::Splitter(self.split()));
+        }
+        None
+    }
+
+    fn collider(&self) -> Collider {
+        Collider::circle(self.base.pos, self.size())
+    }
+
+    fn drop_item(&self, ent: Entity) -> Item {
+        Item::PowerUp(ent)
+    }
+
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        self.base
+            .draw_tex(assets, gl, &assets.splitter_tex, Some([self.size(); 2]));
+    }
+
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        // The sprite is drawn at the size of the first generation
+        self.base.draw_tex(
             context,
             g,
-            match self {
-                Enemy::Enemy1(_) | Enemy::Centipede(_) => &assets.enemy_tex,
-                Enemy::Boss(_) | Enemy::ShieldedBoss(_) => &assets.boss_tex,
-                Enemy::SpiralEnemy(_) => &assets.spiral_enemy_tex,
-            },
-            if let Enemy::SpiralEnemy(_) = self {
-                Some(0.5)
-            } else {
-                None
-            },
+            &assets.splitter_tex,
+            Some(self.size() / splitter_size(SPLITTER_GENERATIONS)),
         );
-        if let Enemy::ShieldedBoss(ref boss) = self {
-            let pos = &boss.base.pos;
-            let tex2 = &*assets.shield_tex;
-            let centerize = translate([
-                -(tex2.get_width() as f64 / 2.),
-                -(tex2.get_height() as f64 / 2.),
-            ]);
-            let rotmat = rotate_radians(0 as f64);
-            let scalemat = scale(
-                boss.shield_health as f64 / 64.,
-                boss.shield_health as f64 / 64.,
-            );
-            let translate = translate(*pos);
-            let draw_state = context.draw_state;
-            let image =
-                Image::new().rect([0., 0., tex2.get_width() as f64, tex2.get_height() as f64]);
-            image.draw(
-                tex2,
-                &draw_state,
-                (Matrix(context.transform)
-                    * Matrix(translate)
-                    * Matrix(scalemat)
-                    * Matrix(rotmat)
-                    * Matrix(centerize))
-                .0,
-                g,
-            );
+    }
+}
+
+impl EnemyBehavior for MineLayer {
+    enemy_base!();
+
+    fn name(&self) -> &str {
+        "MineLayer"
+    }
+
+    fn kind(&self) -> EnemyKind {
+        EnemyKind::MineLayer
+    }
+
+    fn collider(&self) -> Collider {
+        Collider::circle(self.base.pos, MINE_LAYER_SIZE)
+    }
+
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, gl: &GL, assets: &Assets) {
+        self.base.draw_tex(
+            assets,
+            gl,
+            &assets.mine_layer_tex,
+            Some([MINE_LAYER_SIZE; 2]),
+        );
+    }
+
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        self.base.draw_tex(context, g, &assets.mine_layer_tex, None);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Enemy {
+    Enemy1(Enemy1),
+    Boss(Boss),
+    ShieldedBoss(ShieldedBoss),
+    SpiralEnemy(SpiralEnemy),
+    Centipede(CentipedeEnemy),
+    PhaseBoss(PhaseBoss),
+    Kamikaze(Kamikaze),
+    Turret(Turret),
+    Splitter(Splitter),
+    MineLayer(MineLayer),
+    /// An enemy added by the game through the [`EnemyRegistry`]
+    Custom(CustomEnemy),
+}
+
+impl Deref for Enemy {
+    type Target = EnemyBase;
+    fn deref(&self) -> &EnemyBase {
+        self.logic().base()
+    }
+}
+
+impl DerefMut for Enemy {
+    fn deref_mut(&mut self) -> &mut EnemyBase {
+        self.logic_mut().base_mut()
+    }
+}
+
+impl Enemy {
+    /// The logic of the enemy, which the other methods dispatch to.
+    pub fn logic(&self) -> &dyn EnemyBehavior {
+        match self {
+            Enemy::Enemy1(enemy) => enemy,
+            Enemy::Boss(boss) => boss,
+            Enemy::ShieldedBoss(boss) => boss,
+            Enemy::SpiralEnemy(enemy) => enemy,
+            Enemy::Centipede(centipede) => centipede,
+            Enemy::PhaseBoss(boss) => boss,
+            Enemy::Kamikaze(enemy) => enemy,
+            Enemy::Turret(enemy) => enemy,
+            Enemy::Splitter(splitter) => splitter,
+            Enemy::MineLayer(enemy) => enemy,
+            Enemy::Custom(custom) => custom.logic(),
         }
     }
 
-    pub fn test_hit(&self, rect: [f64; 4]) -> bool {
-        if let Enemy::Centipede(centipede) = self {
-            return centipede.joints.iter().any(|joint| {
-                let rect2 = bounding_box(&joint.0, ENEMY_SIZE);
-                bbox_intersects(&rect, &rect2)
-            });
+    pub fn logic_mut(&mut self) -> &mut dyn EnemyBehavior {
+        match self {
+            Enemy::Enemy1(enemy) => enemy,
+            Enemy::Boss(boss) => boss,
+            Enemy::ShieldedBoss(boss) => boss,
+            Enemy::SpiralEnemy(enemy) => enemy,
+            Enemy::Centipede(centipede) => centipede,
+            Enemy::PhaseBoss(boss) => boss,
+            Enemy::Kamikaze(enemy) => enemy,
+            Enemy::Turret(enemy) => enemy,
+            Enemy::Splitter(splitter) => splitter,
+            Enemy::MineLayer(enemy) => enemy,
+            Enemy::Custom(custom) => custom.logic_mut(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.logic().name()
+    }
+
+    pub fn kind(&self) -> EnemyKind {
+        self.logic().kind()
+    }
+
+    /// Apply damage to this enemy, within specified rectangle area.
+    /// The area can be important for patial damages.
+    pub fn damage(&mut self, val: i32, rect: &[f64; 4]) -> Option<Enemy> {
+        self.logic_mut().damage(val, rect)
+    }
+
+    pub fn predicted_damage(&self) -> i32 {
+        self.predicted_damage
+    }
+
+    pub fn add_predicted_damage(&mut self, val: i32) {
+        self.predicted_damage += val;
+    }
+
+    pub fn total_health(&self) -> i32 {
+        self.health
+    }
+
+    pub fn drop_item(&self, ent: Entity) -> Item {
+        self.logic().drop_item(ent)
+    }
+
+    pub fn drop_chance(&self) -> u32 {
+        self.logic().drop_chance()
+    }
+
+    pub fn bomb_drop_chance(&self) -> u32 {
+        self.logic().bomb_drop_chance()
+    }
+
+    pub fn score(&self) -> u32 {
+        self.logic().score()
+    }
+
+    pub fn boss_health(&self) -> Option<f64> {
+        self.logic().boss_health()
+    }
+
+    pub fn animate(&mut self, state: &mut ShooterState) -> Option<DeathReason> {
+        self.age += 1;
+        if self.age == 1 {
+            // Start firing the pattern of the kind, which the behavior can change
+            self.fire = state
+                .bullet_patterns
+                .enemy_pattern(self.kind())
+                .map(PatternRunner::new);
+        }
+
+        let base: &mut EnemyBase = self;
+        if let Some(mut behavior) = base.behavior.take() {
+            behavior.step(base, state);
+            base.behavior = Some(behavior);
+        }
+
+        let pos = self.pos;
+        if let Some(runner) = &mut self.fire {
+            runner.advance(
+                &state.bullet_patterns,
+                pos,
+                &state.player.base,
+                state.player.rank(),
+                &mut state.rng,
+                &mut state.bullets,
+            );
         }
-        let rect2 = self.get_bb();
-        bbox_intersects(&rect, &rect2)
+
+        let base: &mut EnemyBase = self;
+        if let Some(route) = &mut base.route {
+            if let Some(velo) = route.advance(base.base.pos, base.age, state.player.base.pos) {
+                base.base.velo = velo;
+            }
+        }
+
+        self.logic_mut().animate(state)
+    }
+
+    #[cfg(feature = "webgl")]
+    pub fn draw(&self, state: &ShooterState, gl: &GL, assets: &Assets) {
+        self.logic().draw(state, gl, assets);
+    }
+
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    pub fn draw(&self, context: &Context, g: &mut G2d, assets: &Assets) {
+        self.logic().draw(context, g, assets);
+    }
+
+    /// Whether `collider` hits the enemy. Only the joints of a centipede can be hit.
+    pub fn test_hit(&self, collider: &Collider) -> bool {
+        self.logic().test_hit(collider)
+    }
+
+    /// Whether the body, including all the joints of a centipede, touches `collider`.
+    pub fn touches(&self, collider: &Collider) -> bool {
+        self.logic().touches(collider)
+    }
+
+    /// The shape of the body, or the head of a centipede.
+    pub fn collider(&self) -> Collider {
+        self.logic().collider()
     }
 
     pub fn get_bb(&self) -> [f64; 4] {
-        let size = if let Enemy::ShieldedBoss(boss) = self {
-            boss.shield_health as f64
-        } else {
-            ENEMY_SIZE
-        };
-        bounding_box(&self.pos, size)
+        self.collider().bounding_box()
+    }
+
+    /// The bounding boxes of the whole body, which has more than one box for a centipede.
+    pub fn body_bbs(&self) -> Vec<[f64; 4]> {
+        self.logic().body_bbs()
     }
 
     pub fn is_boss(&self) -> bool {
-        matches!(self, Enemy::Boss(_) | Enemy::ShieldedBoss(_))
+        self.logic().is_boss()
+    }
+
+    /// Create a newly spawned enemy of the given kind.
+    ///
+    /// Custom enemies are spawned by name with [`EnemyRegistry::spawn`] or
+    /// [`EnemyRegistry::spawn_kind`] instead, and the one spawned by [`EnemyKind::Custom`]
+    /// alone is removed in the next frame.
+    pub fn spawn(kind: EnemyKind, pos: [f64; 2], velo: [f64; 2]) -> Enemy {
+        let base = EnemyBase::new(pos, velo);
+        match kind {
+            EnemyKind::Enemy1 => Enemy::Enemy1(Enemy1 {
+                base: base.health(3),
+            }),
+            EnemyKind::Boss => Enemy::Boss(Boss {
+                base: base.health(64),
+            }),
+            EnemyKind::ShieldedBoss => Enemy::ShieldedBoss(ShieldedBoss::new(pos, velo)),
+            EnemyKind::SpiralEnemy => Enemy::new_spiral(pos, velo),
+            EnemyKind::Centipede => Enemy::new_centipede(pos, velo),
+            // The boss makes its own entry
+            EnemyKind::PhaseBoss => Enemy::PhaseBoss(PhaseBoss::new(pos)),
+            EnemyKind::Kamikaze => Enemy::Kamikaze(Kamikaze {
+                base: base.health(2),
+            }),
+            EnemyKind::Turret => Enemy::Turret(Turret {
+                base: base.health(12),
+            }),
+            EnemyKind::Splitter => Enemy::Splitter(Splitter::new(pos, velo, SPLITTER_GENERATIONS)),
+            EnemyKind::MineLayer => Enemy::MineLayer(MineLayer {
+                base: base.health(8),
+            }),
+            EnemyKind::Custom => Enemy::Custom(CustomEnemy::unknown(base)),
+        }
     }
 
     pub fn new_spiral(pos: [f64; 2], velo: [f64; 2]) -> Enemy {
-        Enemy::SpiralEnemy(EnemyBase::new(pos, velo))
+        Enemy::SpiralEnemy(SpiralEnemy {
+            base: EnemyBase::new(pos, velo),
+        })
     }
 
     pub fn new_centipede(pos: [f64; 2], velo: [f64; 2]) -> Enemy {
-        Enemy::Centipede(CentipedeEnemy {
-            // The head is particularly tough
-            base: EnemyBase::new(pos, velo).health(32),
-            joints: vec![CentipedeJoint(pos, 16); 10],
-            task: CentipedeTask::Straight,
-            task_time: CENTIPEDE_STRAIGHT_TASK_TIME,
-            heading: velo[1].atan2(velo[0]),
-        })
+        Enemy::new_centipede_joints(pos, velo, vec![CentipedeJoint(pos, 16); 10])
     }
 
     fn new_centipede_joints(pos: [f64; 2], velo: [f64; 2], joints: Vec<CentipedeJoint>) -> Enemy {
         Enemy::Centipede(CentipedeEnemy {
             // The head is particularly tough
-            base: EnemyBase::new(pos, velo).health(32),
+            base: EnemyBase::new(pos, velo)
+                .health(32)
+                .behavior(centipede_behavior()),
             joints,
-            task: CentipedeTask::Straight,
-            task_time: CENTIPEDE_STRAIGHT_TASK_TIME,
-            heading: velo[1].atan2(velo[0]),
         })
     }
 }
+
+#[test]
+fn centipede_contact() {
+    let joints = (1..5)
+        .map(|i| CentipedeJoint([100., 100. + i as f64 * 16.], 16))
+        .collect();
+    let centipede = Enemy::new_centipede_joints([100., 100.], [0., -1.], joints);
+    let at = |pos| Collider::circle(pos, crate::consts::PLAYER_HITBOX_RADIUS);
+    // The head can be touched, but not shot
+    assert!(centipede.touches(&at([100., 100.])));
+    assert!(!centipede.test_hit(&at([100., 100.])));
+    assert!(centipede.touches(&at([100., 148.])));
+    assert!(centipede.test_hit(&at([100., 148.])));
+    assert!(!centipede.touches(&at([130., 148.])));
+}
@@ -0,0 +1,195 @@
+//! Enemies added by the game, beside the built-in kinds.
+//!
+//! A custom enemy implements [`EnemyBehavior`] and is registered by name, with a function
+//! to spawn it and another to restore it from a snapshot. A snapshot saves the name, the
+//! base and [`EnemyBehavior::save`] of a custom enemy, and the enemy stays inert until the
+//! registry of the loaded state restores it at the start of the next frame.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(all(not(feature = "webgl"), feature = "piston"))]
+use crate::assets_piston::Assets;
+#[cfg(feature = "webgl")]
+use crate::assets_webgl::Assets;
+#[cfg(all(not(feature = "webgl"), feature = "piston"))]
+use piston_window::{Context, G2d};
+#[cfg(feature = "webgl")]
+use web_sys::WebGlRenderingContext as GL;
+
+use super::{Collider, DeathReason, Enemy, EnemyBase, EnemyBehavior, EnemyKind};
+use crate::ShooterState;
+
+/// Creates a newly spawned enemy at a position with a velocity.
+pub type SpawnFn = fn([f64; 2], [f64; 2]) -> Box<dyn EnemyBehavior>;
+
+/// Restores an enemy from its base and the data it saved, or returns `None` if the data
+/// is broken.
+pub type LoadFn = fn(EnemyBase, &[u8]) -> Option<Box<dyn EnemyBehavior>>;
+
+/// The custom enemies by name. It is not saved in snapshots, so the game registers them
+/// again after loading one.
+#[derive(Default)]
+pub struct EnemyRegistry {
+    entries: BTreeMap<String, (SpawnFn, LoadFn)>,
+}
+
+impl EnemyRegistry {
+    /// Register an enemy under `name`, which its [`EnemyBehavior::name`] must return.
+    pub fn register(&mut self, name: &str, spawn: SpawnFn, load: LoadFn) {
+        self.entries.insert(name.to_string(), (spawn, load));
+    }
+
+    /// The names of the registered enemies in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(|name| name.as_str())
+    }
+
+    /// Create a newly spawned enemy by name, if it is registered.
+    pub fn spawn(&self, name: &str, pos: [f64; 2], velo: [f64; 2]) -> Option<Enemy> {
+        let (spawn, _) = self.entries.get(name)?;
+        Some(Enemy::Custom(CustomEnemy::Loaded(spawn(pos, velo))))
+    }
+
+    /// Create a newly spawned enemy of `kind`, or the one registered as `custom` if the
+    /// kind is [`EnemyKind::Custom`], as the data files refer to them.
+    pub fn spawn_kind(
+        &self,
+        kind: EnemyKind,
+        custom: Option<&str>,
+        pos: [f64; 2],
+        velo: [f64; 2],
+    ) -> Enemy {
+        if kind == EnemyKind::Custom {
+            if let Some(enemy) = custom.and_then(|name| self.spawn(name, pos, velo)) {
+                return enemy;
+            }
+            console_log!("Custom enemy {:?} is not registered", custom);
+        }
+        Enemy::spawn(kind, pos, velo)
+    }
+
+    /// Restore a custom enemy loaded from a snapshot. An enemy which is not registered or
+    /// fails to load is left inert, and is removed in the next frame.
+    pub fn restore(&self, enemy: &mut Enemy) {
+        let Enemy::Custom(CustomEnemy::Unloaded(unloaded)) = enemy else {
+            return;
+        };
+        let Some((_, load)) = self.entries.get(&unloaded.name) else {
+            return;
+        };
+        let base = std::mem::replace(&mut unloaded.base, EnemyBase::new([0., 0.], [0., 0.]));
+        if let Some(logic) = load(base, &unloaded.data) {
+            *enemy = Enemy::Custom(CustomEnemy::Loaded(logic));
+        }
+    }
+}
+
+/// A custom enemy, or the saved state of one waiting for the registry to restore it.
+pub enum CustomEnemy {
+    Loaded(Box<dyn EnemyBehavior>),
+    Unloaded(Box<UnloadedEnemy>),
+}
+
+impl CustomEnemy {
+    /// An enemy which no registered enemy restores from.
+    pub(super) fn unknown(base: EnemyBase) -> Self {
+        Self::Unloaded(Box::new(UnloadedEnemy {
+            name: String::new(),
+            base,
+            data: vec![],
+        }))
+    }
+
+    pub fn logic(&self) -> &dyn EnemyBehavior {
+        match self {
+            Self::Loaded(logic) => logic.as_ref(),
+            Self::Unloaded(unloaded) => unloaded.as_ref(),
+        }
+    }
+
+    pub fn logic_mut(&mut self) -> &mut dyn EnemyBehavior {
+        match self {
+            Self::Loaded(logic) => logic.as_mut(),
+            Self::Unloaded(unloaded) => unloaded.as_mut(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SavedEnemy<'a> {
+    name: &'a str,
+    base: &'a EnemyBase,
+    data: Vec<u8>,
+}
+
+impl Serialize for CustomEnemy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let logic = self.logic();
+        SavedEnemy {
+            name: logic.name(),
+            base: logic.base(),
+            data: logic.save(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CustomEnemy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        UnloadedEnemy::deserialize(deserializer).map(|unloaded| Self::Unloaded(Box::new(unloaded)))
+    }
+}
+
+/// The saved state of a custom enemy. It can neither be hit nor touched.
+#[derive(Deserialize)]
+pub struct UnloadedEnemy {
+    name: String,
+    base: EnemyBase,
+    data: Vec<u8>,
+}
+
+impl EnemyBehavior for UnloadedEnemy {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn base(&self) -> &EnemyBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut EnemyBase {
+        &mut self.base
+    }
+
+    fn animate(&mut self, _state: &mut ShooterState) -> Option<DeathReason> {
+        Some(DeathReason::RangeOut)
+    }
+
+    fn damage(&mut self, _val: i32, _rect: &[f64; 4]) -> Option<Enemy> {
+        None
+    }
+
+    fn test_hit(&self, _collider: &Collider) -> bool {
+        false
+    }
+
+    fn touches(&self, _collider: &Collider) -> bool {
+        false
+    }
+
+    fn body_bbs(&self) -> Vec<[f64; 4]> {
+        vec![]
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    #[cfg(feature = "webgl")]
+    fn draw(&self, _state: &ShooterState, _gl: &GL, _assets: &Assets) {}
+
+    #[cfg(all(not(feature = "webgl"), feature = "piston"))]
+    fn draw(&self, _context: &Context, _g: &mut G2d, _assets: &Assets) {}
+}
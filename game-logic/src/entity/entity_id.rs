@@ -1,5 +1,7 @@
 use std::{fmt::Display, marker::PhantomData};
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
 use super::RefOption;
 
 pub struct EntityId<T> {
@@ -10,11 +12,7 @@ pub struct EntityId<T> {
 
 impl<T> std::clone::Clone for EntityId<T> {
     fn clone(&self) -> Self {
-        Self {
-            id: self.id,
-            gen: self.gen,
-            _ph: self._ph,
-        }
+        *self
     }
 }
 
@@ -41,6 +39,21 @@ impl<T> std::hash::Hash for EntityId<T> {
     }
 }
 
+/// Serialized as a tuple of (id, gen), so that references between entities survive
+/// a save and load of the EntitySet they point into.
+impl<T> Serialize for EntityId<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.id, self.gen).serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for EntityId<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (id, gen) = <(u32, u32)>::deserialize(deserializer)?;
+        Ok(Self::new(id, gen))
+    }
+}
+
 impl<T> EntityId<T> {
     pub(super) fn new(id: u32, gen: u32) -> Self {
         Self {
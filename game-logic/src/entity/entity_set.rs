@@ -1,8 +1,10 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+
+use serde::{Deserialize, Serialize};
 
 use super::{EntityId, RefMutOption, RefOption};
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 /// An entry in entity list with generational ids, with the payload and the generation
 pub struct EntityEntry<T> {
     pub gen: u32,
@@ -10,47 +12,65 @@ pub struct EntityEntry<T> {
 }
 
 impl<T> EntityEntry<T> {
-    pub(crate) fn new(payload: T) -> Self {
+    pub(crate) fn new(payload: T, gen: u32) -> Self {
         Self {
-            gen: 0,
+            gen,
             payload: RefCell::new(Some(payload)),
         }
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct EntitySet<T> {
     v: Vec<EntityEntry<T>>,
+    /// Indices of vacant entries to be reused by insert, the last one first.
+    /// It is in a RefCell because `retain_borrow_mut` removes items through a shared reference.
+    free: RefCell<Vec<u32>>,
+    /// The number of active elements
+    len: Cell<usize>,
+    /// The generation of newly pushed entries. It is raised above the generations of the
+    /// entries dropped by `shrink`, so that stale ids to them never match a new entity.
+    gen_floor: u32,
 }
 
 impl<T> Default for EntitySet<T> {
     fn default() -> Self {
-        Self { v: vec![] }
+        Self::new()
     }
 }
 
 impl<T> EntitySet<T> {
     pub fn new() -> Self {
-        Self { v: vec![] }
+        Self {
+            v: vec![],
+            free: RefCell::new(vec![]),
+            len: Cell::new(0),
+            gen_floor: 0,
+        }
     }
 
     pub fn clear(&mut self) {
+        for entry in &self.v {
+            self.gen_floor = self.gen_floor.max(entry.gen + 1);
+        }
         self.v.clear();
+        self.free.get_mut().clear();
+        self.len.set(0);
     }
 
     /// Returns the number of active elements in this EntitySet.
     /// It does _not_ return the buffer length.
     pub fn len(&self) -> usize {
-        // TODO: optimize by caching active elements
-        self.v
-            .iter()
-            .filter(|entry| entry.payload.borrow().is_some())
-            .count()
+        self.len.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     /// Return an iterator over Ref<T>.
     /// It borrows the T immutably.
-    pub fn iter(&self) -> impl Iterator<Item = RefOption<T>> {
+    pub fn iter(&self) -> impl Iterator<Item = RefOption<'_, T>> {
         self.v.iter().filter_map(|v| RefOption::new(&v.payload))
     }
 
@@ -64,14 +84,14 @@ impl<T> EntitySet<T> {
 
     /// Return an iterator over RefMut<T>, skipping already borrowed items.
     /// It borrows the T mutablly.
-    pub fn iter_borrow_mut(&self) -> impl Iterator<Item = RefMutOption<T>> {
+    pub fn iter_borrow_mut(&self) -> impl Iterator<Item = RefMutOption<'_, T>> {
         self.v.iter().filter_map(|v| RefMutOption::new(&v.payload))
     }
 
     /// Return an iterator over (id, Ref<T>)
     /// It is convenient when you want the EntityId of the iterated items.
     /// It borrows the T immutably.
-    pub fn items(&self) -> impl Iterator<Item = (EntityId<T>, RefOption<T>)> {
+    pub fn items(&self) -> impl Iterator<Item = (EntityId<T>, RefOption<'_, T>)> {
         self.v.iter().enumerate().filter_map(|(i, v)| {
             Some((EntityId::new(i as u32, v.gen), RefOption::new(&v.payload)?))
         })
@@ -92,7 +112,7 @@ impl<T> EntitySet<T> {
     /// Return an iterator over (id, RefMut<T>), skipping already borrowed items.
     /// It is convenient when you want the EntityId of the iterated items.
     /// It borrows the T mutablly.
-    pub fn items_borrow_mut(&self) -> impl Iterator<Item = (EntityId<T>, RefMutOption<T>)> {
+    pub fn items_borrow_mut(&self) -> impl Iterator<Item = (EntityId<T>, RefMutOption<'_, T>)> {
         self.v.iter().enumerate().filter_map(|(i, v)| {
             Some((
                 EntityId::new(i as u32, v.gen),
@@ -119,38 +139,39 @@ impl<T> EntitySet<T> {
     //     Some((center.payload.as_mut()?.get_mut(), EntitySliceMut([first, last])))
     // }
 
+    /// Insert an item into a vacant entry, or at the end if there is none, in O(1).
     pub fn insert(&mut self, val: T) -> EntityId<T> {
-        for (i, entry) in self.v.iter_mut().enumerate() {
-            let payload = entry.payload.get_mut();
-            if payload.is_none() {
-                entry.gen += 1;
-                entry.payload = RefCell::new(Some(val));
-                return EntityId::new(i as u32, entry.gen);
-            }
+        self.len.set(self.len.get() + 1);
+        if let Some(i) = self.free.get_mut().pop() {
+            let entry = &mut self.v[i as usize];
+            entry.gen += 1;
+            *entry.payload.get_mut() = Some(val);
+            return EntityId::new(i, entry.gen);
         }
-        self.v.push(EntityEntry::new(val));
-        EntityId::new(self.v.len() as u32 - 1, 0)
+        self.v.push(EntityEntry::new(val, self.gen_floor));
+        EntityId::new(self.v.len() as u32 - 1, self.gen_floor)
+    }
+
+    /// Mark the entry at `idx` vacant after its payload was taken.
+    fn release(&self, idx: usize) {
+        self.free.borrow_mut().push(idx as u32);
+        self.len.set(self.len.get() - 1);
     }
 
     pub fn remove(&mut self, id: EntityId<T>) -> Option<T> {
-        self.v.get_mut(id.id as usize).and_then(|entry| {
-            if id.gen == entry.gen {
-                entry.payload.get_mut().take()
-            } else {
-                None
-            }
-        })
+        let entry = self.v.get_mut(id.id as usize)?;
+        if id.gen != entry.gen {
+            return None;
+        }
+        let ret = entry.payload.get_mut().take();
+        if ret.is_some() {
+            self.release(id.id as usize);
+        }
+        ret
     }
 
     pub fn retain(&mut self, mut f: impl FnMut(&mut T) -> bool) {
-        for entry in &mut self.v {
-            let Some(payload) = entry.payload.get_mut().as_mut() else {
-                continue;
-            };
-            if !f(payload) {
-                entry.payload = RefCell::new(None);
-            }
-        }
+        self.retain_id(|_, payload| f(payload));
     }
 
     pub fn retain_id(&mut self, mut f: impl FnMut(EntityId<T>, &mut T) -> bool) {
@@ -160,7 +181,9 @@ impl<T> EntitySet<T> {
             };
             let id = EntityId::new(i as u32, entry.gen);
             if !f(id, payload) {
-                entry.payload = RefCell::new(None);
+                *entry.payload.get_mut() = None;
+                self.free.get_mut().push(i as u32);
+                self.len.set(self.len.get() - 1);
             }
         }
     }
@@ -178,11 +201,34 @@ impl<T> EntitySet<T> {
                 EntityId::new(id as u32, entry.gen),
             ) {
                 *payload = None;
+                self.release(id);
             }
         }
     }
 
-    pub fn get(&self, id: EntityId<T>) -> Option<RefOption<T>> {
+    /// Drop the vacant entries at the end of the buffer and release the unused memory,
+    /// e.g. after a wave with lots of bullets.
+    /// Active items are never moved, so their ids stay valid.
+    pub fn shrink(&mut self) {
+        let new_len = self
+            .v
+            .iter_mut()
+            .rposition(|entry| entry.payload.get_mut().is_some())
+            .map_or(0, |i| i + 1);
+        if new_len == self.v.len() {
+            return;
+        }
+        for entry in &self.v[new_len..] {
+            self.gen_floor = self.gen_floor.max(entry.gen + 1);
+        }
+        self.v.truncate(new_len);
+        self.v.shrink_to_fit();
+        let free = self.free.get_mut();
+        free.retain(|i| (*i as usize) < new_len);
+        free.shrink_to_fit();
+    }
+
+    pub fn get(&self, id: EntityId<T>) -> Option<RefOption<'_, T>> {
         self.v.get(id.id as usize).and_then(|entry| {
             if id.gen == entry.gen {
                 RefOption::new(&entry.payload)
@@ -202,6 +248,17 @@ impl<T> EntitySet<T> {
         })
     }
 
+    /// Borrow mutably through a shared reference, skipping already borrowed items.
+    pub fn borrow_mut(&self, id: EntityId<T>) -> Option<RefMutOption<'_, T>> {
+        self.v.get(id.id as usize).and_then(|entry| {
+            if id.gen == entry.gen {
+                RefMutOption::new(&entry.payload)
+            } else {
+                None
+            }
+        })
+    }
+
     /// Get without generation check
     pub fn get_mut_at(&mut self, idx: usize) -> Option<&mut T> {
         self.v
@@ -209,7 +266,7 @@ impl<T> EntitySet<T> {
             .and_then(|entry| entry.payload.get_mut().as_mut())
     }
 
-    pub fn borrow_mut_at(&self, idx: usize) -> Option<RefMutOption<T>> {
+    pub fn borrow_mut_at(&self, idx: usize) -> Option<RefMutOption<'_, T>> {
         self.v
             .get(idx)
             .and_then(|entry| RefMutOption::new(&entry.payload))
@@ -233,3 +290,51 @@ impl<'a, T> IntoIterator for &'a mut EntitySet<T> {
         Box::new(self.iter_mut()) as Box<_>
     }
 }
+
+/// An inefficient (boxed) consuming iterator for convenicence
+impl<T: 'static> IntoIterator for EntitySet<T> {
+    type Item = T;
+    type IntoIter = Box<dyn Iterator<Item = T>>;
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(
+            self.v
+                .into_iter()
+                .filter_map(|entry| entry.payload.into_inner()),
+        ) as Box<_>
+    }
+}
+
+#[test]
+fn entity_set_reuse() {
+    let mut set = EntitySet::new();
+    let ids: Vec<_> = (0..10).map(|i| set.insert(i)).collect();
+    assert_eq!(set.len(), 10);
+
+    set.retain(|v| *v % 2 == 0);
+    assert_eq!(set.len(), 5);
+    assert!(set.get(ids[1]).is_none());
+    assert_eq!(set.remove(ids[4]), Some(4));
+    assert_eq!(set.remove(ids[4]), None);
+    assert_eq!(set.len(), 4);
+
+    // A reused entry must not be reachable by the stale id
+    let id = set.insert(100);
+    assert_eq!(id.id, ids[4].id);
+    assert!(set.get(ids[4]).is_none());
+    assert_eq!(*set.get(id).unwrap(), 100);
+    assert_eq!(set.len(), 5);
+
+    // Only the vacant entries at the end are dropped
+    set.retain(|v| *v < 8);
+    set.shrink();
+    assert_eq!(set.v.len(), 7);
+    assert_eq!(set.len(), 3);
+    assert_eq!(*set.get(ids[6]).unwrap(), 6);
+
+    // Neither are the entries dropped by shrink reachable by the stale ids
+    let ids2: Vec<_> = (0..10).map(|i| set.insert(i)).collect();
+    assert!(set.get(ids[8]).is_none());
+    assert!(ids2.iter().all(|id| set.get(*id).is_some()));
+    assert_eq!(set.len(), 13);
+    assert_eq!(set.iter().count(), 13);
+}
@@ -0,0 +1,303 @@
+//! Routes for enemies to move along, instead of going straight with the velocity they
+//! spawned with.
+//!
+//! A route is a list of legs, each of which follows a path, moves to a point, holds the
+//! position or dives at the player. The paths are relative to where their leg starts, so
+//! that the same path can be used from anywhere by any enemy kind.
+
+use serde::{Deserialize, Serialize};
+use vecmath::{vec2_add, vec2_len, vec2_scale, vec2_sub};
+
+/// A curve starting at the origin, traced over time.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Path {
+    /// Go straight with a velocity
+    Line { velo: [f64; 2] },
+    /// Go with a velocity, weaving from side to side by `amplitude` every `period` frames
+    Sine {
+        velo: [f64; 2],
+        amplitude: f64,
+        period: f64,
+    },
+    /// A Bezier curve with the control points after the origin, traced in `frames`
+    Bezier { points: Vec<[f64; 2]>, frames: u32 },
+    /// A Catmull-Rom spline passing the points after the origin, traced in `frames`
+    CatmullRom { points: Vec<[f64; 2]>, frames: u32 },
+}
+
+impl Path {
+    /// The frames to trace the curve, or `None` if it goes on forever.
+    /// A curve goes on straight in its end direction after that.
+    pub fn frames(&self) -> Option<u32> {
+        match self {
+            Path::Line { .. } | Path::Sine { .. } => None,
+            Path::Bezier { frames, .. } | Path::CatmullRom { frames, .. } => Some(*frames),
+        }
+    }
+
+    /// Make sure that the position is defined at any time.
+    pub fn check(&self) -> Result<(), &'static str> {
+        match self {
+            Path::Sine { period, .. } if *period <= 0. || period.is_nan() => {
+                Err("the period of Sine must be positive")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The position at `time` frames, relative to the start.
+    pub fn offset(&self, time: u32) -> [f64; 2] {
+        let t = time as f64;
+        match self {
+            Path::Line { velo } => vec2_scale(*velo, t),
+            Path::Sine {
+                velo,
+                amplitude,
+                period,
+            } => {
+                let speed = vec2_len(*velo);
+                let side = if speed == 0. {
+                    [1., 0.]
+                } else {
+                    [-velo[1] / speed, velo[0] / speed]
+                };
+                let weave = amplitude * (2. * std::f64::consts::PI * t / period).sin();
+                vec2_add(vec2_scale(*velo, t), vec2_scale(side, weave))
+            }
+            Path::Bezier { points, frames } => {
+                let points = with_origin(points);
+                let frames = (*frames).max(1) as f64;
+                if t <= frames {
+                    return bezier(&points, t / frames);
+                }
+                let n = points.len() - 1;
+                let end = points[n];
+                let tangent = if n == 0 {
+                    [0., 0.]
+                } else {
+                    vec2_scale(vec2_sub(end, points[n - 1]), n as f64 / frames)
+                };
+                vec2_add(end, vec2_scale(tangent, t - frames))
+            }
+            Path::CatmullRom { points, frames } => {
+                let points = with_origin(points);
+                let frames = (*frames).max(1) as f64;
+                let segments = (points.len() - 1) as f64;
+                let point = |i: isize| points[i.clamp(0, points.len() as isize - 1) as usize];
+                if t <= frames && 0. < segments {
+                    let u = t / frames * segments;
+                    let i = (u.floor() as isize).min(points.len() as isize - 2);
+                    let s = u - i as f64;
+                    return catmull_rom([point(i - 1), point(i), point(i + 1), point(i + 2)], s);
+                }
+                let last = points.len() as isize - 1;
+                let tangent = vec2_scale(
+                    vec2_sub(point(last), point(last - 1)),
+                    0.5 * segments / frames,
+                );
+                vec2_add(point(last), vec2_scale(tangent, t - frames))
+            }
+        }
+    }
+}
+
+fn with_origin(points: &[[f64; 2]]) -> Vec<[f64; 2]> {
+    std::iter::once([0., 0.])
+        .chain(points.iter().copied())
+        .collect()
+}
+
+/// Evaluate the curve with De Casteljau's algorithm.
+fn bezier(points: &[[f64; 2]], t: f64) -> [f64; 2] {
+    let mut points = points.to_vec();
+    for n in (1..points.len()).rev() {
+        for i in 0..n {
+            points[i] = vec2_add(vec2_scale(points[i], 1. - t), vec2_scale(points[i + 1], t));
+        }
+    }
+    points[0]
+}
+
+/// The point at `s` between `p[1]` and `p[2]`.
+fn catmull_rom(p: [[f64; 2]; 4], s: f64) -> [f64; 2] {
+    let (s2, s3) = (s * s, s * s * s);
+    let axis = |k: usize| {
+        let [p0, p1, p2, p3] = p.map(|p| p[k]);
+        0.5 * (2. * p1
+            + (p2 - p0) * s
+            + (2. * p0 - 5. * p1 + 4. * p2 - p3) * s2
+            + (3. * p1 - p0 - 3. * p2 + p3) * s3)
+    };
+    [axis(0), axis(1)]
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Leg {
+    /// Follow a path from where the leg starts. The path goes on if it is the last leg.
+    Follow(Path),
+    /// Move straight to a point at a speed
+    MoveTo { target: [f64; 2], speed: f64 },
+    /// Stay still until the enemy is as old as the given frames
+    HoldUntil(u32),
+    /// Head for where the player is and keep going that way
+    Dive { speed: f64 },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    legs: Vec<Leg>,
+    /// The index of the current leg
+    leg: usize,
+    /// Where the current leg started
+    start: [f64; 2],
+    /// Frames since the current leg started
+    time: u32,
+}
+
+impl Route {
+    pub fn new(legs: Vec<Leg>, start: [f64; 2]) -> Self {
+        Self {
+            legs,
+            leg: 0,
+            start,
+            time: 0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.legs.len() <= self.leg
+    }
+
+    fn next_leg(&mut self, start: [f64; 2]) {
+        self.leg += 1;
+        self.start = start;
+        self.time = 0;
+    }
+
+    /// The velocity to move along the route for a frame from `pos`, or `None` after the
+    /// last leg, when the enemy should keep its velocity.
+    pub fn advance(&mut self, pos: [f64; 2], age: u32, player: [f64; 2]) -> Option<[f64; 2]> {
+        loop {
+            let last = self.leg + 1 == self.legs.len();
+            match self.legs.get(self.leg)? {
+                Leg::Follow(path) => {
+                    if last || path.frames().map_or(true, |frames| self.time < frames) {
+                        self.time += 1;
+                        let next = vec2_add(self.start, path.offset(self.time));
+                        return Some(vec2_sub(next, pos));
+                    }
+                    self.next_leg(pos);
+                }
+                Leg::MoveTo { target, speed } => {
+                    let target = *target;
+                    let delta = vec2_sub(target, pos);
+                    let dist = vec2_len(delta);
+                    if *speed < dist {
+                        return Some(vec2_scale(delta, speed / dist));
+                    }
+                    self.next_leg(target);
+                    return Some(delta);
+                }
+                Leg::HoldUntil(until) => {
+                    if age < *until {
+                        return Some([0., 0.]);
+                    }
+                    self.next_leg(pos);
+                }
+                Leg::Dive { speed } => {
+                    let delta = vec2_sub(player, pos);
+                    let dist = vec2_len(delta);
+                    let velo = if dist == 0. {
+                        [0., *speed]
+                    } else {
+                        vec2_scale(delta, speed / dist)
+                    };
+                    self.next_leg(pos);
+                    return Some(velo);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn path_curves() {
+    let close = |a: [f64; 2], b: [f64; 2]| vec2_len(vec2_sub(a, b)) < 1e-6;
+
+    let bezier = Path::Bezier {
+        points: vec![[0., 100.], [100., 100.]],
+        frames: 100,
+    };
+    assert!(close(bezier.offset(0), [0., 0.]));
+    assert!(close(bezier.offset(50), [25., 75.]));
+    assert!(close(bezier.offset(100), [100., 100.]));
+    // Keeps going in the end direction
+    assert!(close(bezier.offset(110), [120., 100.]));
+
+    let spline = Path::CatmullRom {
+        points: vec![[100., 0.], [100., 100.]],
+        frames: 100,
+    };
+    // Passes through the points
+    assert!(close(spline.offset(50), [100., 0.]));
+    assert!(close(spline.offset(100), [100., 100.]));
+    assert!(close(spline.offset(110), [100., 110.]));
+
+    let sine = Path::Sine {
+        velo: [0., 1.],
+        amplitude: 10.,
+        period: 40.,
+    };
+    assert!(close(sine.offset(10), [-10., 10.]));
+    assert!(close(sine.offset(20), [0., 20.]));
+    assert!(sine.check().is_ok());
+    for period in [0., -1., f64::NAN] {
+        let still = Path::Sine {
+            velo: [0., 1.],
+            amplitude: 10.,
+            period,
+        };
+        assert!(still.check().is_err());
+    }
+}
+
+#[test]
+fn route_legs() {
+    let close = |a: [f64; 2], b: [f64; 2]| vec2_len(vec2_sub(a, b)) < 1e-6;
+    let mut route = Route::new(
+        vec![
+            Leg::Follow(Path::Bezier {
+                points: vec![[0., 10.]],
+                frames: 10,
+            }),
+            Leg::MoveTo {
+                target: [0., 20.],
+                speed: 4.,
+            },
+            Leg::HoldUntil(30),
+            Leg::Dive { speed: 2. },
+        ],
+        [0., 0.],
+    );
+    let (mut pos, mut age) = ([0., 0.], 0);
+    let mut step = |route: &mut Route, pos: &mut [f64; 2]| {
+        age += 1;
+        let velo = route.advance(*pos, age, [100., 20.]);
+        *pos = vec2_add(*pos, velo.unwrap_or([0., 0.]));
+        velo
+    };
+    for _ in 0..10 {
+        step(&mut route, &mut pos);
+    }
+    assert!(close(pos, [0., 10.]));
+    for _ in 0..3 {
+        step(&mut route, &mut pos);
+    }
+    assert!(close(pos, [0., 20.]));
+    for _ in 13..29 {
+        assert_eq!(step(&mut route, &mut pos), Some([0., 0.]));
+    }
+    assert!(close(step(&mut route, &mut pos).unwrap(), [2., 0.]));
+    assert!(route.is_finished());
+    assert_eq!(step(&mut route, &mut pos), None);
+}
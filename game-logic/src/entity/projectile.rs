@@ -4,6 +4,7 @@ use crate::assets_piston::Assets;
 use crate::assets_webgl::Assets;
 #[cfg(feature = "webgl")]
 use cgmath::{Matrix3, Matrix4};
+use serde::{Deserialize, Serialize};
 use std::ops::Deref;
 #[cfg(feature = "webgl")]
 use web_sys::WebGlRenderingContext as GL;
@@ -15,10 +16,26 @@ use vecmath::{vec2_add, vec2_len, vec2_normalized, vec2_scale, vec2_square_len,
 #[cfg(all(not(feature = "webgl"), feature = "piston"))]
 use piston_window::*;
 
-use super::{DeathReason, Enemy, Entity, EntityId, EntitySet, Player, BULLET_SIZE, MISSILE_SPEED};
+use super::{
+    bounding_box, Collider, DeathReason, Enemy, Entity, EntityId, EntitySet, Player, SpatialGrid,
+    Weapon, BULLET_SIZE, ENEMY_BULLET_RADIUS, LONG_BULLET_SIZE, MISSILE_SPEED,
+};
+use crate::{event::GameEvent, xor128::Xor128};
 
+mod bulletml;
+mod pattern;
+
+pub use self::bulletml::{BulletML, BulletMLError, BulletMLRunner};
+use self::pattern::{ramp_speed, ramp_velo};
+pub use self::pattern::{
+    BulletKind, BulletPattern, BulletSpec, BulletSplit, PatternAction, PatternEmitter,
+    PatternError, PatternLibrary, PatternRunner,
+};
+
+#[derive(Serialize, Deserialize)]
 pub struct BulletBase(pub Entity);
 
+#[derive(Serialize, Deserialize)]
 pub enum Projectile {
     Bullet(BulletBase),
     EnemyBullet(BulletBase),
@@ -37,6 +54,32 @@ pub enum Projectile {
         target: Option<EntityId<Enemy>>,
         trail: Vec<[f64; 2]>,
     },
+    /// An enemy bullet fired by a pattern, which ramps its speed or splits.
+    Patterned {
+        bullet: Box<Projectile>,
+        accel: f64,
+        speed_limit: Option<f64>,
+        split: Option<BulletSplit>,
+        /// Frames since fired
+        age: u32,
+    },
+    /// An enemy missile, which steers toward the player for a while.
+    EnemyMissile {
+        base: BulletBase,
+        speed: f64,
+        /// Frames left to steer
+        homing: u32,
+    },
+    /// An enemy bullet running its own BulletML actions.
+    BulletML {
+        base: BulletBase,
+        runner: Box<BulletMLRunner>,
+    },
+    /// A slow enemy bullet, which goes off when the player comes close.
+    Mine {
+        base: BulletBase,
+        triggered: bool,
+    },
 }
 
 impl Deref for Projectile {
@@ -47,7 +90,11 @@ impl Deref for Projectile {
             &Projectile::PhaseBullet { base, .. } | &Projectile::SpiralBullet { base, .. } => {
                 &base.0
             }
-            &Projectile::Missile { base, .. } => &base.0,
+            &Projectile::Missile { base, .. }
+            | &Projectile::EnemyMissile { base, .. }
+            | &Projectile::BulletML { base, .. }
+            | &Projectile::Mine { base, .. } => &base.0,
+            &Projectile::Patterned { bullet, .. } => bullet,
         }
     }
 }
@@ -58,6 +105,12 @@ const MISSILE_HOMING_SPEED: f64 = 0.25;
 const MISSILE_TRAIL_WIDTH: f64 = 5.;
 const MISSILE_TRAIL_LENGTH: usize = 20;
 const MISSILE_DAMAGE: i32 = 5;
+/// Enemy missiles turn much slower than the player's, so that they can be dodged.
+const ENEMY_MISSILE_HOMING_SPEED: f64 = 0.03;
+const ENEMY_MISSILE_HOMING_TIME: u32 = 150;
+const MINE_SIZE: f64 = 8.;
+/// A mine goes off when the player comes within this distance
+const MINE_TRIGGER_DISTANCE: f64 = 40.;
 
 impl Projectile {
     pub fn new_phase(base: BulletBase) -> Projectile {
@@ -78,61 +131,140 @@ impl Projectile {
         }
     }
 
+    pub fn new_enemy_missile(base: BulletBase) -> Projectile {
+        let speed = vec2_len(base.0.velo);
+        Projectile::EnemyMissile {
+            base,
+            speed,
+            homing: ENEMY_MISSILE_HOMING_TIME,
+        }
+    }
+
+    pub fn new_mine(base: BulletBase) -> Projectile {
+        Projectile::Mine {
+            base,
+            triggered: false,
+        }
+    }
+
     pub fn get_type(&self) -> &str {
         match &self {
             &Projectile::Bullet(_) | &Projectile::EnemyBullet(_) => "Bullet",
             &Projectile::PhaseBullet { .. } => "PhaseBullet",
             &Projectile::SpiralBullet { .. } => "SpiralBullet",
             &Projectile::Missile { .. } => "Missile",
+            &Projectile::EnemyMissile { .. } => "EnemyMissile",
+            &Projectile::Patterned { bullet, .. } => bullet.get_type(),
+            &Projectile::BulletML { .. } => "BulletML",
+            &Projectile::Mine { .. } => "Mine",
         }
     }
 
+    /// Whether it was fired by an enemy, i.e. it can hit the player.
+    pub fn is_enemy(&self) -> bool {
+        !matches!(self, Projectile::Bullet(_) | Projectile::Missile { .. })
+    }
+
     fn animate_player_bullet(
         mut base: &mut BulletBase,
+        weapon: Weapon,
         enemies: &mut EntitySet<Enemy>,
+        grid: &mut SpatialGrid,
         mut _player: &mut Player,
+        events: &mut Vec<GameEvent>,
     ) -> Option<DeathReason> {
         let bbox = Self::get_bb_base(base);
         let &mut BulletBase(ent) = &mut base;
         let mut spawned_enemy = None;
-        for enemy in enemies.iter_mut() {
-            if enemy.test_hit(bbox) {
+        let mut hit = None;
+        for id in grid.query(&bbox) {
+            let Some(enemy) = enemies.get_mut(id) else {
+                continue;
+            };
+            if enemy.test_hit(&Collider::Aabb(bbox)) {
+                hit = Some((enemy.kind(), ent.health));
                 spawned_enemy = enemy.damage(ent.health, &bbox);
                 ent.health = 0;
                 break;
             }
         }
         if let Some(enemy) = spawned_enemy {
-            enemies.insert(enemy);
+            let id = enemies.insert(enemy);
+            if let Some(enemy) = enemies.get(id) {
+                grid.insert(id, &enemy);
+            }
+        }
+        let res = ent.animate();
+        if let Some((kind, damage)) = hit {
+            events.push(GameEvent::EnemyDamaged {
+                kind,
+                pos: ent.pos,
+                weapon,
+                damage,
+            });
+        }
+        res
+    }
+
+    /// The shape of a round enemy bullet, or a `long` one along its rotation.
+    fn enemy_collider(ent: &Entity, long: bool) -> Collider {
+        if long {
+            let [length, width] = LONG_BULLET_SIZE;
+            Collider::capsule(ent.pos, ent.rotation as f64, length - width, width)
+        } else {
+            Collider::circle(ent.pos, ENEMY_BULLET_RADIUS)
+        }
+    }
+
+    pub fn collider(&self) -> Collider {
+        match self {
+            Projectile::Bullet(base) | Projectile::Missile { base, .. } => {
+                Collider::Aabb(Self::get_bb_base(base))
+            }
+            Projectile::EnemyBullet(base)
+            | Projectile::EnemyMissile { base, .. }
+            | Projectile::BulletML { base, .. } => Self::enemy_collider(&base.0, false),
+            Projectile::PhaseBullet { base, .. } | Projectile::SpiralBullet { base, .. } => {
+                Self::enemy_collider(&base.0, true)
+            }
+            Projectile::Mine { base, .. } => Collider::circle(base.0.pos, MINE_SIZE),
+            Projectile::Patterned { bullet, .. } => bullet.collider(),
         }
-        ent.animate()
     }
 
     fn animate_enemy_bullet(
         base: &mut BulletBase,
+        long: bool,
         _enemies: &mut EntitySet<Enemy>,
         player: &mut Player,
     ) -> Option<DeathReason> {
         let BulletBase(ref mut ent) = base;
-        if let Some(death_reason) = ent.hits_player(&player.base) {
-            player.base.health -= ent.health;
-            return Some(death_reason);
+        if Self::enemy_collider(ent, long).intersects(&player.collider()) {
+            return Some(DeathReason::HitPlayer);
         }
         ent.animate()
     }
 
+    /// Animate this bullet and test hits against enemies, whose positions are registered
+    /// in `grid`. Hits on enemies are reported to `events`.
     pub fn animate_bullet(
         &mut self,
         enemies: &mut EntitySet<Enemy>,
+        grid: &mut SpatialGrid,
         player: &mut Player,
+        events: &mut Vec<GameEvent>,
     ) -> Option<DeathReason> {
         match self {
-            Projectile::Bullet(base) => Self::animate_player_bullet(base, enemies, player),
-            Projectile::EnemyBullet(base) => Self::animate_enemy_bullet(base, enemies, player),
+            Projectile::Bullet(base) => {
+                Self::animate_player_bullet(base, Weapon::Bullet, enemies, grid, player, events)
+            }
+            Projectile::EnemyBullet(base) => {
+                Self::animate_enemy_bullet(base, false, enemies, player)
+            }
             Projectile::PhaseBullet { base, velo, phase } => {
                 base.0.velo = vec2_scale(*velo, (phase.sin() + 1.) / 2.);
                 *phase += 0.02 * std::f64::consts::PI;
-                Self::animate_enemy_bullet(base, enemies, player)
+                Self::animate_enemy_bullet(base, true, enemies, player)
             }
             Projectile::SpiralBullet {
                 base,
@@ -144,7 +276,7 @@ impl Projectile {
                 base.0.rotation = rotation as f32;
                 base.0.velo = vec2_scale([rotation.cos(), rotation.sin()], *speed);
                 *traveled += *speed;
-                Self::animate_enemy_bullet(base, enemies, player)
+                Self::animate_enemy_bullet(base, true, enemies, player)
             }
             Projectile::Missile {
                 base,
@@ -152,22 +284,24 @@ impl Projectile {
                 trail,
             } => {
                 if target.is_none() {
-                    let best =
-                        enemies
-                            .items_mut()
-                            .fold((None, 1e5, None), |bestpair, (id, enemy)| {
-                                let dist = vec2_len(vec2_sub(base.0.pos, enemy.pos));
-                                if dist < MISSILE_DETECTION_RANGE
-                                    && dist < bestpair.1
-                                    && enemy.predicted_damage() < enemy.total_health()
-                                {
-                                    (Some(id), dist, Some(enemy))
-                                } else {
-                                    bestpair
-                                }
-                            });
+                    let range = bounding_box(&base.0.pos, MISSILE_DETECTION_RANGE);
+                    let best = grid
+                        .query(&range)
+                        .into_iter()
+                        .filter_map(|id| Some((id, enemies.borrow_mut(id)?)))
+                        .fold((None, 1e5, None), |bestpair, (id, enemy)| {
+                            let dist = vec2_len(vec2_sub(base.0.pos, enemy.pos));
+                            if dist < MISSILE_DETECTION_RANGE
+                                && dist < bestpair.1
+                                && enemy.predicted_damage() < enemy.total_health()
+                            {
+                                (Some(id), dist, Some(enemy))
+                            } else {
+                                bestpair
+                            }
+                        });
                     *target = best.0;
-                    if let Some(enemy) = best.2 {
+                    if let Some(mut enemy) = best.2 {
                         enemy.add_predicted_damage(MISSILE_DAMAGE);
                         println!(
                             "Add predicted damage: {} -> {}",
@@ -176,27 +310,12 @@ impl Projectile {
                         );
                     }
                 } else if let Some(target_enemy) = target.and_then(|t| enemies.get(t)) {
-                    let norm = vec2_normalized(vec2_sub(target_enemy.pos, base.0.pos));
-                    let desired_velo = vec2_scale(norm, MISSILE_SPEED);
-                    let desired_diff = vec2_sub(desired_velo, base.0.velo);
-                    if std::f64::EPSILON < vec2_square_len(desired_diff) {
-                        base.0.velo = if vec2_square_len(desired_diff)
-                            < MISSILE_HOMING_SPEED * MISSILE_HOMING_SPEED
-                        {
-                            desired_velo
-                        } else {
-                            let desired_diff_norm = vec2_normalized(desired_diff);
-                            vec2_add(
-                                base.0.velo,
-                                vec2_scale(desired_diff_norm, MISSILE_HOMING_SPEED),
-                            )
-                        };
-                        let angle = base.0.velo[1].atan2(base.0.velo[0]);
-                        base.0.rotation = (angle + std::f64::consts::FRAC_PI_2) as f32;
-                        let (s, c) = angle.sin_cos();
-                        base.0.velo[0] = MISSILE_SPEED * c;
-                        base.0.velo[1] = MISSILE_SPEED * s;
-                    }
+                    steer(
+                        &mut base.0,
+                        target_enemy.pos,
+                        MISSILE_SPEED,
+                        MISSILE_HOMING_SPEED,
+                    );
                 } else {
                     *target = None;
                 }
@@ -204,7 +323,14 @@ impl Projectile {
                     trail.remove(0);
                 }
                 trail.push(base.0.pos);
-                let res = Self::animate_player_bullet(base, enemies, player);
+                let res = Self::animate_player_bullet(
+                    base,
+                    Weapon::Missile,
+                    enemies,
+                    grid,
+                    player,
+                    events,
+                );
                 if res.is_some() {
                     if let Some(target_enemy) = target.and_then(|t| enemies.get_mut(t)) {
                         target_enemy.add_predicted_damage(-MISSILE_DAMAGE);
@@ -217,6 +343,107 @@ impl Projectile {
                 }
                 res
             }
+            Projectile::Patterned {
+                bullet,
+                accel,
+                speed_limit,
+                split,
+                age,
+            } => {
+                bullet.ramp(*accel, *speed_limit);
+                let res = bullet.animate_bullet(enemies, grid, player, events);
+                *age += 1;
+                if res.is_none() && split.as_ref().is_some_and(|split| split.after <= *age) {
+                    return Some(DeathReason::Killed);
+                }
+                res
+            }
+            Projectile::EnemyMissile {
+                base,
+                speed,
+                homing,
+            } => {
+                if 0 < *homing {
+                    *homing -= 1;
+                    steer(
+                        &mut base.0,
+                        player.base.pos,
+                        *speed,
+                        ENEMY_MISSILE_HOMING_SPEED,
+                    );
+                }
+                Self::animate_enemy_bullet(base, false, enemies, player)
+            }
+            Projectile::BulletML { base, runner } => {
+                if runner.is_vanished() {
+                    return Some(DeathReason::Killed);
+                }
+                Self::animate_enemy_bullet(base, false, enemies, player)
+            }
+            Projectile::Mine { base, triggered } => {
+                let ent = &mut base.0;
+                ent.rotation += 0.02;
+                if vec2_len(vec2_sub(player.base.pos, ent.pos)) < MINE_TRIGGER_DISTANCE {
+                    *triggered = true;
+                    return Some(DeathReason::Killed);
+                }
+                ent.animate()
+            }
+        }
+    }
+
+    /// Run the BulletML actions of this bullet for a frame, if it has any, collecting the
+    /// bullets it fires into `fired`.
+    pub fn run_bulletml(
+        &mut self,
+        library: &PatternLibrary,
+        target: [f64; 2],
+        rank: f64,
+        rng: &mut Xor128,
+        fired: &mut Vec<Projectile>,
+    ) {
+        let Projectile::BulletML { base, runner } = self else {
+            return;
+        };
+        if let Some(doc) = library.bulletml.get(runner.document()) {
+            runner.advance(doc, base.0.pos, target, rank, rng, fired);
+        }
+        base.0.velo = runner.velo();
+    }
+
+    /// The pattern to fire from this bullet's position when it has died by splitting,
+    /// or by a mine going off.
+    pub fn split_pattern(&self) -> Option<&str> {
+        match self {
+            Projectile::Patterned {
+                bullet,
+                split: Some(split),
+                age,
+                ..
+            } if split.after <= *age
+                || matches!(
+                    **bullet,
+                    Projectile::Mine {
+                        triggered: true,
+                        ..
+                    }
+                ) =>
+            {
+                Some(&split.pattern)
+            }
+            _ => None,
+        }
+    }
+
+    /// Change the speed of an enemy bullet by `accel`, up to `limit`.
+    fn ramp(&mut self, accel: f64, limit: Option<f64>) {
+        match self {
+            Projectile::EnemyBullet(base) => base.0.velo = ramp_velo(base.0.velo, accel, limit),
+            Projectile::PhaseBullet { velo, .. } => *velo = ramp_velo(*velo, accel, limit),
+            Projectile::SpiralBullet { speed, .. } | Projectile::EnemyMissile { speed, .. } => {
+                *speed = ramp_speed(*speed, accel, limit)
+            }
+            _ => (),
         }
     }
 
@@ -232,8 +459,11 @@ impl Projectile {
 
     #[cfg(feature = "webgl")]
     pub fn draw(&self, gl: &GL, assets: &Assets) {
+        if let Projectile::Patterned { bullet, .. } = self {
+            return bullet.draw(gl, assets);
+        }
         if let Projectile::Bullet(base) = self {
-            if let Some(ref shader) = assets.sprite_shader.as_ref() {
+            if let Some(shader) = assets.sprite_shader.as_ref() {
                 gl.blend_equation(GL::FUNC_ADD);
                 gl.blend_func(GL::SRC_ALPHA, GL::ONE);
                 gl.uniform1f(shader.alpha_loc.as_ref(), 0.15);
@@ -309,27 +539,36 @@ impl Projectile {
         }
         use Projectile::*;
 
-        use crate::LONG_BULLET_SIZE;
-
         self.draw_tex(
             assets,
             gl,
             match self {
                 Bullet(_) => &assets.bullet_texture,
-                EnemyBullet(_) => &assets.enemy_bullet_texture,
+                EnemyBullet(_) | BulletML { .. } => &assets.enemy_bullet_texture,
                 PhaseBullet { .. } => &assets.phase_bullet_tex,
                 SpiralBullet { .. } => &assets.spiral_bullet_tex,
-                Missile { .. } => &assets.missile_tex,
+                Missile { .. } | EnemyMissile { .. } => &assets.missile_tex,
+                Mine { .. } => &assets.mine_tex,
+                Patterned { .. } => unreachable!(),
             },
             Some(match self {
-                Bullet(_) | EnemyBullet(_) | Missile { .. } => [BULLET_SIZE; 2],
+                Bullet(_)
+                | EnemyBullet(_)
+                | Missile { .. }
+                | EnemyMissile { .. }
+                | BulletML { .. } => [BULLET_SIZE; 2],
                 PhaseBullet { .. } | SpiralBullet { .. } => LONG_BULLET_SIZE,
+                Mine { .. } => [MINE_SIZE; 2],
+                Patterned { .. } => unreachable!(),
             }),
         );
     }
 
     #[cfg(all(not(feature = "webgl"), feature = "piston"))]
     pub fn draw(&self, c: &Context, g: &mut G2d, assets: &Assets) {
+        if let Projectile::Patterned { bullet, .. } = self {
+            return bullet.draw(c, g, assets);
+        }
         if let Projectile::Missile {
             base: _,
             target: _,
@@ -355,12 +594,35 @@ impl Projectile {
             g,
             match self {
                 Projectile::Bullet(_) => &assets.bullet_tex,
-                Projectile::EnemyBullet(_) => &assets.ebullet_tex,
+                Projectile::EnemyBullet(_) | Projectile::BulletML { .. } => &assets.ebullet_tex,
                 Projectile::PhaseBullet { .. } => &assets.phase_bullet_tex,
                 Projectile::SpiralBullet { .. } => &assets.spiral_bullet_tex,
-                Projectile::Missile { .. } => &assets.missile_tex,
+                Projectile::Missile { .. } | Projectile::EnemyMissile { .. } => &assets.missile_tex,
+                Projectile::Mine { .. } => &assets.mine_tex,
+                Projectile::Patterned { .. } => unreachable!(),
             },
             None,
         );
     }
 }
+
+/// Turn the velocity of `ent` toward `target` by up to `homing_speed` a frame, keeping
+/// the speed at `speed`.
+fn steer(ent: &mut Entity, target: [f64; 2], speed: f64, homing_speed: f64) {
+    let norm = vec2_normalized(vec2_sub(target, ent.pos));
+    let desired_velo = vec2_scale(norm, speed);
+    let desired_diff = vec2_sub(desired_velo, ent.velo);
+    if f64::EPSILON < vec2_square_len(desired_diff) {
+        ent.velo = if vec2_square_len(desired_diff) < homing_speed * homing_speed {
+            desired_velo
+        } else {
+            let desired_diff_norm = vec2_normalized(desired_diff);
+            vec2_add(ent.velo, vec2_scale(desired_diff_norm, homing_speed))
+        };
+        let angle = ent.velo[1].atan2(ent.velo[0]);
+        ent.rotation = (angle + std::f64::consts::FRAC_PI_2) as f32;
+        let (s, c) = angle.sin_cos();
+        ent.velo[0] = speed * c;
+        ent.velo[1] = speed * s;
+    }
+}
@@ -0,0 +1,1053 @@
+//! Import of BulletML, the XML format for bullet patterns by ABA Games, so that existing
+//! pattern collections can be dropped into the game.
+//!
+//! A document is parsed into [`BulletML`] ahead of time. A [`BulletMLRunner`] runs its top
+//! actions (the ones whose labels start with `top`) from an enemy, and the bullets with
+//! actions of their own become [`Projectile::BulletML`], which run theirs every frame.
+//!
+//! Directions are in degrees clockwise from up, as in the vertical type of BulletML, and
+//! speeds are in pixels per frame.
+
+use std::collections::BTreeMap;
+
+use roxmltree::Node;
+use serde::{Deserialize, Serialize};
+
+use super::{BulletBase, Projectile};
+use crate::{entity::Entity, xor128::Xor128};
+
+#[derive(Debug)]
+pub enum BulletMLError {
+    Xml(roxmltree::Error),
+    /// The XML parsed, but it is not a BulletML document we understand.
+    Invalid(String),
+}
+
+impl std::fmt::Display for BulletMLError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BulletMLError::Xml(e) => write!(f, "BulletML file is broken: {}", e),
+            BulletMLError::Invalid(e) => write!(f, "BulletML file is invalid: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BulletMLError {}
+
+/// Nested actions deeper than this are skipped, which keeps the stack of an action
+/// referring to itself from growing without bound.
+const MAX_DEPTH: usize = 64;
+
+/// The most steps an action runs in a frame before it goes on in the next frame, so that
+/// actions repeating without waits, or referring to themselves, cannot freeze the game.
+const MAX_STEPS_PER_FRAME: u32 = 1024;
+
+/// `<times>` of a `<repeat>` is clamped to this.
+const MAX_REPEAT: f64 = 65536.;
+
+/// Enemies face down the screen, which matters to relative directions in top actions.
+const ENEMY_DIRECTION: f64 = 180.;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BulletML {
+    /// The labels of the top actions, which run in parallel
+    pub top: Vec<String>,
+    pub actions: BTreeMap<String, Action>,
+    pub bullets: BTreeMap<String, Bullet>,
+    pub fires: BTreeMap<String, Fire>,
+}
+
+pub type Action = Vec<Command>;
+
+/// An element given inline or referred to by its label with parameters (`$1`, `$2`...).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Ref<T> {
+    Inline(T),
+    Label { label: String, params: Vec<Expr> },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bullet {
+    pub direction: Option<Direction>,
+    pub speed: Option<Speed>,
+    pub actions: Vec<Ref<Action>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Fire {
+    /// Overrides the direction of the bullet
+    pub direction: Option<Direction>,
+    /// Overrides the speed of the bullet
+    pub speed: Option<Speed>,
+    pub bullet: Ref<Bullet>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    Repeat {
+        times: Expr,
+        action: Ref<Action>,
+    },
+    Fire(Ref<Fire>),
+    ChangeDirection {
+        direction: Direction,
+        term: Expr,
+    },
+    ChangeSpeed {
+        speed: Speed,
+        term: Expr,
+    },
+    /// Change the velocity apart from the direction, where right and down are positive.
+    Accel {
+        horizontal: Option<Speed>,
+        vertical: Option<Speed>,
+        term: Expr,
+    },
+    Wait(Expr),
+    Vanish,
+    Action(Ref<Action>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirectionType {
+    Aim,
+    Absolute,
+    Relative,
+    Sequence,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Direction {
+    pub kind: DirectionType,
+    pub value: Expr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpeedType {
+    Absolute,
+    Relative,
+    Sequence,
+}
+
+/// A speed, or a component of the velocity in `<accel>`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Speed {
+    pub kind: SpeedType,
+    pub value: Expr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+/// A numeric expression, which can use `$rand` (0 to 1), `$rank` (the difficulty from
+/// 0 to 1) and the parameters `$1`, `$2`... of a reference.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Expr {
+    Num(f64),
+    Rand,
+    Rank,
+    /// A parameter, counting from 1
+    Param(usize),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, BulletMLError> {
+        let mut parser = ExprParser { text, pos: 0 };
+        let ret = parser.sum();
+        match ret {
+            Some(ret) if parser.peek().is_none() => Ok(ret),
+            _ => Err(BulletMLError::Invalid(format!(
+                "bad expression: {:?}",
+                text
+            ))),
+        }
+    }
+
+    pub fn eval(&self, params: &[f64], rank: f64, rng: &mut Xor128) -> f64 {
+        match self {
+            Expr::Num(value) => *value,
+            Expr::Rand => rng.gen(),
+            Expr::Rank => rank,
+            Expr::Param(i) => params.get(i - 1).copied().unwrap_or(0.),
+            Expr::Neg(expr) => -expr.eval(params, rank, rng),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(params, rank, rng);
+                let rhs = rhs.eval(params, rank, rng);
+                match op {
+                    BinOp::Add => lhs + rhs,
+                    BinOp::Sub => lhs - rhs,
+                    BinOp::Mul => lhs * rhs,
+                    BinOp::Div => lhs / rhs,
+                    BinOp::Mod => lhs % rhs,
+                }
+            }
+        }
+    }
+}
+
+struct ExprParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+        self.text[self.pos..].chars().next()
+    }
+
+    fn sum(&mut self) -> Option<Expr> {
+        let mut lhs = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Some('+') => BinOp::Add,
+                Some('-') => BinOp::Sub,
+                _ => return Some(lhs),
+            };
+            self.pos += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Option<Expr> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some('*') => BinOp::Mul,
+                Some('/') => BinOp::Div,
+                Some('%') => BinOp::Mod,
+                _ => return Some(lhs),
+            };
+            self.pos += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Option<Expr> {
+        match self.peek()? {
+            '-' => {
+                self.pos += 1;
+                Some(Expr::Neg(Box::new(self.unary()?)))
+            }
+            '+' => {
+                self.pos += 1;
+                self.unary()
+            }
+            '(' => {
+                self.pos += 1;
+                let ret = self.sum()?;
+                if self.peek()? != ')' {
+                    return None;
+                }
+                self.pos += 1;
+                Some(ret)
+            }
+            '$' => {
+                self.pos += 1;
+                match self.word() {
+                    "rand" => Some(Expr::Rand),
+                    "rank" => Some(Expr::Rank),
+                    word => word.parse().ok().filter(|i| 0 < *i).map(Expr::Param),
+                }
+            }
+            _ => self.number(),
+        }
+    }
+
+    fn word(&mut self) -> &'a str {
+        let rest = &self.text[self.pos..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn number(&mut self) -> Option<Expr> {
+        let rest = &self.text[self.pos..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        self.pos += len;
+        rest[..len].parse().ok().map(Expr::Num)
+    }
+}
+
+impl BulletML {
+    pub fn parse(xml: &str) -> Result<Self, BulletMLError> {
+        // Documents usually declare the DTD of BulletML
+        let options = roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..roxmltree::ParsingOptions::default()
+        };
+        let doc =
+            roxmltree::Document::parse_with_options(xml, options).map_err(BulletMLError::Xml)?;
+        let root = doc.root_element();
+        if root.tag_name().name() != "bulletml" {
+            return Err(invalid("the root element must be <bulletml>"));
+        }
+        let mut ret = Self {
+            top: vec![],
+            actions: BTreeMap::new(),
+            bullets: BTreeMap::new(),
+            fires: BTreeMap::new(),
+        };
+        for node in elements(root) {
+            // Elements without labels cannot be referred to, so they are never used
+            let label = node.attribute("label").unwrap_or("").to_string();
+            match node.tag_name().name() {
+                "action" => {
+                    if label.starts_with("top") {
+                        ret.top.push(label.clone());
+                    }
+                    ret.actions.insert(label, parse_action(node)?);
+                }
+                "bullet" => {
+                    ret.bullets.insert(label, parse_bullet(node)?);
+                }
+                "fire" => {
+                    ret.fires.insert(label, parse_fire(node)?);
+                }
+                name => return Err(unknown_element(name)),
+            }
+        }
+        if ret.top.is_empty() {
+            return Err(invalid("no action is labeled top"));
+        }
+        ret.check()?;
+        Ok(ret)
+    }
+
+    /// Make sure that all the referred labels exist.
+    fn check(&self) -> Result<(), BulletMLError> {
+        for action in self.actions.values() {
+            self.check_action(action)?;
+        }
+        for bullet in self.bullets.values() {
+            self.check_bullet(bullet)?;
+        }
+        for fire in self.fires.values() {
+            self.check_fire(fire)?;
+        }
+        Ok(())
+    }
+
+    fn check_action(&self, action: &Action) -> Result<(), BulletMLError> {
+        for command in action {
+            match command {
+                Command::Repeat { action, .. } | Command::Action(action) => {
+                    self.check_ref(action, &self.actions, Self::check_action)?
+                }
+                Command::Fire(fire) => self.check_ref(fire, &self.fires, Self::check_fire)?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_bullet(&self, bullet: &Bullet) -> Result<(), BulletMLError> {
+        for action in &bullet.actions {
+            self.check_ref(action, &self.actions, Self::check_action)?;
+        }
+        Ok(())
+    }
+
+    fn check_fire(&self, fire: &Fire) -> Result<(), BulletMLError> {
+        self.check_ref(&fire.bullet, &self.bullets, Self::check_bullet)
+    }
+
+    fn check_ref<T>(
+        &self,
+        item: &Ref<T>,
+        labeled: &BTreeMap<String, T>,
+        check: fn(&Self, &T) -> Result<(), BulletMLError>,
+    ) -> Result<(), BulletMLError> {
+        match item {
+            Ref::Inline(item) => check(self, item),
+            Ref::Label { label, .. } if labeled.contains_key(label) => Ok(()),
+            Ref::Label { label, .. } => Err(invalid(format!("unknown label {:?}", label))),
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> BulletMLError {
+    BulletMLError::Invalid(message.into())
+}
+
+fn unknown_element(name: &str) -> BulletMLError {
+    invalid(format!("unexpected element <{}>", name))
+}
+
+fn elements<'a, 'input>(node: Node<'a, 'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(Node::is_element)
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    elements(node).find(|child| child.tag_name().name() == name)
+}
+
+fn required<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &str,
+) -> Result<Node<'a, 'input>, BulletMLError> {
+    child(node, name)
+        .ok_or_else(|| invalid(format!("<{}> needs <{}>", node.tag_name().name(), name)))
+}
+
+fn parse_expr(node: Node) -> Result<Expr, BulletMLError> {
+    Expr::parse(node.text().unwrap_or(""))
+}
+
+fn parse_ref<T>(node: Node) -> Result<Ref<T>, BulletMLError> {
+    let label = node
+        .attribute("label")
+        .ok_or_else(|| invalid(format!("<{}> needs a label", node.tag_name().name())))?;
+    let params = elements(node)
+        .filter(|child| child.tag_name().name() == "param")
+        .map(parse_expr)
+        .collect::<Result<_, _>>()?;
+    Ok(Ref::Label {
+        label: label.to_string(),
+        params,
+    })
+}
+
+fn parse_action(node: Node) -> Result<Action, BulletMLError> {
+    elements(node).map(parse_command).collect()
+}
+
+fn parse_action_ref(node: Node) -> Result<Ref<Action>, BulletMLError> {
+    match node.tag_name().name() {
+        "action" => Ok(Ref::Inline(parse_action(node)?)),
+        _ => parse_ref(node),
+    }
+}
+
+fn parse_command(node: Node) -> Result<Command, BulletMLError> {
+    Ok(match node.tag_name().name() {
+        "repeat" => Command::Repeat {
+            times: parse_expr(required(node, "times")?)?,
+            action: parse_action_ref(
+                elements(node)
+                    .find(|child| matches!(child.tag_name().name(), "action" | "actionRef"))
+                    .ok_or_else(|| invalid("<repeat> needs <action>"))?,
+            )?,
+        },
+        "fire" => Command::Fire(Ref::Inline(parse_fire(node)?)),
+        "fireRef" => Command::Fire(parse_ref(node)?),
+        "changeDirection" => Command::ChangeDirection {
+            direction: parse_direction(required(node, "direction")?)?,
+            term: parse_expr(required(node, "term")?)?,
+        },
+        "changeSpeed" => Command::ChangeSpeed {
+            speed: parse_speed(required(node, "speed")?)?,
+            term: parse_expr(required(node, "term")?)?,
+        },
+        "accel" => Command::Accel {
+            horizontal: child(node, "horizontal").map(parse_speed).transpose()?,
+            vertical: child(node, "vertical").map(parse_speed).transpose()?,
+            term: parse_expr(required(node, "term")?)?,
+        },
+        "wait" => Command::Wait(parse_expr(node)?),
+        "vanish" => Command::Vanish,
+        "action" | "actionRef" => Command::Action(parse_action_ref(node)?),
+        name => return Err(unknown_element(name)),
+    })
+}
+
+fn parse_bullet(node: Node) -> Result<Bullet, BulletMLError> {
+    let mut actions = vec![];
+    for child in elements(node) {
+        match child.tag_name().name() {
+            "action" | "actionRef" => actions.push(parse_action_ref(child)?),
+            "direction" | "speed" => (),
+            name => return Err(unknown_element(name)),
+        }
+    }
+    Ok(Bullet {
+        direction: child(node, "direction").map(parse_direction).transpose()?,
+        speed: child(node, "speed").map(parse_speed).transpose()?,
+        actions,
+    })
+}
+
+fn parse_fire(node: Node) -> Result<Fire, BulletMLError> {
+    let bullet = if let Some(bullet) = child(node, "bullet") {
+        Ref::Inline(parse_bullet(bullet)?)
+    } else {
+        parse_ref(required(node, "bulletRef")?)?
+    };
+    Ok(Fire {
+        direction: child(node, "direction").map(parse_direction).transpose()?,
+        speed: child(node, "speed").map(parse_speed).transpose()?,
+        bullet,
+    })
+}
+
+fn parse_direction(node: Node) -> Result<Direction, BulletMLError> {
+    let kind = match node.attribute("type").unwrap_or("aim") {
+        "aim" => DirectionType::Aim,
+        "absolute" => DirectionType::Absolute,
+        "relative" => DirectionType::Relative,
+        "sequence" => DirectionType::Sequence,
+        kind => return Err(invalid(format!("unknown direction type {:?}", kind))),
+    };
+    Ok(Direction {
+        kind,
+        value: parse_expr(node)?,
+    })
+}
+
+fn parse_speed(node: Node) -> Result<Speed, BulletMLError> {
+    let kind = match node.attribute("type").unwrap_or("absolute") {
+        "absolute" => SpeedType::Absolute,
+        "relative" => SpeedType::Relative,
+        "sequence" => SpeedType::Sequence,
+        kind => return Err(invalid(format!("unknown speed type {:?}", kind))),
+    };
+    Ok(Speed {
+        kind,
+        value: parse_expr(node)?,
+    })
+}
+
+/// A gradual change of a value, which is applied once a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Change {
+    delta: f64,
+    frames: u32,
+}
+
+fn apply_change(change: &mut Option<Change>, value: &mut f64) {
+    if let Some(c) = change {
+        *value += c.delta;
+        c.frames -= 1;
+        if c.frames == 0 {
+            *change = None;
+        }
+    }
+}
+
+/// An action being run, and how many more times it runs.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Frame {
+    action: Action,
+    cursor: usize,
+    params: Vec<f64>,
+    repeat: u32,
+}
+
+/// One of the actions running in parallel, with the nested actions it has entered.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Thread {
+    stack: Vec<Frame>,
+    /// Frames to wait before running the next command
+    wait: u32,
+    /// The direction and the speed of the last fired bullet, for the sequence types
+    prev_direction: Option<f64>,
+    prev_speed: Option<f64>,
+}
+
+impl Frame {
+    fn new((action, params): (Action, Vec<f64>), repeat: u32) -> Self {
+        Self {
+            action,
+            cursor: 0,
+            params,
+            repeat,
+        }
+    }
+}
+
+impl Thread {
+    fn new(action: (Action, Vec<f64>)) -> Self {
+        Self {
+            stack: vec![Frame::new(action, 1)],
+            wait: 0,
+            prev_direction: None,
+            prev_speed: None,
+        }
+    }
+}
+
+/// What the commands can see and affect in a frame.
+struct Context<'a> {
+    doc: &'a BulletML,
+    pos: [f64; 2],
+    target: [f64; 2],
+    rank: f64,
+    rng: &'a mut Xor128,
+    fired: &'a mut Vec<Projectile>,
+}
+
+impl Context<'_> {
+    fn eval(&mut self, expr: &Expr, params: &[f64]) -> f64 {
+        expr.eval(params, self.rank, self.rng)
+    }
+
+    /// The direction to the target.
+    fn aim(&self) -> f64 {
+        let delta = [self.target[0] - self.pos[0], self.target[1] - self.pos[1]];
+        delta[0].atan2(-delta[1]).to_degrees()
+    }
+
+    /// Look up a reference, evaluating its parameters.
+    fn resolve<T: Clone>(
+        &mut self,
+        item: Ref<T>,
+        labeled: fn(&BulletML) -> &BTreeMap<String, T>,
+        params: &[f64],
+    ) -> Option<(T, Vec<f64>)> {
+        match item {
+            Ref::Inline(item) => Some((item, params.to_vec())),
+            Ref::Label {
+                label,
+                params: args,
+            } => {
+                let item = labeled(self.doc).get(&label)?.clone();
+                let args = args.iter().map(|arg| self.eval(arg, params)).collect();
+                Some((item, args))
+            }
+        }
+    }
+}
+
+/// Turn an angle in degrees into the range of -180 to 180, to take the shorter way around.
+fn normalize_angle(angle: f64) -> f64 {
+    (angle + 180.).rem_euclid(360.) - 180.
+}
+
+/// The running actions of a BulletML document, from an enemy or a bullet.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BulletMLRunner {
+    /// The name of the document in the pattern library
+    document: String,
+    threads: Vec<Thread>,
+    /// In degrees clockwise from up
+    direction: f64,
+    speed: f64,
+    /// The velocity added apart from the direction and the speed, changed by `<accel>`
+    accel_velo: [f64; 2],
+    change_direction: Option<Change>,
+    change_speed: Option<Change>,
+    change_accel: [Option<Change>; 2],
+    vanished: bool,
+}
+
+impl BulletMLRunner {
+    /// Start the top actions of a document, fired from an enemy.
+    pub fn new(document: &str, doc: &BulletML) -> Self {
+        let threads = doc
+            .top
+            .iter()
+            .filter_map(|label| Some(Thread::new((doc.actions.get(label)?.clone(), vec![]))))
+            .collect();
+        Self::with_threads(document.to_string(), threads, ENEMY_DIRECTION, 0.)
+    }
+
+    fn with_threads(document: String, threads: Vec<Thread>, direction: f64, speed: f64) -> Self {
+        Self {
+            document,
+            threads,
+            direction,
+            speed,
+            accel_velo: [0.; 2],
+            change_direction: None,
+            change_speed: None,
+            change_accel: [None; 2],
+            vanished: false,
+        }
+    }
+
+    pub fn document(&self) -> &str {
+        &self.document
+    }
+
+    /// All the actions have run to the end.
+    pub fn is_finished(&self) -> bool {
+        self.threads.iter().all(|thread| thread.stack.is_empty())
+    }
+
+    /// A `<vanish>` command has run, which removes the bullet.
+    pub fn is_vanished(&self) -> bool {
+        self.vanished
+    }
+
+    /// The velocity of the bullet on the screen.
+    pub fn velo(&self) -> [f64; 2] {
+        let (s, c) = self.direction.to_radians().sin_cos();
+        [
+            s * self.speed + self.accel_velo[0],
+            -c * self.speed + self.accel_velo[1],
+        ]
+    }
+
+    /// Run the actions for a frame from `pos`, collecting the fired bullets into `fired`.
+    /// Aimed directions aim at `target`, and `rank` is the difficulty from 0 to 1.
+    pub fn advance(
+        &mut self,
+        doc: &BulletML,
+        pos: [f64; 2],
+        target: [f64; 2],
+        rank: f64,
+        rng: &mut Xor128,
+        fired: &mut Vec<Projectile>,
+    ) {
+        apply_change(&mut self.change_direction, &mut self.direction);
+        apply_change(&mut self.change_speed, &mut self.speed);
+        for (change, velo) in self.change_accel.iter_mut().zip(&mut self.accel_velo) {
+            apply_change(change, velo);
+        }
+
+        let mut ctx = Context {
+            doc,
+            pos,
+            target,
+            rank,
+            rng,
+            fired,
+        };
+        let mut threads = std::mem::take(&mut self.threads);
+        for thread in &mut threads {
+            self.run(thread, &mut ctx);
+        }
+        self.threads = threads;
+    }
+
+    fn run(&mut self, thread: &mut Thread, ctx: &mut Context) {
+        if 0 < thread.wait {
+            thread.wait -= 1;
+            if 0 < thread.wait {
+                return;
+            }
+        }
+        let mut steps = 0;
+        while let Some(frame) = thread.stack.last_mut() {
+            if MAX_STEPS_PER_FRAME <= steps {
+                return;
+            }
+            steps += 1;
+            let Some(command) = frame.action.get(frame.cursor).cloned() else {
+                if 1 < frame.repeat {
+                    frame.repeat -= 1;
+                    frame.cursor = 0;
+                } else {
+                    thread.stack.pop();
+                }
+                continue;
+            };
+            frame.cursor += 1;
+            let params = frame.params.clone();
+            match command {
+                Command::Repeat { times, action } => {
+                    let times = ctx.eval(&times, &params).floor().min(MAX_REPEAT);
+                    if 1. <= times && thread.stack.len() < MAX_DEPTH {
+                        if let Some(resolved) = ctx.resolve(action, |doc| &doc.actions, &params) {
+                            thread.stack.push(Frame::new(resolved, times as u32));
+                        }
+                    }
+                }
+                Command::Action(action) => {
+                    if thread.stack.len() < MAX_DEPTH {
+                        if let Some(resolved) = ctx.resolve(action, |doc| &doc.actions, &params) {
+                            thread.stack.push(Frame::new(resolved, 1));
+                        }
+                    }
+                }
+                Command::Fire(fire) => {
+                    if let Some((fire, params)) = ctx.resolve(fire, |doc| &doc.fires, &params) {
+                        self.fire(thread, fire, &params, ctx);
+                    }
+                }
+                Command::ChangeDirection { direction, term } => {
+                    let term = ctx.eval(&term, &params).max(1.);
+                    let value = ctx.eval(&direction.value, &params);
+                    let delta = match direction.kind {
+                        DirectionType::Aim => normalize_angle(ctx.aim() + value - self.direction),
+                        DirectionType::Absolute => normalize_angle(value - self.direction),
+                        DirectionType::Relative => value,
+                        DirectionType::Sequence => value * term,
+                    };
+                    self.change_direction = Some(Change {
+                        delta: delta / term,
+                        frames: term as u32,
+                    });
+                }
+                Command::ChangeSpeed { speed, term } => {
+                    let term = ctx.eval(&term, &params).max(1.);
+                    let delta = speed_delta(&speed, self.speed, term, &params, ctx);
+                    self.change_speed = Some(Change {
+                        delta,
+                        frames: term as u32,
+                    });
+                }
+                Command::Accel {
+                    horizontal,
+                    vertical,
+                    term,
+                } => {
+                    let term = ctx.eval(&term, &params).max(1.);
+                    for (i, speed) in [horizontal, vertical].iter().enumerate() {
+                        if let Some(speed) = speed {
+                            let delta = speed_delta(speed, self.accel_velo[i], term, &params, ctx);
+                            self.change_accel[i] = Some(Change {
+                                delta,
+                                frames: term as u32,
+                            });
+                        }
+                    }
+                }
+                Command::Wait(frames) => {
+                    let frames = ctx.eval(&frames, &params).floor();
+                    if 1. <= frames {
+                        thread.wait = frames as u32;
+                        return;
+                    }
+                }
+                Command::Vanish => {
+                    self.vanished = true;
+                    thread.stack.clear();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn fire(&mut self, thread: &mut Thread, fire: Fire, params: &[f64], ctx: &mut Context) {
+        let Some((bullet, bullet_params)) = ctx.resolve(fire.bullet, |doc| &doc.bullets, params)
+        else {
+            return;
+        };
+        let aim = ctx.aim();
+
+        // The fire overrides the direction and the speed of the bullet
+        let direction = match (&fire.direction, &bullet.direction) {
+            (Some(direction), _) => Some((direction, params)),
+            (None, Some(direction)) => Some((direction, &bullet_params[..])),
+            (None, None) => None,
+        };
+        let direction = direction.map_or(aim, |(direction, params)| {
+            let value = ctx.eval(&direction.value, params);
+            match direction.kind {
+                DirectionType::Aim => aim + value,
+                DirectionType::Absolute => value,
+                DirectionType::Relative => self.direction + value,
+                DirectionType::Sequence => thread.prev_direction.unwrap_or(aim) + value,
+            }
+        });
+        let speed = match (&fire.speed, &bullet.speed) {
+            (Some(speed), _) => Some((speed, params)),
+            (None, Some(speed)) => Some((speed, &bullet_params[..])),
+            (None, None) => None,
+        };
+        let speed = speed.map_or(1., |(speed, params)| {
+            let value = ctx.eval(&speed.value, params);
+            match speed.kind {
+                SpeedType::Absolute => value,
+                SpeedType::Relative => self.speed + value,
+                SpeedType::Sequence => thread.prev_speed.unwrap_or(1.) + value,
+            }
+        });
+        thread.prev_direction = Some(direction);
+        thread.prev_speed = Some(speed);
+
+        let threads = bullet
+            .actions
+            .into_iter()
+            .filter_map(|action| ctx.resolve(action, |doc| &doc.actions, &bullet_params))
+            .map(Thread::new)
+            .collect::<Vec<_>>();
+        let runner = Self::with_threads(self.document.clone(), threads, direction, speed);
+        let base = BulletBase(Entity::new(ctx.pos, runner.velo()));
+        // A bullet without actions just goes straight
+        ctx.fired.push(if runner.threads.is_empty() {
+            Projectile::EnemyBullet(base)
+        } else {
+            Projectile::BulletML {
+                base,
+                runner: Box::new(runner),
+            }
+        });
+    }
+}
+
+/// The change per frame of a speed or a velocity component by `<changeSpeed>` or `<accel>`.
+fn speed_delta(speed: &Speed, current: f64, term: f64, params: &[f64], ctx: &mut Context) -> f64 {
+    let value = ctx.eval(&speed.value, params);
+    match speed.kind {
+        SpeedType::Absolute => (value - current) / term,
+        SpeedType::Relative => value / term,
+        SpeedType::Sequence => value,
+    }
+}
+
+#[cfg(test)]
+fn run_frames(xml: &str, frames: usize, fired: &mut Vec<Projectile>) -> BulletMLRunner {
+    let doc = BulletML::parse(xml).unwrap();
+    let mut runner = BulletMLRunner::new("test", &doc);
+    let mut rng = Xor128::new(1);
+    for _ in 0..frames {
+        runner.advance(&doc, [100., 100.], [100., 200.], 0.5, &mut rng, fired);
+    }
+    runner
+}
+
+#[test]
+fn bulletml_expressions() {
+    let mut rng = Xor128::new(1);
+    let eval = |text: &str, rng: &mut Xor128| Expr::parse(text).unwrap().eval(&[4., 2.], 0.5, rng);
+    assert_eq!(eval("2 + 3 * $1", &mut rng), 14.);
+    assert_eq!(eval("(1+2)*-3", &mut rng), -9.);
+    assert_eq!(eval("$rank * 10 - $2 / 4", &mut rng), 4.5);
+    assert_eq!(eval("7 % 4", &mut rng), 3.);
+    let rand = eval("$rand", &mut rng);
+    assert!((0. ..1.).contains(&rand));
+    assert!(Expr::parse("1 +").is_err());
+    assert!(Expr::parse("$0").is_err());
+    assert!(Expr::parse("(2").is_err());
+}
+
+#[test]
+fn bulletml_ring() {
+    // A ring of 8 bullets from straight up, then another one 10 frames later
+    let xml = r#"<?xml version="1.0" ?>
+<!DOCTYPE bulletml SYSTEM "bulletml.dtd">
+<bulletml type="vertical" xmlns="http://www.asahi-net.or.jp/~cs8k-cyu/bulletml">
+<action label="top">
+  <fireRef label="shot"><param>0</param></fireRef>
+  <repeat><times>7</times>
+    <action>
+      <fire>
+        <direction type="sequence">45</direction>
+        <speed type="sequence">0</speed>
+        <bulletRef label="plain"/>
+      </fire>
+    </action>
+  </repeat>
+  <wait>10</wait>
+  <fire><bulletRef label="plain"/></fire>
+</action>
+<fire label="shot">
+  <direction type="absolute">$1</direction>
+  <speed>2</speed>
+  <bulletRef label="plain"/>
+</fire>
+<bullet label="plain"/>
+</bulletml>"#;
+    let mut fired = vec![];
+    let runner = run_frames(xml, 10, &mut fired);
+    assert_eq!(fired.len(), 8);
+    for (i, bullet) in fired.iter().enumerate() {
+        let angle = (i as f64 * 45.).to_radians();
+        assert!(matches!(bullet, Projectile::EnemyBullet(_)));
+        // The speed is kept in the sequence
+        assert!((bullet.velo[0] - 2. * angle.sin()).abs() < 1e-9);
+        assert!((bullet.velo[1] + 2. * angle.cos()).abs() < 1e-9);
+    }
+    assert!(!runner.is_finished());
+
+    let mut fired = vec![];
+    let runner = run_frames(xml, 12, &mut fired);
+    assert_eq!(fired.len(), 9);
+    // Aimed down at the target with the default speed
+    let last = fired.last().unwrap();
+    assert!(last.velo[0].abs() < 1e-9 && (last.velo[1] - 1.).abs() < 1e-9);
+    assert!(runner.is_finished());
+}
+
+#[test]
+fn bulletml_bullet_actions() {
+    let xml = r#"<bulletml>
+<action label="top">
+  <fire>
+    <direction type="absolute">180</direction>
+    <speed>1</speed>
+    <bullet>
+      <action>
+        <changeSpeed><speed>3</speed><term>10</term></changeSpeed>
+        <changeDirection><direction type="relative">90</direction><term>10</term></changeDirection>
+        <accel><horizontal>0.5</horizontal><term>5</term></accel>
+        <wait>20</wait>
+        <vanish/>
+      </action>
+    </bullet>
+  </fire>
+</action>
+</bulletml>"#;
+    let doc = BulletML::parse(xml).unwrap();
+    let mut fired = vec![];
+    run_frames(xml, 1, &mut fired);
+    let Some(Projectile::BulletML { runner, .. }) = fired.pop() else {
+        panic!("the bullet has no actions")
+    };
+    let mut runner = *runner;
+    let mut rng = Xor128::new(1);
+    for _ in 0..11 {
+        runner.advance(&doc, [0.; 2], [0.; 2], 0., &mut rng, &mut fired);
+    }
+    // Turned from down to left at the speed of 3, drifting right by 0.5
+    let velo = runner.velo();
+    assert!((velo[0] + 2.5).abs() < 1e-9 && velo[1].abs() < 1e-9);
+    assert!(!runner.is_vanished());
+    for _ in 0..10 {
+        runner.advance(&doc, [0.; 2], [0.; 2], 0., &mut rng, &mut fired);
+    }
+    assert!(runner.is_vanished());
+    assert!(fired.is_empty());
+}
+
+#[test]
+fn bulletml_errors() {
+    assert!(matches!(
+        BulletML::parse("<bulletml><action"),
+        Err(BulletMLError::Xml(_))
+    ));
+    let invalid = [
+        "<html/>",
+        "<bulletml><action label=\"a\"/></bulletml>",
+        "<bulletml><action label=\"top\"><actionRef label=\"none\"/></action></bulletml>",
+        "<bulletml><action label=\"top\"><shoot/></action></bulletml>",
+        "<bulletml><action label=\"top\"><wait>$rnd</wait></action></bulletml>",
+    ];
+    for xml in invalid {
+        assert!(
+            matches!(BulletML::parse(xml), Err(BulletMLError::Invalid(_))),
+            "{}",
+            xml
+        );
+    }
+}
+
+#[test]
+fn bulletml_runaway() {
+    let runaway = [
+        // Refers to itself twice at every level
+        r#"<bulletml><action label="top">
+  <repeat><times>2</times><action>
+    <fire><bullet/></fire>
+    <actionRef label="top"/>
+  </action></repeat>
+</action></bulletml>"#,
+        // Repeats without waits
+        r#"<bulletml><action label="top">
+  <repeat><times>1000000000</times><action><fire><bullet/></fire></action></repeat>
+</action></bulletml>"#,
+    ];
+    for xml in runaway {
+        let mut fired = vec![];
+        let runner = run_frames(xml, 3, &mut fired);
+        // Goes on over the frames instead of freezing in one
+        assert!(!runner.is_finished());
+        assert!(!fired.is_empty());
+        assert!(fired.len() <= 3 * MAX_STEPS_PER_FRAME as usize);
+    }
+}
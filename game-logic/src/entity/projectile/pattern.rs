@@ -0,0 +1,539 @@
+//! A small declarative language for enemy bullet patterns.
+//!
+//! A pattern is a list of actions, like firing a ring of bullets, turning or waiting,
+//! which a [`PatternRunner`] runs over frames. Enemy kinds refer to patterns by name in
+//! a [`PatternLibrary`], which can be loaded from JSON, so new patterns need no Rust code.
+//! Patterns can also run BulletML documents loaded into the library.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use vecmath::{vec2_add, vec2_dot, vec2_len, vec2_scale, vec2_square_len, vec2_sub};
+
+use super::{BulletBase, BulletML, BulletMLError, BulletMLRunner, Projectile};
+use crate::{
+    entity::{EnemyKind, Entity, EntitySet},
+    xor128::Xor128,
+};
+
+#[derive(Debug)]
+pub enum PatternError {
+    Parse(serde_json::Error),
+    /// An enemy kind or a split refers to a pattern that does not exist.
+    UnknownPattern(String),
+    /// A value is out of its range.
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatternError::Parse(e) => write!(f, "Bullet pattern file is broken: {}", e),
+            PatternError::UnknownPattern(name) => write!(f, "Unknown bullet pattern: {}", name),
+            PatternError::Invalid(e) => write!(f, "Bullet pattern is invalid: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// The look and the movement of a bullet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BulletKind {
+    /// A round bullet going straight
+    Enemy,
+    /// A long bullet pulsing its speed
+    Phase,
+    /// A long bullet curving into a spiral
+    Spiral,
+    /// A missile steering toward the player for a while
+    Missile,
+    /// A mine going off when the player comes close, firing its split pattern, which it
+    /// must have as it does not hit the player by itself
+    Mine,
+}
+
+/// A bullet fired by an action.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BulletSpec {
+    pub kind: BulletKind,
+    pub speed: f64,
+    /// Added to the speed every frame.
+    #[serde(default)]
+    pub accel: f64,
+    /// The speed stops ramping at this value.
+    #[serde(default)]
+    pub speed_limit: Option<f64>,
+    /// Replace the bullet with another pattern fired from its position after some frames.
+    #[serde(default)]
+    pub split: Option<BulletSplit>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BulletSplit {
+    pub after: u32,
+    pub pattern: String,
+}
+
+/// An action of a pattern. Angles are in radians, clockwise from the right on the screen.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PatternAction {
+    /// Fire `count` bullets evenly spaced around the circle, starting at the current direction.
+    Ring { count: u32, bullet: BulletSpec },
+    /// Fire `count` bullets spread over `spread` radians, centered on the player.
+    AimedFan {
+        count: u32,
+        spread: f64,
+        bullet: BulletSpec,
+        /// Aim at where the player will be when the bullet arrives, if they keep moving
+        /// the same way.
+        #[serde(default)]
+        lead: bool,
+    },
+    /// Fire a bullet in the current direction.
+    Fire { bullet: BulletSpec },
+    /// Fire a bullet with a random velocity, each component of which is up to half the speed.
+    Scatter { bullet: BulletSpec },
+    /// Turn the current direction, which makes rotating streams in a repeat.
+    Rotate(f64),
+    /// Set the current direction to a random angle up to the given value.
+    RandomAngle(f64),
+    /// Run the next action after the given number of frames.
+    Wait(u32),
+    /// Roll a dice every frame, and run the next action when it comes up one in the given number.
+    WaitChance(u32),
+    Repeat {
+        times: u32,
+        actions: Vec<PatternAction>,
+    },
+    /// Run the top actions of the named BulletML document in the library until they finish.
+    /// A document that is not loaded fires nothing.
+    BulletML(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BulletPattern {
+    pub actions: Vec<PatternAction>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PatternLibrary {
+    pub patterns: BTreeMap<String, BulletPattern>,
+    /// The pattern each enemy kind fires over and over
+    pub enemies: BTreeMap<EnemyKind, String>,
+    /// BulletML documents by name, added with [`PatternLibrary::load_bulletml`]
+    #[serde(default)]
+    pub bulletml: BTreeMap<String, BulletML>,
+}
+
+impl Default for PatternLibrary {
+    fn default() -> Self {
+        // The built-in patterns are tested to parse
+        Self::from_json(include_str!("../../../bullet_patterns/default.json")).unwrap()
+    }
+}
+
+impl PatternLibrary {
+    pub fn from_json(json: &str) -> Result<Self, PatternError> {
+        let ret: Self = serde_json::from_str(json).map_err(PatternError::Parse)?;
+        ret.check()?;
+        Ok(ret)
+    }
+
+    /// Make sure that all the referred patterns exist and the values are in range.
+    fn check(&self) -> Result<(), PatternError> {
+        fn check_actions(
+            library: &PatternLibrary,
+            actions: &[PatternAction],
+        ) -> Result<(), PatternError> {
+            for action in actions {
+                let bullet = match action {
+                    PatternAction::Ring { bullet, .. }
+                    | PatternAction::AimedFan { bullet, .. }
+                    | PatternAction::Fire { bullet }
+                    | PatternAction::Scatter { bullet } => bullet,
+                    PatternAction::Repeat { actions, .. } => {
+                        check_actions(library, actions)?;
+                        continue;
+                    }
+                    PatternAction::WaitChance(0) => {
+                        return Err(PatternError::Invalid("WaitChance must be positive"));
+                    }
+                    _ => continue,
+                };
+                if let Some(split) = &bullet.split {
+                    library.get(&split.pattern)?;
+                } else if bullet.kind == BulletKind::Mine {
+                    return Err(PatternError::Invalid("a Mine must have a split to fire"));
+                }
+            }
+            Ok(())
+        }
+
+        for name in self.enemies.values() {
+            self.get(name)?;
+        }
+        for pattern in self.patterns.values() {
+            check_actions(self, &pattern.actions)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<&BulletPattern, PatternError> {
+        self.patterns
+            .get(name)
+            .ok_or_else(|| PatternError::UnknownPattern(name.to_string()))
+    }
+
+    /// Add the BulletML document in `xml` under `name`, replacing the one with the same name.
+    pub fn load_bulletml(&mut self, name: &str, xml: &str) -> Result<(), BulletMLError> {
+        self.bulletml
+            .insert(name.to_string(), BulletML::parse(xml)?);
+        Ok(())
+    }
+
+    /// The pattern fired by an enemy kind, if any.
+    pub fn enemy_pattern(&self, kind: EnemyKind) -> Option<&str> {
+        self.enemies.get(&kind).map(|name| name.as_str())
+    }
+}
+
+/// The progress of a pattern fired by an enemy or a split bullet.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PatternRunner {
+    pattern: String,
+    /// The index of the next action and the count of iterations at each level of repeats
+    stack: Vec<(usize, u32)>,
+    /// Frames to wait before running the next action
+    wait: u32,
+    /// The current direction
+    angle: f64,
+    /// The BulletML document being run
+    bulletml: Option<BulletMLRunner>,
+}
+
+impl PatternRunner {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            stack: vec![(0, 0)],
+            wait: 0,
+            angle: 0.,
+            bulletml: None,
+        }
+    }
+
+    /// Run the pattern for a frame, firing bullets from `pos` into `bullets`.
+    /// Aimed actions aim at `target`, which is the player, and `rank` is the difficulty
+    /// from 0 to 1.
+    ///
+    /// Returns true when the pattern has finished. It starts over in the next call, keeping
+    /// the current direction.
+    pub fn advance(
+        &mut self,
+        library: &PatternLibrary,
+        pos: [f64; 2],
+        target: &Entity,
+        rank: f64,
+        rng: &mut Xor128,
+        bullets: &mut EntitySet<Projectile>,
+    ) -> bool {
+        if 0 < self.wait {
+            self.wait -= 1;
+            if 0 < self.wait {
+                return false;
+            }
+        }
+        if let Some(runner) = &mut self.bulletml {
+            if let Some(doc) = library.bulletml.get(runner.document()) {
+                let mut fired = vec![];
+                runner.advance(doc, pos, target.pos, rank, rng, &mut fired);
+                for bullet in fired {
+                    bullets.insert(bullet);
+                }
+                if !runner.is_finished() {
+                    return false;
+                }
+            }
+            self.bulletml = None;
+        }
+        let Some(pattern) = library.patterns.get(&self.pattern) else {
+            return true;
+        };
+        loop {
+            let depth = self.stack.len() - 1;
+            let actions = self.actions_at(pattern, depth);
+            let (cursor, iteration) = self.stack[depth];
+            let Some(action) = actions.get(cursor) else {
+                if depth == 0 {
+                    self.stack[0] = (0, 0);
+                    return true;
+                }
+                let PatternAction::Repeat { times, .. } =
+                    self.actions_at(pattern, depth - 1)[self.stack[depth - 1].0]
+                else {
+                    unreachable!()
+                };
+                if iteration + 1 < times {
+                    self.stack[depth] = (0, iteration + 1);
+                } else {
+                    self.stack.pop();
+                    self.stack[depth - 1].0 += 1;
+                }
+                continue;
+            };
+            self.stack[depth].0 += 1;
+            match action {
+                PatternAction::Ring { count, bullet } => {
+                    for i in 0..*count {
+                        let angle =
+                            2. * std::f64::consts::PI * i as f64 / *count as f64 + self.angle;
+                        fire(bullets, bullet, pos, angle);
+                    }
+                }
+                PatternAction::AimedFan {
+                    count,
+                    spread,
+                    bullet,
+                    lead,
+                } => {
+                    let aim = if *lead {
+                        lead_angle(pos, target, bullet.speed)
+                    } else {
+                        let delta = vec2_sub(target.pos, pos);
+                        delta[1].atan2(delta[0])
+                    };
+                    for i in 0..*count {
+                        let angle = if *count <= 1 {
+                            aim
+                        } else {
+                            aim - spread / 2. + spread * i as f64 / (*count - 1) as f64
+                        };
+                        fire(bullets, bullet, pos, angle);
+                    }
+                }
+                PatternAction::Fire { bullet } => fire(bullets, bullet, pos, self.angle),
+                PatternAction::Scatter { bullet } => {
+                    let velo = [rng.gen() - 0.5, rng.gen() - 0.5];
+                    bullets.insert(new_bullet(
+                        bullet,
+                        Entity::new(pos, vec2_scale(velo, bullet.speed)),
+                    ));
+                }
+                PatternAction::Rotate(angle) => self.angle += angle,
+                PatternAction::RandomAngle(range) => self.angle = rng.gen() * range,
+                PatternAction::Wait(frames) => {
+                    if 0 < *frames {
+                        self.wait = *frames;
+                        return false;
+                    }
+                }
+                PatternAction::WaitChance(one_in) => {
+                    if rng.gen_range(0, *one_in) != 0 {
+                        self.stack[depth].0 -= 1;
+                        return false;
+                    }
+                }
+                PatternAction::Repeat { times, actions } => {
+                    if 0 < *times && !actions.is_empty() {
+                        self.stack[depth].0 -= 1;
+                        self.stack.push((0, 0));
+                    }
+                }
+                PatternAction::BulletML(name) => {
+                    if let Some(doc) = library.bulletml.get(name) {
+                        self.bulletml = Some(BulletMLRunner::new(name, doc));
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The actions at the given level of repeats.
+    fn actions_at<'a>(&self, pattern: &'a BulletPattern, depth: usize) -> &'a [PatternAction] {
+        let mut actions = &pattern.actions[..];
+        for (cursor, _) in &self.stack[..depth] {
+            if let PatternAction::Repeat {
+                actions: children, ..
+            } = &actions[*cursor]
+            {
+                actions = children;
+            }
+        }
+        actions
+    }
+}
+
+fn fire(bullets: &mut EntitySet<Projectile>, bullet: &BulletSpec, pos: [f64; 2], angle: f64) {
+    let velo = vec2_scale([angle.cos(), angle.sin()], bullet.speed);
+    bullets.insert(new_bullet(
+        bullet,
+        Entity::new(pos, velo).rotation(angle as f32),
+    ));
+}
+
+fn new_bullet(spec: &BulletSpec, ent: Entity) -> Projectile {
+    let base = BulletBase(ent);
+    let bullet = match spec.kind {
+        BulletKind::Enemy => Projectile::EnemyBullet(base),
+        BulletKind::Phase => Projectile::new_phase(base),
+        BulletKind::Spiral => Projectile::new_spiral(base),
+        BulletKind::Missile => Projectile::new_enemy_missile(base),
+        BulletKind::Mine => Projectile::new_mine(base),
+    };
+    if spec.accel == 0. && spec.split.is_none() {
+        return bullet;
+    }
+    Projectile::Patterned {
+        bullet: Box::new(bullet),
+        accel: spec.accel,
+        speed_limit: spec.speed_limit,
+        split: spec.split.clone(),
+        age: 0,
+    }
+}
+
+/// The direction to hit `target` with a bullet of `speed` if it keeps its velocity, or
+/// the direction to its current position if the bullet cannot catch up with it.
+fn lead_angle(pos: [f64; 2], target: &Entity, speed: f64) -> f64 {
+    let delta = vec2_sub(target.pos, pos);
+    // Solve |delta + velo * time| = speed * time for the earliest time in the future
+    let a = vec2_square_len(target.velo) - speed * speed;
+    let b = 2. * vec2_dot(delta, target.velo);
+    let c = vec2_square_len(delta);
+    let time = if a.abs() < f64::EPSILON {
+        -c / b
+    } else {
+        let discriminant = b * b - 4. * a * c;
+        if discriminant < 0. {
+            -1.
+        } else {
+            let root = discriminant.sqrt();
+            let (t0, t1) = ((-b - root) / (2. * a), (-b + root) / (2. * a));
+            if 0. < t0.min(t1) {
+                t0.min(t1)
+            } else {
+                t0.max(t1)
+            }
+        }
+    };
+    let aim = if 0. < time {
+        vec2_add(delta, vec2_scale(target.velo, time))
+    } else {
+        delta
+    };
+    aim[1].atan2(aim[0])
+}
+
+/// Change a speed by `accel`, up to `limit`.
+pub(super) fn ramp_speed(speed: f64, accel: f64, limit: Option<f64>) -> f64 {
+    let speed = speed + accel;
+    let speed = match limit {
+        Some(limit) if 0. < accel => speed.min(limit),
+        Some(limit) => speed.max(limit),
+        None => speed,
+    };
+    speed.max(0.)
+}
+
+/// Scale a velocity to the ramped speed.
+pub(super) fn ramp_velo(velo: [f64; 2], accel: f64, limit: Option<f64>) -> [f64; 2] {
+    let speed = vec2_len(velo);
+    if speed == 0. {
+        return velo;
+    }
+    vec2_scale(velo, ramp_speed(speed, accel, limit) / speed)
+}
+
+/// A pattern fired by a split bullet, which stays where the bullet was.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PatternEmitter {
+    pub pos: [f64; 2],
+    pub runner: PatternRunner,
+}
+
+#[test]
+fn default_patterns() {
+    let mut library = PatternLibrary::default();
+    library
+        .load_bulletml("example", include_str!("../../../bulletml/example.xml"))
+        .unwrap();
+    for name in library.patterns.keys() {
+        let mut runner = PatternRunner::new(name);
+        let mut bullets = EntitySet::new();
+        let mut rng = Xor128::new(1);
+        for _ in 0..1000 {
+            runner.advance(
+                &library,
+                [100., 100.],
+                &Entity::new([100., 200.], [0.; 2]),
+                0.,
+                &mut rng,
+                &mut bullets,
+            );
+        }
+        assert!(!bullets.is_empty(), "{} did not fire", name);
+    }
+
+    let json = serde_json::to_string(&library).unwrap();
+    assert_eq!(PatternLibrary::from_json(&json).unwrap(), library);
+    assert!(matches!(
+        PatternLibrary::from_json(r#"{"patterns": {}, "enemies": {"Boss": "none"}}"#),
+        Err(PatternError::UnknownPattern(_))
+    ));
+    assert!(matches!(
+        PatternLibrary::from_json(
+            r#"{"patterns": {"a": {"actions": [{"Repeat": {"times": 2, "actions": [{"WaitChance": 0}]}}]}}, "enemies": {}}"#
+        ),
+        Err(PatternError::Invalid(_))
+    ));
+    assert!(matches!(
+        PatternLibrary::from_json(
+            r#"{"patterns": {"a": {"actions": [{"Fire": {"bullet": {"kind": "Mine", "speed": 0.2}}}]}}, "enemies": {}}"#
+        ),
+        Err(PatternError::Invalid(_))
+    ));
+}
+
+#[test]
+fn lead_shot() {
+    let library = PatternLibrary::from_json(
+        r#"{
+            "patterns": {
+                "lead": {
+                    "actions": [{
+                        "AimedFan": {
+                            "count": 1,
+                            "spread": 0,
+                            "bullet": { "kind": "Enemy", "speed": 2 },
+                            "lead": true
+                        }
+                    }]
+                }
+            },
+            "enemies": {}
+        }"#,
+    )
+    .unwrap();
+    let mut target = Entity::new([100., 200.], [1.5, 0.]);
+    let mut bullets = EntitySet::new();
+    PatternRunner::new("lead").advance(
+        &library,
+        [100., 100.],
+        &target,
+        0.,
+        &mut Xor128::new(1),
+        &mut bullets,
+    );
+    let mut bullet = bullets.iter().next().unwrap().pos;
+    let velo = bullets.iter().next().unwrap().velo;
+    // The bullet meets the target as long as it moves straight
+    let mut closest = f64::INFINITY;
+    for _ in 0..200 {
+        bullet = vec2_add(bullet, velo);
+        target.pos = vec2_add(target.pos, target.velo);
+        closest = closest.min(vec2_len(vec2_sub(bullet, target.pos)));
+    }
+    assert!(closest < 1., "missed by {}", closest);
+}
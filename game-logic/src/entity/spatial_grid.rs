@@ -0,0 +1,114 @@
+//! A uniform grid broadphase for collision tests against enemies.
+//!
+//! Testing every bullet against every enemy costs O(bullets × enemies) per frame.
+//! The grid buckets the enemies by the cells their bounding boxes cover, so that a query
+//! only returns the enemies near the queried rectangle. The exact tests are still made
+//! by the caller, so the results are identical to testing all the enemies.
+
+use super::{Enemy, EntityId, EntitySet};
+use crate::consts::{HEIGHT, WIDTH};
+
+const CELL_SIZE: f64 = 32.;
+const COLS: usize = (WIDTH as usize).div_ceil(CELL_SIZE as usize);
+const ROWS: usize = (HEIGHT as usize).div_ceil(CELL_SIZE as usize);
+
+pub struct SpatialGrid {
+    /// Ids of the enemies in each cell
+    cells: Vec<Vec<EntityId<Enemy>>>,
+}
+
+impl SpatialGrid {
+    pub fn new(enemies: &EntitySet<Enemy>) -> Self {
+        let mut ret = Self {
+            cells: vec![vec![]; COLS * ROWS],
+        };
+        for (id, enemy) in enemies.items() {
+            ret.insert(id, &enemy);
+        }
+        ret
+    }
+
+    /// Register an enemy added after the grid was built, e.g. a split centipede.
+    pub fn insert(&mut self, id: EntityId<Enemy>, enemy: &Enemy) {
+        let mut add_rect = |rect: &[f64; 4]| {
+            let [x0, y0, x1, y1] = Self::cell_range(rect);
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    let cell = &mut self.cells[x + y * COLS];
+                    if cell.last() != Some(&id) {
+                        cell.push(id);
+                    }
+                }
+            }
+        };
+        for rect in enemy.body_bbs() {
+            add_rect(&rect);
+        }
+    }
+
+    /// Returns the ids of the enemies that may intersect with `rect`, in the same order as
+    /// iterating the EntitySet.
+    /// Enemies outside of the screen are put in the cells at the border, so no enemy is missed.
+    pub fn query(&self, rect: &[f64; 4]) -> Vec<EntityId<Enemy>> {
+        let [x0, y0, x1, y1] = Self::cell_range(rect);
+        let mut ret = vec![];
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                ret.extend_from_slice(&self.cells[x + y * COLS]);
+            }
+        }
+        ret.sort_unstable_by_key(|id| id.id);
+        ret.dedup();
+        ret
+    }
+
+    /// The range of cells covered by the rectangle, clamped to the grid.
+    fn cell_range(rect: &[f64; 4]) -> [usize; 4] {
+        let cell = |v: f64, max: usize| ((v / CELL_SIZE).floor().max(0.) as usize).min(max - 1);
+        [
+            cell(rect[0], COLS),
+            cell(rect[1], ROWS),
+            cell(rect[2], COLS),
+            cell(rect[3], ROWS),
+        ]
+    }
+}
+
+#[test]
+fn grid_query() {
+    use super::{bounding_box, Collider};
+    use crate::{input::InputFrame, xor128::Xor128, ShooterState};
+
+    let mut rng = Xor128::new(1);
+    let mut state = ShooterState::default();
+    for _ in 0..10 {
+        for _ in 0..300 {
+            state.step(&InputFrame::default());
+        }
+
+        let enemies = &state.enemies;
+        let grid = SpatialGrid::new(enemies);
+        for _ in 0..1000 {
+            let pos = [
+                rng.gen_rangef(-64., WIDTH as f64 + 64.),
+                rng.gen_rangef(-64., HEIGHT as f64 + 64.),
+            ];
+            let rect = bounding_box(&pos, rng.gen_rangef(1., 64.));
+            let brute_force: Vec<_> = enemies
+                .items()
+                .filter(|(_, enemy)| enemy.test_hit(&Collider::Aabb(rect)))
+                .map(|(id, _)| id)
+                .collect();
+            let broadphase: Vec<_> = grid
+                .query(&rect)
+                .into_iter()
+                .filter(|id| {
+                    enemies
+                        .get(*id)
+                        .is_some_and(|enemy| enemy.test_hit(&Collider::Aabb(rect)))
+                })
+                .collect();
+            assert_eq!(broadphase, brute_force);
+        }
+    }
+}
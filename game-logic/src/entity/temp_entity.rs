@@ -1,67 +1,115 @@
-use std::rc::Rc;
-
+#[cfg(all(not(feature = "webgl"), feature = "piston"))]
+use crate::assets_piston::Assets;
 #[cfg(feature = "webgl")]
 use crate::assets_webgl::Assets;
 
 use super::{DeathReason, Entity};
+use crate::consts::{EXPLODE2_SIZE, EXPLODE_SIZE};
 #[cfg(feature = "webgl")]
 use cgmath::{Matrix3, Matrix4, Rad, Vector2, Vector3};
+use serde::{Deserialize, Serialize};
 #[cfg(feature = "webgl")]
 use web_sys::{WebGlRenderingContext as GL, WebGlTexture};
 
 #[cfg(all(not(feature = "webgl"), feature = "piston"))]
 use piston_window::{
-    math::{rotate_radians, translate},
+    math::{rotate_radians, scale, translate},
     *,
 };
 
 #[cfg(all(not(feature = "webgl"), feature = "piston"))]
 use super::Matrix;
 
-#[cfg(feature = "webgl")]
-pub struct TempEntity {
-    pub base: Entity,
-    pub texture: Rc<WebGlTexture>,
-    pub max_frames: u32,
-    pub width: u32,
-    pub playback_rate: u32,
-    pub image_width: u32,
-    pub size: f64,
-    pub shrink_rate: f32,
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TempEntityType {
     Explode,
     Explode2,
     Blood,
 }
 
-#[cfg(all(not(feature = "webgl"), feature = "piston"))]
+/// A short-lived visual effect, like an explosion.
+///
+/// It does not hold a texture handle, so that the simulation can run without any
+/// rendering backend. The backends look up the texture by `ty` when drawing.
+#[derive(Serialize, Deserialize)]
 pub struct TempEntity {
     pub base: Entity,
-    pub texture: Rc<G2dTexture>,
+    pub ty: TempEntityType,
     pub max_frames: u32,
     pub width: u32,
     pub playback_rate: u32,
+    pub image_width: u32,
+    pub size: f64,
+    pub shrink_rate: f32,
+    /// Drawing scale relative to the natural size of the effect
+    pub scale: f64,
 }
 
-#[cfg(feature = "webgl")]
 impl TempEntity {
+    /// Create an effect of the given type, with the animation parameters for the type.
+    /// The health of `base` is overwritten to the length of the animation.
+    pub fn new(ty: TempEntityType, base: Entity) -> Self {
+        type TT = TempEntityType;
+        let (playback_rate, max_frames, width, image_width) = match ty {
+            TT::Explode => (2, 8, 16, 128),
+            TT::Explode2 => (4, 6, 32, 256),
+            TT::Blood => (2, 4, 16, 64),
+        };
+        let (size, shrink_rate) = match ty {
+            TT::Explode => (EXPLODE_SIZE, 0.),
+            TT::Explode2 => (EXPLODE2_SIZE, 0.),
+            TT::Blood => (EXPLODE_SIZE, 1.),
+        };
+        Self {
+            base: base.health((max_frames * playback_rate) as i32),
+            ty,
+            max_frames,
+            width,
+            playback_rate,
+            image_width,
+            size,
+            shrink_rate,
+            scale: 1.,
+        }
+    }
+
     #[allow(dead_code)]
     pub fn max_frames(mut self, max_frames: u32) -> Self {
         self.max_frames = max_frames;
         self
     }
+
+    /// Play the animation `repeats` times before disappearing.
+    pub fn repeats(mut self, repeats: u32) -> Self {
+        self.base.health = (self.max_frames * self.playback_rate * repeats) as i32;
+        self
+    }
+
+    pub fn scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
     pub fn animate_temp(&mut self) -> Option<DeathReason> {
         self.base.health -= 1;
         self.base.animate()
     }
+}
+
+#[cfg(feature = "webgl")]
+impl TempEntity {
+    fn texture<'a>(&self, assets: &'a Assets) -> &'a WebGlTexture {
+        match self.ty {
+            TempEntityType::Explode => &assets.explode_tex,
+            TempEntityType::Explode2 => &assets.explode2_tex,
+            TempEntityType::Blood => &assets.blood_tex,
+        }
+    }
 
     pub fn draw_temp(&self, context: &GL, assets: &Assets) {
         let shader = assets.sprite_shader.as_ref().unwrap();
         let pos = &self.base.pos;
-        context.bind_texture(GL::TEXTURE_2D, Some(&self.texture));
+        context.bind_texture(GL::TEXTURE_2D, Some(self.texture(assets)));
         let rotation = Matrix4::from_angle_z(Rad(self.base.rotation as f64));
         let translation = Matrix4::from_translation(Vector3::new(pos[0], pos[1], 0.));
         let scale = if self.shrink_rate == 0. {
@@ -70,8 +118,8 @@ impl TempEntity {
             self.size
                 .min(self.base.health as f64 * self.shrink_rate as f64)
         };
-        let scale = Matrix4::from_scale(scale);
-        let frame = self.max_frames as i32 - (self.base.health / self.playback_rate as i32) as i32;
+        let scale = Matrix4::from_scale(scale * self.scale);
+        let frame = self.max_frames as i32 - (self.base.health / self.playback_rate as i32);
         // let image   = Image::new().rect([0f64, 0f64, self.width as f64, tex2.get_height() as f64])
         //     .src_rect([frame as f64 * self.width as f64, 0., self.width as f64, tex2.get_height() as f64]);
         let transform = assets.world_transform * translation * rotation * scale;
@@ -96,23 +144,21 @@ impl TempEntity {
 
 #[cfg(all(not(feature = "webgl"), feature = "piston"))]
 impl TempEntity {
-    #[allow(dead_code)]
-    pub fn max_frames(mut self, max_frames: u32) -> Self {
-        self.max_frames = max_frames;
-        self
-    }
-    pub fn animate_temp(&mut self) -> Option<DeathReason> {
-        self.base.health -= 1;
-        self.base.animate()
+    fn texture<'a>(&self, assets: &'a Assets) -> &'a G2dTexture {
+        match self.ty {
+            TempEntityType::Explode => &assets.explode_tex,
+            TempEntityType::Explode2 => &assets.explode2_tex,
+            TempEntityType::Blood => &assets.blood_tex,
+        }
     }
 
-    pub fn draw_temp(&self, context: &Context, g: &mut G2d) {
+    pub fn draw_temp(&self, context: &Context, g: &mut G2d, assets: &Assets) {
         let pos = &self.base.pos;
-        let tex2 = &*self.texture;
+        let tex2 = self.texture(assets);
         let centerize = translate([-(16. / 2.), -(tex2.get_height() as f64 / 2.)]);
         let rotmat = rotate_radians(self.base.rotation as f64);
         let translate = translate(*pos);
-        let frame = self.max_frames - (self.base.health as u32 / self.playback_rate) as u32;
+        let frame = self.max_frames - (self.base.health as u32 / self.playback_rate);
         let draw_state = if let Some(blend_mode) = self.base.blend {
             context.draw_state.blend(blend_mode)
         } else {
@@ -129,7 +175,12 @@ impl TempEntity {
         image.draw(
             tex2,
             &draw_state,
-            (Matrix(context.transform) * Matrix(translate) * Matrix(rotmat) * Matrix(centerize)).0,
+            (Matrix(context.transform)
+                * Matrix(translate)
+                * Matrix(rotmat)
+                * Matrix(scale(self.scale, self.scale))
+                * Matrix(centerize))
+            .0,
             g,
         );
     }
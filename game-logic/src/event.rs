@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use crate::entity::{BossPhase, EnemyKind, ItemKind, Weapon};
+
+/// Something that happened in the game, collected in [`ShooterState::events`] during a
+/// frame, so that effects, audio, stats and UI can react to it.
+///
+/// [`ShooterState::events`]: crate::ShooterState::events
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GameEvent {
+    EnemyKilled {
+        kind: EnemyKind,
+        pos: [f64; 2],
+    },
+    /// An enemy took `damage` from `weapon` at `pos`, which is where it was hit.
+    EnemyDamaged {
+        kind: EnemyKind,
+        pos: [f64; 2],
+        weapon: Weapon,
+        damage: i32,
+    },
+    /// An enemy bullet hit the player at `pos`, even if the player was invincible, or an
+    /// enemy body touched it in HP mode.
+    PlayerHit {
+        pos: [f64; 2],
+    },
+    /// The player lost a life, and `lives` are left.
+    LifeLost {
+        lives: u32,
+    },
+    GameOver,
+    ItemCollected {
+        kind: ItemKind,
+        pos: [f64; 2],
+    },
+    /// An enemy bullet passed close to the player at `pos` without hitting.
+    Grazed {
+        pos: [f64; 2],
+    },
+    /// A boss was killed quickly enough to give `bonus` points.
+    BossBonus {
+        bonus: u32,
+    },
+    WeaponFired {
+        weapon: Weapon,
+    },
+    WaveStarted {
+        wave: usize,
+    },
+    /// All the commands of the stage have run.
+    StageCleared,
+    /// The player used a bomb at `pos`.
+    BombUsed {
+        pos: [f64; 2],
+    },
+    /// A boss fight started, or the boss entered another attack phase.
+    BossPhaseChanged {
+        phase: BossPhase,
+    },
+}
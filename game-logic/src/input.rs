@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+/// The state of the player's controls in a single frame.
+///
+/// Frontends collect their keyboard state into this structure and pass it to
+/// [`ShooterState::step`](crate::ShooterState::step).
+/// `next_weapon`, `prev_weapon`, `pause` and `bomb` are one-shot actions that should be
+/// set only in the frame the key was pressed. The others are held while the key is down.
+///
+/// It is serialized as bit flags in two bytes to keep replay files compact.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
+pub struct InputFrame {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    pub shoot: bool,
+    pub next_weapon: bool,
+    pub prev_weapon: bool,
+    pub pause: bool,
+    pub bomb: bool,
+    /// Move slowly and show the hitbox
+    pub focus: bool,
+}
+
+impl From<InputFrame> for u16 {
+    fn from(input: InputFrame) -> u16 {
+        [
+            input.up,
+            input.down,
+            input.left,
+            input.right,
+            input.shoot,
+            input.next_weapon,
+            input.prev_weapon,
+            input.pause,
+            input.bomb,
+            input.focus,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, b)| acc | ((*b as u16) << i))
+    }
+}
+
+impl From<u16> for InputFrame {
+    fn from(bits: u16) -> Self {
+        let bit = |i: u16| bits & (1 << i) != 0;
+        Self {
+            up: bit(0),
+            down: bit(1),
+            left: bit(2),
+            right: bit(3),
+            shoot: bit(4),
+            next_weapon: bit(5),
+            prev_weapon: bit(6),
+            pause: bit(7),
+            bomb: bit(8),
+            focus: bit(9),
+        }
+    }
+}
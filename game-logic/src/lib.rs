@@ -1,10 +1,11 @@
 use entity::{EntitySet, TempEntityType};
 #[cfg(all(not(feature = "webgl"), feature = "piston"))]
 use piston_window::{draw_state::Blend, G2d, *};
+use serde::{Deserialize, Serialize};
 #[cfg(feature = "webgl")]
 use std::rc::Rc;
 use std::vec;
-use vecmath::{vec2_normalized, vec2_scale};
+use vecmath::{vec2_len, vec2_normalized, vec2_scale, vec2_sub};
 #[cfg(feature = "webgl")]
 use wasm_bindgen::{prelude::*, JsCast};
 #[cfg(feature = "webgl")]
@@ -12,6 +13,8 @@ use web_sys::{HtmlImageElement, WebGlBuffer, WebGlRenderingContext as GL, WebGlT
 
 #[cfg(feature = "webgl")]
 #[macro_export]
+// Expands to the `log` function of the calling crate, which binds console.log.
+#[allow(clippy::crate_in_macro_def)]
 macro_rules! console_log {
     ($fmt:expr, $($arg1:expr),*) => {
         crate::log(&format!($fmt, $($arg1),+))
@@ -61,6 +64,12 @@ pub mod assets_piston;
 pub mod assets_webgl;
 pub mod consts;
 pub mod entity;
+pub mod event;
+pub mod input;
+pub mod replay;
+pub mod snapshot;
+pub mod spawn_table;
+pub mod stage;
 pub mod xor128;
 
 #[cfg(all(not(feature = "webgl"), feature = "piston"))]
@@ -69,9 +78,14 @@ use crate::assets_piston::Assets;
 use crate::assets_webgl::Assets;
 use crate::consts::*;
 use crate::entity::{
-    BulletBase, DeathReason, Enemy, EnemyBase, Entity, Item, Player, Projectile, ShieldedBoss,
-    TempEntity, Weapon,
+    BulletBase, Collider, DeathReason, Enemy, EnemyKind, EnemyRegistry, Entity, Item,
+    PatternEmitter, PatternLibrary, PatternRunner, Player, Projectile, SpatialGrid, TempEntity,
+    Weapon,
 };
+use crate::event::GameEvent;
+use crate::input::InputFrame;
+use crate::spawn_table::SpawnTable;
+use crate::stage::StageRunner;
 use xor128::Xor128;
 
 #[cfg(feature = "webgl")]
@@ -87,6 +101,7 @@ pub type ShooterError = JsValue;
 #[cfg(not(feature = "webgl"))]
 pub type ShooterError = std::io::Error;
 
+#[derive(Serialize, Deserialize)]
 pub struct ShooterState {
     pub time: usize,
     pub disptime: usize,
@@ -97,13 +112,64 @@ pub struct ShooterState {
     pub items: EntitySet<Item>,
     pub bullets: EntitySet<Projectile>,
     pub tent: EntitySet<TempEntity>,
+    /// The seed that `rng` is initialized with on (re)start.
+    pub seed: u32,
+    /// The random number generator for everything that affects the gameplay.
     pub rng: Xor128,
+    /// The random number generator for visual effects only. Keeping it apart from `rng`
+    /// lets effects change without affecting the gameplay, so replays stay valid.
+    pub cosmetic_rng: Xor128,
     pub shots_bullet: usize,
     pub shots_missile: usize,
+    /// The branches of the lightning shot in the last frame, kept while paused.
+    pub lightning_branches: Vec<LightningBranch>,
+    /// The events that happened in the last frame. Cleared at the beginning of every frame.
+    pub events: Vec<GameEvent>,
+    /// The enemies to spawn in waves. It is kept on restart.
+    pub spawn_table: SpawnTable,
+    /// The authored stage being played, if any. It starts over on restart.
+    pub stage: Option<StageRunner>,
+    /// The bullet patterns fired by enemies.
+    pub bullet_patterns: PatternLibrary,
+    /// The patterns fired by split bullets
+    pub pattern_emitters: Vec<PatternEmitter>,
+    /// Hits drain the health of the player and enemy bodies deal contact damage, instead
+    /// of any bullet taking a life. It is kept on restart.
+    pub hp_mode: bool,
+    /// The custom enemies the game added. It is kept on restart, but not saved in
+    /// snapshots, so the game sets it again after loading one.
+    #[serde(skip)]
+    pub enemy_registry: EnemyRegistry,
+}
+
+/// A branch of the lightning, as it was shot and hit-tested, so that frontends can
+/// draw it without simulating it again.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LightningBranch {
+    /// The polyline of the branch, starting from the player.
+    pub vertices: Vec<[f64; 2]>,
+    /// The branch stopped at an enemy.
+    pub hit: bool,
 }
 
 impl Default for ShooterState {
     fn default() -> Self {
+        Self::with_seed(DEFAULT_SEED)
+    }
+}
+
+/// The seed of the daily challenge, so that everyone playing on the same day
+/// faces the same enemies.
+///
+/// `days_since_epoch` is the number of days since 1970-01-01 in UTC, so that the day
+/// switches at the same moment all over the world.
+pub fn daily_seed(days_since_epoch: u32) -> u32 {
+    Xor128::new(DAILY_SEED_SALT ^ days_since_epoch).nexti()
+}
+
+impl ShooterState {
+    /// Create a game whose random sequence is determined by `seed`.
+    pub fn with_seed(seed: u32) -> Self {
         let mut player = Player::new(Entity::new([FWIDTH / 2., FHEIGHT * 3. / 4.], [0., 0.]));
         player.reset();
 
@@ -117,29 +183,209 @@ impl Default for ShooterState {
             items: EntitySet::new(),
             bullets: EntitySet::new(),
             tent: EntitySet::new(),
-            rng: Xor128::new(3232132),
+            seed,
+            rng: Xor128::new(seed),
+            cosmetic_rng: Xor128::new(seed ^ COSMETIC_SEED_SALT),
             shots_bullet: 0,
             shots_missile: 0,
+            lightning_branches: vec![],
+            events: vec![],
+            spawn_table: SpawnTable::default(),
+            stage: None,
+            bullet_patterns: PatternLibrary::default(),
+            pattern_emitters: vec![],
+            hp_mode: false,
+            enemy_registry: EnemyRegistry::default(),
         }
     }
-}
 
-impl ShooterState {
     pub fn restart(&mut self) -> Result<(), ShooterError> {
         self.items.clear();
         self.enemies.clear();
         self.bullets.clear();
-        #[cfg(feature = "webgl")]
         self.tent.clear();
         self.time = 0;
+        self.rng = Xor128::new(self.seed);
+        self.cosmetic_rng = Xor128::new(self.seed ^ COSMETIC_SEED_SALT);
         self.player.reset();
         self.shots_bullet = 0;
         self.shots_missile = 0;
+        self.lightning_branches.clear();
+        self.events.clear();
+        self.pattern_emitters.clear();
+        if let Some(stage) = &mut self.stage {
+            stage.reset();
+        }
         self.paused = false;
         self.game_over = false;
         Ok(())
     }
 
+    /// Restart the game with a new seed.
+    pub fn restart_with_seed(&mut self, seed: u32) -> Result<(), ShooterError> {
+        self.seed = seed;
+        self.restart()
+    }
+
+    /// Advance the game by a frame with the given input.
+    ///
+    /// This is the whole gameplay sequence of a frame, so that all frontends run the
+    /// simulation in exactly the same order. Frontends only need to collect the input
+    /// and render the resulting state, and react to the `events` of the frame.
+    pub fn step(&mut self, input: &InputFrame) {
+        self.events.clear();
+
+        if input.pause {
+            self.paused = !self.paused;
+        }
+
+        if !self.game_over {
+            if input.next_weapon {
+                self.player.weapon = self.player.weapon.next();
+                console_log!("Weapon switched: {}", self.player.weapon);
+            } else if input.prev_weapon {
+                self.player.weapon = self.player.weapon.prev();
+                console_log!("Weapon switched: {}", self.player.weapon);
+            }
+        }
+
+        if !self.paused {
+            self.time += 1;
+
+            // Release the memory of the bullets of the last wave
+            if self.time % self.wave_period() == 0 {
+                self.events.push(GameEvent::WaveStarted {
+                    wave: self.time / self.wave_period(),
+                });
+                self.bullets.shrink();
+                self.enemies.shrink();
+                self.items.shrink();
+                self.tent.shrink();
+            }
+        }
+
+        if !self.paused {
+            if let Some(stage) = &mut self.stage {
+                stage.advance(&mut self.enemies, &self.enemy_registry, &mut self.events);
+            }
+        }
+
+        self.gen_enemies();
+
+        if !self.paused {
+            self.lightning_branches.clear();
+        }
+
+        if !self.game_over && !self.paused {
+            let prev_pos = self.player.base.pos;
+            let axis = |neg: bool, pos: bool| pos as i32 as f64 - neg as i32 as f64;
+            self.player.focus = input.focus;
+            self.player
+                .move_toward([axis(input.left, input.right), axis(input.up, input.down)]);
+            // Kept for enemies leading their shots
+            self.player.base.velo = vec2_sub(self.player.base.pos, prev_pos);
+
+            if input.bomb {
+                self.use_bomb();
+            }
+
+            if input.shoot {
+                let seed = self.rng.nexti();
+
+                self.try_shoot(true, seed);
+            }
+
+            if self.player.cooldown < 1 {
+                self.player.cooldown = 0;
+            } else {
+                self.player.cooldown -= 1;
+            }
+
+            if 0 < self.player.invtime {
+                self.player.invtime -= 1;
+            }
+
+            self.player.decay_chain();
+        }
+
+        self.animate_items();
+
+        self.animate_enemies();
+
+        self.animate_bullets();
+
+        self.spawn_effects();
+
+        self.animate_tents();
+    }
+
+    /// Add the visual effects for the events of this frame.
+    fn spawn_effects(&mut self) {
+        for i in 0..self.events.len() {
+            match self.events[i] {
+                GameEvent::EnemyDamaged { pos, weapon, .. } => {
+                    let ty = if weapon == Weapon::Bullet {
+                        TempEntityType::Explode
+                    } else {
+                        TempEntityType::Explode2
+                    };
+                    self.add_tent(ty, &pos, &[0.; 2]);
+                }
+                GameEvent::PlayerHit { pos } => {
+                    self.add_tent(TempEntityType::Explode, &pos, &[0.; 2]);
+                }
+                GameEvent::EnemyKilled { kind, pos } => {
+                    let blood_count = match kind {
+                        EnemyKind::Boss | EnemyKind::SpiralEnemy => 15,
+                        EnemyKind::PhaseBoss => 50,
+                        _ => 5,
+                    };
+                    for _i in 0..blood_count {
+                        let velo = [
+                            self.cosmetic_rng.gen_rangef(-BLOOD_SPEED, BLOOD_SPEED),
+                            self.cosmetic_rng.gen_rangef(-BLOOD_SPEED, BLOOD_SPEED),
+                        ];
+                        self.add_tent(TempEntityType::Blood, &pos, &velo);
+                    }
+                }
+                GameEvent::BombUsed { pos } => {
+                    self.add_scaled_tent(TempEntityType::Explode2, &pos, &[0.; 2], 8.);
+                    for i in 0..BOMB_EFFECT_COUNT {
+                        let angle = i as f64 * 2. * std::f64::consts::PI / BOMB_EFFECT_COUNT as f64;
+                        let speed = self.cosmetic_rng.gen_rangef(4., 6.);
+                        let velo = [speed * angle.cos(), speed * angle.sin()];
+                        self.add_scaled_tent(TempEntityType::Explode2, &pos, &velo, 2.);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Add a temporary entity (visual effect) at a jittered position with random rotation.
+    /// It only draws from `cosmetic_rng`, so it does not affect the gameplay.
+    pub fn add_tent(&mut self, ty: TempEntityType, pos: &[f64; 2], velo: &[f64; 2]) {
+        self.add_scaled_tent(ty, pos, velo, 1.);
+    }
+
+    fn add_scaled_tent(&mut self, ty: TempEntityType, pos: &[f64; 2], velo: &[f64; 2], scale: f64) {
+        let rng = &mut self.cosmetic_rng;
+        let ent = Entity::new(
+            [
+                pos[0] + 4. * (rng.gen() - 0.5),
+                pos[1] + 4. * (rng.gen() - 0.5),
+            ],
+            *velo,
+        )
+        .rotation(rng.gen() as f32 * 2. * std::f32::consts::PI);
+        let repeats = match ty {
+            TempEntityType::Blood => rng.gen_range(2, 5),
+            _ => 1,
+        };
+        self.tent
+            .insert(TempEntity::new(ty, ent).repeats(repeats).scale(scale));
+    }
+
     #[cfg(not(feature = "piston"))]
     fn add_blend(ent: Entity) -> Entity {
         ent
@@ -150,8 +396,8 @@ impl ShooterState {
         ent.blend(Blend::Add)
     }
 
-    /// Function to call the same lightning sequence twice, first pass for detecting hit enemy
-    /// and second pass for rendering.
+    /// Walk a lightning branch generated from `seed`, calling `f` with each segment until it
+    /// returns false. Returns the number of segments walked.
     pub fn lightning_branch(
         &mut self,
         seed: u32,
@@ -197,7 +443,6 @@ impl ShooterState {
         let mut branch_rng = Xor128::new(seed);
 
         for _ in 0..nmax {
-            // Use the same seed twice to reproduce random sequence
             let seed = branch_rng.nexti();
 
             f(self, seed);
@@ -206,12 +451,37 @@ impl ShooterState {
         nmax
     }
 
-    pub fn try_shoot(
-        &mut self,
-        key_shoot: bool,
-        seed: u32,
-        add_tent: &mut impl FnMut(TempEntityType, &[f64; 2], &mut ShooterState),
-    ) -> usize {
+    /// Use a bomb from the stock, if any. It clears the enemy projectiles, damages every
+    /// enemy on screen and makes the player invincible for a while.
+    pub fn use_bomb(&mut self) {
+        if self.player.bombs == 0 {
+            return;
+        }
+        self.player.bombs -= 1;
+        self.bullets.retain(|bullet| !bullet.is_enemy());
+        let mut spawned = vec![];
+        for enemy in self.enemies.iter_mut() {
+            let pos = enemy.pos;
+            if SCREEN_RECT[0] <= pos[0]
+                && pos[0] < SCREEN_RECT[2]
+                && SCREEN_RECT[1] <= pos[1]
+                && pos[1] < SCREEN_RECT[3]
+            {
+                spawned.extend(enemy.damage(BOMB_DAMAGE, &SCREEN_RECT));
+            }
+        }
+        for enemy in spawned {
+            self.enemies.insert(enemy);
+        }
+        self.player.invtime = self.player.invtime.max(BOMB_INVINCIBLE_TIME);
+        self.events.push(GameEvent::BombUsed {
+            pos: self.player.base.pos,
+        });
+    }
+
+    /// Shoot the current weapon. The branches of a lightning are recorded in
+    /// `lightning_branches`.
+    pub fn try_shoot(&mut self, key_shoot: bool, seed: u32) {
         let weapon = self.player.weapon;
         let shoot_period = if let Weapon::Bullet = weapon { 5 } else { 50 };
 
@@ -220,14 +490,21 @@ impl ShooterState {
             if key_shoot && player.cooldown == 0 {
                 let level = player.power_level() as i32;
                 player.cooldown += shoot_period;
+                self.events.push(GameEvent::WeaponFired { weapon });
+                let spread = if player.focus && weapon == Weapon::Bullet {
+                    FOCUS_BULLET_SPREAD
+                } else {
+                    1.
+                };
                 for i in -1 - level..2 + level {
                     let speed = if let Weapon::Bullet = weapon {
                         BULLET_SPEED
                     } else {
                         MISSILE_SPEED
                     };
-                    let mut ent = Entity::new(player.base.pos, [i as f64, -speed])
-                        .rotation((i as f32).atan2(speed as f32));
+                    let vx = i as f64 * spread;
+                    let mut ent =
+                        Entity::new(player.base.pos, [vx, -speed]).rotation(vx.atan2(speed) as f32);
                     if let Weapon::Bullet = weapon {
                         self.shots_bullet += 1;
                         ent = Self::add_blend(ent);
@@ -253,108 +530,148 @@ impl ShooterState {
                 player.base.pos[1],
             ];
 
+            self.events.push(GameEvent::WeaponFired { weapon });
+            let grid = SpatialGrid::new(&self.enemies);
             let mut enemies = std::mem::take(&mut self.enemies);
-            for enemy in enemies.iter_mut() {
-                if enemy.test_hit(beam_rect) {
-                    add_tent(TempEntityType::Explode2, &enemy.pos, self);
-                    enemy.damage(1 + level, &beam_rect);
+            let mut spawned = vec![];
+            for id in grid.query(&beam_rect) {
+                let Some(enemy) = enemies.get_mut(id) else {
+                    continue;
+                };
+                if enemy.test_hit(&Collider::Aabb(beam_rect)) {
+                    self.events.push(GameEvent::EnemyDamaged {
+                        kind: enemy.kind(),
+                        pos: enemy.pos,
+                        weapon,
+                        damage: 1 + level,
+                    });
+                    spawned.extend(enemy.damage(1 + level, &beam_rect));
                 }
             }
+            for enemy in spawned {
+                enemies.insert(enemy);
+            }
             self.enemies = enemies;
         } else if Weapon::Lightning == weapon && key_shoot {
-            return self.lightning(seed, None, &mut |state, seed| {
-                state.lightning_branch(
+            self.events.push(GameEvent::WeaponFired { weapon });
+            let mut grid = SpatialGrid::new(&self.enemies);
+            self.lightning(seed, None, &mut |state, seed| {
+                let mut vertices = vec![state.player.base.pos];
+                let length = state.lightning_branch(
                     seed,
                     LIGHTNING_VERTICES,
                     &mut |state: &mut Self, segment: &[f64; 4]| {
                         let b = [segment[2], segment[3]];
-                        let mut res = true;
-                        for enemy in state.enemies.iter_mut() {
-                            let ebb = enemy.get_bb();
-                            if ebb[0] < b[0] + 4.
-                                && b[0] - 4. <= ebb[2]
-                                && ebb[1] < b[1] + 4.
-                                && b[1] - 4. <= ebb[3]
-                            {
-                                enemy.damage(2 + state.rng.gen_range(0, 3) as i32, &ebb);
-                                res = false;
-                                // Needs to break this loop before add_tent for borrow checker limitation.
+                        let mut hit = None;
+                        let mut spawned = None;
+                        for id in grid.query(&[b[0] - 4., b[1] - 4., b[0] + 4., b[1] + 4.]) {
+                            let Some(enemy) = state.enemies.get_mut(id) else {
+                                continue;
+                            };
+                            let tip = Collider::square(b, 4.);
+                            if enemy.test_hit(&tip) {
+                                let damage = 2 + state.rng.gen_range(0, 3) as i32;
+                                spawned = enemy.damage(damage, &tip.bounding_box());
+                                hit = Some((enemy.kind(), damage));
                                 break;
                             }
                         }
-                        if !res {
-                            add_tent(TempEntityType::Explode2, &b, state);
+                        if let Some(enemy) = spawned {
+                            let id = state.enemies.insert(enemy);
+                            if let Some(enemy) = state.enemies.get(id) {
+                                grid.insert(id, &enemy);
+                            }
+                        }
+                        // The branch reaches the enemy it hits
+                        vertices.push(b);
+                        if let Some((kind, damage)) = hit {
+                            state.events.push(GameEvent::EnemyDamaged {
+                                kind,
+                                pos: b,
+                                weapon,
+                                damage,
+                            });
                         }
-                        res
+                        hit.is_none()
                     },
                 );
+                state.lightning_branches.push(LightningBranch {
+                    vertices,
+                    hit: length != LIGHTNING_VERTICES,
+                });
             });
         }
-        0
+    }
+
+    /// The remaining health of the boss in the range of 0 to 1, if a boss fight is going on.
+    pub fn boss_health(&self) -> Option<f64> {
+        self.enemies.iter().find_map(|enemy| enemy.boss_health())
+    }
+
+    /// The length of an enemy wave in frames.
+    pub fn wave_period(&self) -> usize {
+        self.spawn_table.wave_period
     }
 
     /// Generate enemies in this frame.
     ///
     /// Returns: wave_period
     pub fn gen_enemies(&mut self) -> usize {
-        let wave_period = 1024;
-        if !self.paused {
-            let dice = 256;
+        let wave_period = self.wave_period();
+        let random_spawns = self
+            .stage
+            .as_ref()
+            .map_or(true, |stage| stage.random_spawns());
+        // Regular spawns pause during a boss fight
+        let mut boss_fight = self.boss_health().is_some();
+        if !self.paused && random_spawns && !boss_fight {
+            let table = &self.spawn_table;
+            let waves = self.time / wave_period;
+            if self.time % wave_period == 0
+                && 0 < table.boss_interval
+                && 0 < waves
+                && waves % table.boss_interval == 0
+            {
+                self.enemies.insert(Enemy::spawn(
+                    EnemyKind::PhaseBoss,
+                    [FWIDTH / 2., 0.],
+                    [0.; 2],
+                ));
+                boss_fight = true;
+            }
+        }
+        if !self.paused && random_spawns && !boss_fight {
+            let table = &self.spawn_table;
             let wave = self.time % wave_period;
-            if wave < wave_period * 3 / 4 {
-                let [mut enemy_count, mut boss_count, mut shielded_boss_count, mut spiral_count, mut centipede_count] =
-                    [0; 5];
-                for e in self.enemies.iter() {
-                    match &*e {
-                        Enemy::Enemy1(_) => {
-                            enemy_count += 1;
-                        }
-                        Enemy::Boss(_) => {
-                            boss_count += 1;
-                        }
-                        Enemy::ShieldedBoss(_) => {
-                            shielded_boss_count += 1;
-                        }
-                        Enemy::SpiralEnemy(_) => {
-                            spiral_count += 1;
-                        }
-                        Enemy::Centipede(_) => {
-                            centipede_count += 1;
-                        }
-                    }
-                }
-                let gen_amount = self.player.difficulty_level() * 4 + 8;
-                let mut i = self.rng.gen_range(0, dice);
+            if wave < table.spawn_frames {
+                let counts: Vec<_> = table
+                    .entries
+                    .iter()
+                    .map(|entry| self.enemies.iter().filter(|e| entry.matches(e)).count())
+                    .collect();
+                let gen_amount = table.gen_amount(&self.player);
+                let mut i = self.rng.gen_range(0, table.dice);
                 while i < gen_amount {
-                    let weights = [
-                        if enemy_count < 128 {
-                            if self.player.score < 1024 {
-                                64
+                    let weights: Vec<_> = table
+                        .entries
+                        .iter()
+                        .zip(&counts)
+                        .map(|(entry, count)| {
+                            if *count < entry.max_count {
+                                entry.weight(&self.player)
                             } else {
-                                16
+                                0
                             }
-                        } else {
-                            0
-                        },
-                        if boss_count < 32 { 4 } else { 0 },
-                        if shielded_boss_count < 32 {
-                            std::cmp::min(4, self.player.difficulty_level())
-                        } else {
-                            0
-                        },
-                        if spiral_count < 4 { 4 } else { 0 },
-                        if centipede_count < 4 { 4 } else { 0 },
-                    ];
+                        })
+                        .collect();
                     let allweights = weights.iter().sum();
-                    let accum = {
-                        let mut accum = [0; 5];
-                        let mut accumulator = 0;
-                        for (i, e) in weights.iter().enumerate() {
-                            accumulator += e;
-                            accum[i] = accumulator;
-                        }
-                        accum
-                    };
+                    let accum: Vec<_> = weights
+                        .iter()
+                        .scan(0, |accumulator, e| {
+                            *accumulator += e;
+                            Some(*accumulator)
+                        })
+                        .collect();
 
                     if 0 < allweights {
                         let rng = &mut self.rng;
@@ -385,16 +702,16 @@ impl ShooterState {
                         };
                         let velo = vec2_scale(vec2_normalized(velo), 0.5);
                         if let Some(x) = accum.iter().position(|x| dice < *x) {
-                            self.enemies.insert(match x {
-                                0 => Enemy::Enemy1(EnemyBase::new(pos, velo).health(3)),
-                                1 => Enemy::Boss(EnemyBase::new(pos, velo).health(64)),
-                                2 => Enemy::ShieldedBoss(ShieldedBoss::new(pos, velo)),
-                                3 => Enemy::new_spiral(pos, velo),
-                                _ => Enemy::new_centipede(pos, velo),
-                            });
+                            let entry = &table.entries[x];
+                            self.enemies.insert(self.enemy_registry.spawn_kind(
+                                entry.enemy,
+                                entry.custom.as_deref(),
+                                pos,
+                                velo,
+                            ));
                         }
                     }
-                    i += self.rng.gen_range(0, dice);
+                    i += self.rng.gen_range(0, table.dice);
                 }
             }
         }
@@ -411,7 +728,7 @@ impl ShooterState {
     #[cfg(all(not(feature = "webgl"), feature = "piston"))]
     pub fn draw_items(&self, context: &Context, graphics: &mut G2d, assets: &Assets) {
         for item in &self.items {
-            item.draw(&context, graphics, &assets);
+            item.draw(context, graphics, assets);
         }
     }
 
@@ -422,6 +739,10 @@ impl ShooterState {
         let mut items = std::mem::take(&mut self.items);
         items.retain_id(|id, e| {
             if e.animate(&mut self.player).is_some() {
+                self.events.push(GameEvent::ItemCollected {
+                    kind: e.kind(),
+                    pos: e.pos,
+                });
                 println!("Deleted Item {} / {}", id, self.items.len());
                 return false;
             }
@@ -440,14 +761,17 @@ impl ShooterState {
     #[cfg(all(not(feature = "webgl"), feature = "piston"))]
     pub fn draw_enemies(&self, context: &Context, graphics: &mut G2d, assets: &Assets) {
         for enemy in &self.enemies {
-            enemy.draw(&context, graphics, &assets);
+            enemy.draw(context, graphics, assets);
         }
     }
 
-    pub fn animate_enemies(&mut self, on_killed: &mut impl FnMut(&Enemy, &mut ShooterState)) {
+    pub fn animate_enemies(&mut self) {
         if self.paused {
             return;
         }
+        for enemy in self.enemies.iter_mut() {
+            self.enemy_registry.restore(enemy);
+        }
         let mut enemies = std::mem::take(&mut self.enemies);
         enemies.retain_id(|id, enemy| {
             if self.paused {
@@ -456,34 +780,84 @@ impl ShooterState {
             let ret = {
                 if let Some(death_reason) = enemy.animate(self) {
                     if matches!(death_reason, DeathReason::Killed) {
-                        on_killed(enemy, self);
+                        self.events.push(GameEvent::EnemyKilled {
+                            kind: enemy.kind(),
+                            pos: enemy.pos,
+                        });
                         self.player.kills += 1;
-                        self.player.score += if enemy.is_boss() { 10 } else { 1 };
-                        if self.rng.gen_range(0, 100) < 20 {
+                        self.player.add_kill_score(enemy.score());
+                        if enemy.is_boss() && enemy.age < BOSS_BONUS_TIME {
+                            let bonus =
+                                BOSS_BONUS * (BOSS_BONUS_TIME - enemy.age) / BOSS_BONUS_TIME;
+                            self.player.score += bonus;
+                            self.events.push(GameEvent::BossBonus { bonus });
+                        }
+                        if self.rng.gen_range(0, 100) < enemy.drop_chance() {
                             let ent = Entity::new(enemy.pos, [0., 1.]);
                             self.items.insert(enemy.drop_item(ent));
                         }
+                        let bomb_chance = enemy.bomb_drop_chance();
+                        if 0 < bomb_chance && self.rng.gen_range(0, 100) < bomb_chance {
+                            // Falls slower to come apart from the other item
+                            let ent = Entity::new(enemy.pos, [0., 0.5]);
+                            self.items.insert(Item::Bomb(ent));
+                        }
                     }
                     println!(
                         "Deleted Enemy {} id={} {}",
-                        match enemy {
-                            Enemy::Enemy1(_) => "enemy",
-                            Enemy::Boss(_) => "boss",
-                            Enemy::ShieldedBoss(_) => "ShieldedBoss",
-                            Enemy::SpiralEnemy(_) => "SpiralEnemy",
-                            Enemy::Centipede(_) => "Centipede",
-                        },
+                        enemy.name(),
                         id,
                         self.enemies.len()
                     );
                     false
                 } else {
+                    if self.hp_mode
+                        && self.player.invtime == 0
+                        && !self.game_over
+                        && enemy.touches(&self.player.collider())
+                    {
+                        self.hit_player(CONTACT_DAMAGE, self.player.base.pos);
+                    }
                     true
                 }
             };
             ret
         });
-        self.enemies = enemies;
+        // Enemies spawned by behaviors during the frame went into the set left empty
+        let spawned = std::mem::replace(&mut self.enemies, enemies);
+        for enemy in spawned {
+            self.enemies.insert(enemy);
+        }
+    }
+
+    /// The player was hit at `pos`. A life is lost, or in HP mode, `damage` is taken from
+    /// the health and a life is lost when it runs out.
+    fn hit_player(&mut self, damage: i32, pos: [f64; 2]) {
+        self.events.push(GameEvent::PlayerHit { pos });
+        if self.player.invtime != 0 || self.game_over || self.player.lives == 0 {
+            return;
+        }
+        if self.hp_mode {
+            self.player.base.health -= damage;
+            if 0 < self.player.base.health {
+                self.player.invtime = PLAYER_HIT_INVINCIBLE_TIME;
+                return;
+            }
+        }
+        self.player.lives -= 1;
+        self.player.chain = 0;
+        self.player.chain_time = 0;
+        self.events.push(GameEvent::LifeLost {
+            lives: self.player.lives,
+        });
+        if self.player.lives == 0 {
+            self.game_over = true;
+            self.events.push(GameEvent::GameOver);
+        } else {
+            self.player.invtime = PLAYER_INVINCIBLE_TIME;
+            self.player.bombs = PLAYER_BOMBS;
+            self.player.base.health = PLAYER_HEALTH;
+        }
     }
 
     #[cfg(feature = "webgl")]
@@ -496,60 +870,91 @@ impl ShooterState {
     #[cfg(all(not(feature = "webgl"), feature = "piston"))]
     pub fn draw_bullets(&self, context: &Context, graphics: &mut G2d, assets: &Assets) {
         for b in self.bullets.iter() {
-            b.draw(&context, graphics, &assets);
+            b.draw(context, graphics, assets);
         }
     }
 
-    /// Returns true if player was killed, signaling game over event.
-    pub fn animate_bullets(
-        &mut self,
-        add_tent: &mut impl FnMut(TempEntityType, &[f64; 2], &mut ShooterState),
-    ) -> bool {
+    /// Whether the bullet came within `GRAZE_DISTANCE` of the player in this frame. It is
+    /// tested against the positions in the last frame, so that a bullet grazes only once.
+    fn grazes(&self, bullet: &Projectile) -> bool {
+        let player = &self.player.base;
+        let dist = vec2_len(vec2_sub(bullet.pos, player.pos));
+        let prev_dist = vec2_len(vec2_sub(
+            vec2_sub(bullet.pos, bullet.velo),
+            vec2_sub(player.pos, player.velo),
+        ));
+        dist < GRAZE_DISTANCE && GRAZE_DISTANCE <= prev_dist
+    }
+
+    pub fn animate_bullets(&mut self) {
         if self.paused {
-            return false;
+            return;
         }
-        let mut ret = false;
         let mut bullets_to_delete = Vec::new();
         let mut bullets = std::mem::take(&mut self.bullets);
+        let mut grid = SpatialGrid::new(&self.enemies);
+        let mut splits = vec![];
+        let mut fired = vec![];
+        let rank = self.player.rank();
         bullets.retain_id(|i, b| {
             if self.paused {
                 return true;
             }
-            let Some(death_reason) = b.animate_bullet(&mut self.enemies, &mut self.player) else {
+            b.run_bulletml(
+                &self.bullet_patterns,
+                self.player.base.pos,
+                rank,
+                &mut self.rng,
+                &mut fired,
+            );
+            let Some(death_reason) = b.animate_bullet(
+                &mut self.enemies,
+                &mut grid,
+                &mut self.player,
+                &mut self.events,
+            ) else {
+                if b.is_enemy() && !self.game_over && self.grazes(b) {
+                    self.player.graze += 1;
+                    self.player.score += GRAZE_SCORE;
+                    self.events.push(GameEvent::Grazed { pos: b.pos });
+                }
                 return true;
             };
             bullets_to_delete.push(i);
 
-            match death_reason {
-                DeathReason::Killed | DeathReason::HitPlayer => {
-                    let tt = match b {
-                        Projectile::Missile { .. } => TempEntityType::Explode2,
-                        _ => TempEntityType::Explode,
-                    };
-                    add_tent(tt, &b.pos, self)
-                }
-                _ => {}
+            if let Some(pattern) = b.split_pattern() {
+                splits.push(PatternEmitter {
+                    pos: b.pos,
+                    runner: PatternRunner::new(pattern),
+                });
             }
 
             if let DeathReason::HitPlayer = death_reason {
-                if self.player.invtime == 0 && !self.game_over && 0 < self.player.lives {
-                    self.player.lives -= 1;
-                    if self.player.lives == 0 {
-                        self.game_over = true;
-                        ret = true;
-                    } else {
-                        self.player.invtime = PLAYER_INVINCIBLE_TIME;
-                    }
-                }
+                self.hit_player(b.health, b.pos);
             }
 
             println!("Deleted {} id={} ({})", b.get_type(), i, self.bullets.len());
 
             false
         });
+        for bullet in fired {
+            bullets.insert(bullet);
+        }
         self.bullets = bullets;
 
-        ret
+        self.pattern_emitters.extend(splits);
+        let mut emitters = std::mem::take(&mut self.pattern_emitters);
+        emitters.retain_mut(|emitter| {
+            !emitter.runner.advance(
+                &self.bullet_patterns,
+                emitter.pos,
+                &self.player.base,
+                rank,
+                &mut self.rng,
+                &mut self.bullets,
+            )
+        });
+        self.pattern_emitters = emitters;
     }
 
     #[cfg(feature = "webgl")]
@@ -560,9 +965,9 @@ impl ShooterState {
     }
 
     #[cfg(all(not(feature = "webgl"), feature = "piston"))]
-    pub fn draw_tents(&self, context: &Context, graphics: &mut G2d) {
+    pub fn draw_tents(&self, context: &Context, graphics: &mut G2d, assets: &Assets) {
         for e in &self.tent {
-            e.draw_temp(&context, graphics);
+            e.draw_temp(context, graphics, assets);
         }
     }
 
@@ -581,6 +986,699 @@ impl ShooterState {
     }
 }
 
+#[test]
+fn headless_simulation() {
+    let mut state = ShooterState::default();
+    let input = InputFrame {
+        shoot: true,
+        ..InputFrame::default()
+    };
+    for _ in 0..2000 {
+        state.step(&input);
+    }
+    assert_eq!(state.time, 2000);
+    assert!(0 < state.player.kills);
+}
+
+#[test]
+fn replay_playback() {
+    use crate::replay::{Replay, ReplayError, ReplayPlayer};
+
+    let mut input_rng = Xor128::new(42);
+    let mut state = ShooterState::with_seed(12345);
+    let mut replay = Replay::new(&state);
+    for _ in 0..3000 {
+        let input = InputFrame::from(input_rng.nexti() as u16 & 0x17f);
+        replay.record(&input);
+        state.step(&input);
+    }
+
+    let replay = Replay::from_bytes(&replay.to_bytes()).unwrap();
+    assert!(replay.is_compatible());
+    let mut played = ShooterState::with_seed(replay.seed);
+    assert!(replay.matches_settings(&played));
+    played.hp_mode = true;
+    assert!(!replay.matches_settings(&played));
+    played.hp_mode = false;
+    for input in ReplayPlayer::new(replay) {
+        played.step(&input);
+    }
+
+    assert_eq!(played.time, state.time);
+    assert_eq!(played.player.score, state.player.score);
+    assert_eq!(played.player.base.pos, state.player.base.pos);
+    assert_eq!(played.enemies.len(), state.enemies.len());
+    assert_eq!(played.rng.nexti(), state.rng.nexti());
+
+    // Replays in other formats are refused rather than misread
+    assert!(matches!(
+        Replay::from_bytes(b"SHRP\x05\0\0\0\0\0\0\x000.1.0"),
+        Err(ReplayError::Format(1))
+    ));
+    let mut newer = b"SHRF".to_vec();
+    newer.extend_from_slice(&99u32.to_le_bytes());
+    assert!(matches!(
+        Replay::from_bytes(&newer),
+        Err(ReplayError::Format(99))
+    ));
+}
+
+#[test]
+fn cosmetic_rng_independence() {
+    let input = InputFrame {
+        shoot: true,
+        ..InputFrame::default()
+    };
+    let mut state = ShooterState::default();
+    let mut other = ShooterState::default();
+    for i in 0..2000 {
+        state.step(&input);
+        other.step(&input);
+        // Pretend that the other frontend spawns more effects
+        for _ in 0..i % 7 {
+            other.cosmetic_rng.nexti();
+        }
+    }
+    assert_eq!(other.player.score, state.player.score);
+    assert_eq!(other.enemies.len(), state.enemies.len());
+    assert_eq!(other.rng.nexti(), state.rng.nexti());
+}
+
+#[test]
+fn lightning_branches() {
+    let mut state = ShooterState::default();
+    state.player.weapon = Weapon::Lightning;
+    let input = InputFrame {
+        shoot: true,
+        ..InputFrame::default()
+    };
+    let mut hits = 0;
+    for _ in 0..2000 {
+        state.step(&input);
+        for branch in &state.lightning_branches {
+            assert_eq!(branch.vertices[0], state.player.base.pos);
+            if branch.hit {
+                hits += 1;
+                let tip = *branch.vertices.last().unwrap();
+                assert!(state.events.iter().any(|event| matches!(
+                    event,
+                    GameEvent::EnemyDamaged { pos, .. } if *pos == tip
+                )));
+            } else {
+                assert_eq!(branch.vertices.len(), LIGHTNING_VERTICES as usize + 1);
+            }
+        }
+    }
+    assert!(0 < hits);
+}
+
+#[test]
+fn lightning_splits() {
+    // The enemies split by a shot can be hit by the rest of it, as when testing all of them
+    // instead of the grid
+    let hits = |state: &mut ShooterState, seed: u32| {
+        let mut hits = vec![];
+        state.lightning(seed, None, &mut |state, seed| {
+            state.lightning_branch(seed, LIGHTNING_VERTICES, &mut |state, segment| {
+                let b = [segment[2], segment[3]];
+                let tip = Collider::square(b, 4.);
+                let Some(enemy) = state.enemies.iter_mut().find(|e| e.test_hit(&tip)) else {
+                    return true;
+                };
+                let damage = 2 + state.rng.gen_range(0, 3) as i32;
+                if let Some(spawned) = enemy.damage(damage, &tip.bounding_box()) {
+                    state.enemies.insert(spawned);
+                }
+                hits.push(b);
+                false
+            });
+        });
+        hits
+    };
+    for seed in 0..100 {
+        let mut state = ShooterState::default();
+        state.player.weapon = Weapon::Lightning;
+        state.player.power = 255;
+        state.player.base.pos = [WIDTH as f64 / 2., HEIGHT as f64 - 32.];
+        let splitter = Enemy::spawn(
+            EnemyKind::Splitter,
+            [WIDTH as f64 / 2., HEIGHT as f64 - 96.],
+            [0., 1.],
+        );
+        let id = state.enemies.insert(splitter);
+        // Split at the first hit
+        state.enemies.get_mut(id).unwrap().health = 1;
+        let mut brute_force = ShooterState::load_snapshot(&state.save_snapshot()).unwrap();
+        let expected = hits(&mut brute_force, seed);
+
+        state.try_shoot(true, seed);
+        let actual: Vec<_> = state
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                GameEvent::EnemyDamaged { pos, .. } => Some(*pos),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn seeded_restart() {
+    let input = InputFrame {
+        shoot: true,
+        ..InputFrame::default()
+    };
+    let mut state = ShooterState::with_seed(daily_seed(20000));
+    let mut other = ShooterState::default();
+    for _ in 0..1000 {
+        state.step(&input);
+        other.step(&input);
+    }
+    let score = state.player.score;
+
+    other.restart_with_seed(state.seed).unwrap();
+    for _ in 0..1000 {
+        other.step(&input);
+    }
+    assert_eq!(other.player.score, score);
+    assert_ne!(daily_seed(20000), daily_seed(20001));
+}
+
+#[test]
+fn zero_state_seeds() {
+    // Seeds which would cancel out the initial state of the generators
+    for seed in [2463534242, 2463534242 ^ COSMETIC_SEED_SALT] {
+        let mut state = ShooterState::with_seed(seed);
+        for _ in 0..100 {
+            state.step(&InputFrame::default());
+        }
+        assert_ne!(state.rng.nexti(), state.rng.nexti());
+        assert_ne!(state.cosmetic_rng.nexti(), state.cosmetic_rng.nexti());
+    }
+}
+
+#[test]
+fn game_events() {
+    let input = InputFrame {
+        shoot: true,
+        ..InputFrame::default()
+    };
+    let mut state = ShooterState::default();
+    let mut kills = 0;
+    let mut waves = 0;
+    for _ in 0..2000 {
+        state.step(&input);
+        for event in &state.events {
+            match event {
+                GameEvent::EnemyKilled { .. } => kills += 1,
+                GameEvent::WaveStarted { wave } => {
+                    waves += 1;
+                    assert_eq!(*wave, waves);
+                }
+                _ => (),
+            }
+        }
+    }
+    assert_eq!(kills, state.player.kills);
+    assert_eq!(waves, 1);
+}
+
+#[test]
+fn phase_boss_fight() {
+    use crate::entity::BossPhase;
+
+    let mut state = ShooterState::default();
+    state.player.lives = 1000;
+    state.enemies.insert(Enemy::spawn(
+        EnemyKind::PhaseBoss,
+        [FWIDTH / 2., 0.],
+        [0.; 2],
+    ));
+    let input = InputFrame {
+        shoot: true,
+        ..InputFrame::default()
+    };
+    let mut phases = vec![];
+    let mut killed = false;
+    for _ in 0..20000 {
+        state.step(&input);
+        if state.boss_health().is_some() {
+            // No regular enemies during the fight
+            assert_eq!(state.enemies.len(), 1);
+        }
+        for event in &state.events {
+            match event {
+                GameEvent::BossPhaseChanged { phase } => phases.push(*phase),
+                GameEvent::EnemyKilled {
+                    kind: EnemyKind::PhaseBoss,
+                    ..
+                } => killed = true,
+                _ => (),
+            }
+        }
+        if killed {
+            break;
+        }
+    }
+    assert!(killed);
+    assert_eq!(
+        phases,
+        [BossPhase::Rings, BossPhase::Spirals, BossPhase::Frenzy]
+    );
+}
+
+#[test]
+fn split_bullets() {
+    let mut state = ShooterState::default();
+    state.spawn_table.entries.clear();
+    state.bullet_patterns.enemies = [(EnemyKind::Enemy1, "splitting_shot".to_string())].into();
+    state.enemies.insert(Enemy::spawn(
+        EnemyKind::Enemy1,
+        [FWIDTH / 2., FHEIGHT / 4.],
+        [0.; 2],
+    ));
+    let count_bullets = |state: &ShooterState| {
+        state
+            .bullets
+            .iter()
+            .fold([0, 0], |[plain, patterned], b| match &*b {
+                Projectile::EnemyBullet(_) => [plain + 1, patterned],
+                Projectile::Patterned { .. } => [plain, patterned + 1],
+                _ => [plain, patterned],
+            })
+    };
+    for _ in 0..200 {
+        state.step(&InputFrame::default());
+    }
+    assert_eq!(count_bullets(&state), [0, 1]);
+    for _ in 0..100 {
+        state.step(&InputFrame::default());
+    }
+    assert_eq!(count_bullets(&state), [8, 0]);
+}
+
+#[test]
+fn mines() {
+    let mut state = ShooterState::default();
+    state.spawn_table.entries.clear();
+    state.enemies.insert(Enemy::spawn(
+        EnemyKind::MineLayer,
+        [FWIDTH / 2., FHEIGHT / 4.],
+        [0.; 2],
+    ));
+    for _ in 0..100 {
+        state.step(&InputFrame::default());
+    }
+    let mine_pos = {
+        let mut mines = state.bullets.iter().filter(|b| b.get_type() == "Mine");
+        let mine = mines.next().unwrap();
+        assert!(mines.next().is_none());
+        mine.pos
+    };
+    // Nothing happens while the player is away
+    assert_eq!(state.bullets.len(), 1);
+
+    state.player.base.pos = [mine_pos[0], mine_pos[1] + 32.];
+    state.step(&InputFrame::default());
+    assert_eq!(
+        state
+            .bullets
+            .iter()
+            .filter(|b| matches!(&**b, Projectile::EnemyBullet(_)))
+            .count(),
+        8
+    );
+    assert!(state.bullets.iter().all(|b| b.get_type() != "Mine"));
+}
+
+#[test]
+fn splitters() {
+    let mut state = ShooterState::default();
+    state.spawn_table.entries.clear();
+    state.bullet_patterns.enemies.clear();
+    state.enemies.insert(Enemy::spawn(
+        EnemyKind::Splitter,
+        [FWIDTH / 2., FHEIGHT / 4.],
+        [0., 0.5],
+    ));
+    // Two generations of splitting, and then they die
+    for count in [2, 4, 0] {
+        state.use_bomb();
+        state.step(&InputFrame::default());
+        assert_eq!(state.enemies.len(), count);
+    }
+    assert_eq!(state.player.kills, 4);
+}
+
+#[test]
+fn enemy_missiles() {
+    let mut state = ShooterState::default();
+    state.spawn_table.entries.clear();
+    state.player.base.pos = [FWIDTH / 2., FHEIGHT * 3. / 4.];
+    state
+        .bullets
+        .insert(Projectile::new_enemy_missile(BulletBase(Entity::new(
+            [FWIDTH / 2., FHEIGHT / 4.],
+            [0.8, 0.],
+        ))));
+    let missile_velo = |state: &ShooterState| state.bullets.iter().next().unwrap().velo;
+    for _ in 0..60 {
+        state.step(&InputFrame::default());
+    }
+    // Turned from the right toward the player below, keeping the speed
+    let velo = missile_velo(&state);
+    assert!(0.5 < velo[1]);
+    assert!((vecmath::vec2_len(velo) - 0.8).abs() < 1e-9);
+    for _ in 0..100 {
+        state.step(&InputFrame::default());
+    }
+    // Goes straight after homing for a while
+    let velo = missile_velo(&state);
+    state.step(&InputFrame::default());
+    assert_eq!(missile_velo(&state), velo);
+}
+
+#[test]
+fn bombs() {
+    use crate::entity::ItemKind;
+
+    let mut state = ShooterState::default();
+    state.spawn_table.entries.clear();
+    state.player.base.pos = [FWIDTH / 2., FHEIGHT * 3. / 4.];
+    let enemy = state.enemies.insert(Enemy::spawn(
+        EnemyKind::Boss,
+        [FWIDTH / 2., FHEIGHT / 4.],
+        [0.; 2],
+    ));
+    let health = state.enemies.get(enemy).unwrap().health;
+    let bullet = state
+        .bullets
+        .insert(Projectile::EnemyBullet(BulletBase(Entity::new(
+            [FWIDTH / 2., FHEIGHT / 2.],
+            [0., 1.],
+        ))));
+    state.step(&InputFrame {
+        bomb: true,
+        ..InputFrame::default()
+    });
+    assert!(state.bullets.get(bullet).is_none());
+    assert_eq!(
+        state.enemies.get(enemy).unwrap().health,
+        health - BOMB_DAMAGE
+    );
+    assert_eq!(state.player.bombs, PLAYER_BOMBS - 1);
+    assert!(0 < state.player.invtime);
+    assert!(state
+        .events
+        .iter()
+        .any(|event| matches!(event, GameEvent::BombUsed { .. })));
+
+    // Refilled after losing a life
+    state.player.invtime = 0;
+    state
+        .bullets
+        .insert(Projectile::EnemyBullet(BulletBase(Entity::new(
+            state.player.base.pos,
+            [0.; 2],
+        ))));
+    state.step(&InputFrame::default());
+    assert_eq!(state.player.lives, PLAYER_LIVES - 1);
+    assert_eq!(state.player.bombs, PLAYER_BOMBS);
+
+    // Bosses drop bomb items besides their power-ups
+    let drops = |kind: EnemyKind| {
+        let mut drops = vec![];
+        for seed in 0..100 {
+            let mut state = ShooterState::with_seed(seed);
+            state.spawn_table.entries.clear();
+            let id = state
+                .enemies
+                .insert(Enemy::spawn(kind, [FWIDTH / 2., FHEIGHT / 4.], [0.; 2]));
+            state.enemies.get_mut(id).unwrap().health = 0;
+            state.step(&InputFrame::default());
+            drops.extend(state.items.iter().map(|item| item.kind()));
+        }
+        drops
+    };
+    let shielded = drops(EnemyKind::ShieldedBoss);
+    assert!(shielded.contains(&ItemKind::PowerUp10));
+    assert!(shielded.contains(&ItemKind::Bomb));
+    let phase = drops(EnemyKind::PhaseBoss);
+    assert!(phase.contains(&ItemKind::PowerUp10));
+    assert_eq!(
+        phase.iter().filter(|kind| **kind == ItemKind::Bomb).count(),
+        100
+    );
+}
+
+#[test]
+fn scoring() {
+    let mut state = ShooterState::default();
+    state.spawn_table.entries.clear();
+    state.bullet_patterns.enemies.clear();
+    state.player.base.pos = [FWIDTH / 2., FHEIGHT * 3. / 4.];
+
+    // A quick boss kill gives a bonus on top of the multiplied points
+    let boss = state.enemies.insert(Enemy::spawn(
+        EnemyKind::Boss,
+        [FWIDTH / 2., FHEIGHT / 4.],
+        [0.; 2],
+    ));
+    state.player.chain = CHAIN_STEP;
+    state.player.chain_time = CHAIN_TIME;
+    state.enemies.get_mut(boss).unwrap().health = 0;
+    state.step(&InputFrame::default());
+    assert_eq!(state.player.multiplier(), 2);
+    let bonus = state
+        .events
+        .iter()
+        .find_map(|event| match event {
+            GameEvent::BossBonus { bonus } => Some(*bonus),
+            _ => None,
+        })
+        .unwrap();
+    assert!(0 < bonus && bonus <= BOSS_BONUS);
+    assert_eq!(state.player.score, 20 + bonus);
+
+    // The chain decays without kills
+    for _ in 0..CHAIN_TIME + 1 {
+        state.step(&InputFrame::default());
+    }
+    let chain = CHAIN_STEP + 1;
+    assert_eq!(state.player.chain, chain / 2);
+
+    // A bullet passing by grazes the player once
+    let score = state.player.score;
+    state
+        .bullets
+        .insert(Projectile::EnemyBullet(BulletBase(Entity::new(
+            [state.player.base.pos[0] + 24., FHEIGHT / 2.],
+            [0., 2.],
+        ))));
+    for _ in 0..100 {
+        state.step(&InputFrame::default());
+    }
+    assert_eq!(state.player.graze, 1);
+    assert_eq!(state.player.score, score + GRAZE_SCORE);
+    assert_eq!(state.player.lives, PLAYER_LIVES);
+}
+
+#[test]
+fn hp_mode() {
+    let mut state = ShooterState::default();
+    state.spawn_table.entries.clear();
+    state.bullet_patterns.enemies.clear();
+    state.hp_mode = true;
+    state.player.base.pos = [FWIDTH / 2., FHEIGHT * 3. / 4.];
+    let pos = state.player.base.pos;
+    let bullet = |state: &mut ShooterState| {
+        state
+            .bullets
+            .insert(Projectile::EnemyBullet(BulletBase(Entity::new(
+                pos, [0.; 2],
+            ))));
+    };
+
+    // A bullet drains the health, but not a life
+    bullet(&mut state);
+    state.step(&InputFrame::default());
+    assert_eq!(state.player.base.health, PLAYER_HEALTH - 1);
+    assert_eq!(state.player.lives, PLAYER_LIVES);
+    assert_eq!(state.player.invtime, PLAYER_HIT_INVINCIBLE_TIME);
+
+    // An enemy touching the player deals damage once in the invincibility time
+    state
+        .enemies
+        .insert(Enemy::spawn(EnemyKind::Enemy1, pos, [0.; 2]));
+    for _ in 0..PLAYER_HIT_INVINCIBLE_TIME + 1 {
+        state.step(&InputFrame::default());
+    }
+    assert_eq!(state.player.base.health, PLAYER_HEALTH - 1 - CONTACT_DAMAGE);
+    state.enemies.clear();
+
+    // A life is lost when the health runs out
+    state.player.base.health = 1;
+    state.player.invtime = 0;
+    bullet(&mut state);
+    state.step(&InputFrame::default());
+    assert_eq!(state.player.lives, PLAYER_LIVES - 1);
+    assert_eq!(state.player.base.health, PLAYER_HEALTH);
+}
+
+#[test]
+fn bulletml_boss() {
+    let mut state = ShooterState::default();
+    state.spawn_table.entries.clear();
+    state
+        .bullet_patterns
+        .load_bulletml("example", include_str!("../bulletml/example.xml"))
+        .unwrap();
+    state.bullet_patterns.enemies = [(EnemyKind::Boss, "bulletml_example".to_string())].into();
+    state.enemies.insert(Enemy::spawn(
+        EnemyKind::Boss,
+        [FWIDTH / 2., FHEIGHT / 4.],
+        [0.; 2],
+    ));
+    let count_bullets = |state: &ShooterState| {
+        state
+            .bullets
+            .iter()
+            .fold([0, 0], |[plain, bulletml], b| match &*b {
+                Projectile::EnemyBullet(_) => [plain + 1, bulletml],
+                Projectile::BulletML { .. } => [plain, bulletml + 1],
+                _ => [plain, bulletml],
+            })
+    };
+    for _ in 0..180 {
+        state.step(&InputFrame::default());
+    }
+    // The ring of bullets that stop and aim
+    assert_eq!(count_bullets(&state)[1], 12);
+    for _ in 0..60 {
+        state.step(&InputFrame::default());
+    }
+    // They have turned into aimed bullets
+    let [plain, bulletml] = count_bullets(&state);
+    assert_eq!(bulletml, 0);
+    assert!(12 <= plain);
+}
+
+#[test]
+fn custom_enemies() {
+    use crate::entity::{EnemyBase, EnemyBehavior};
+    use std::convert::TryInto;
+
+    /// Zigzags by flipping the horizontal velocity every 10 frames.
+    struct Zigzag {
+        base: EnemyBase,
+        frames: u32,
+    }
+
+    impl EnemyBehavior for Zigzag {
+        fn name(&self) -> &str {
+            "zigzag"
+        }
+
+        fn base(&self) -> &EnemyBase {
+            &self.base
+        }
+
+        fn base_mut(&mut self) -> &mut EnemyBase {
+            &mut self.base
+        }
+
+        fn animate(&mut self, _state: &mut ShooterState) -> Option<DeathReason> {
+            self.frames += 1;
+            if self.frames % 10 == 0 {
+                self.base.velo[0] = -self.base.velo[0];
+            }
+            self.base.animate()
+        }
+
+        fn score(&self) -> u32 {
+            7
+        }
+
+        fn save(&self) -> Vec<u8> {
+            self.frames.to_le_bytes().to_vec()
+        }
+    }
+
+    let mut registry = EnemyRegistry::default();
+    registry.register(
+        "zigzag",
+        |pos, velo| {
+            Box::new(Zigzag {
+                base: EnemyBase::new(pos, velo).health(1),
+                frames: 0,
+            })
+        },
+        |base, data| {
+            Some(Box::new(Zigzag {
+                base,
+                frames: u32::from_le_bytes(data.try_into().ok()?),
+            }))
+        },
+    );
+    assert!(registry.spawn("unknown", [0.; 2], [0.; 2]).is_none());
+
+    let mut state = ShooterState::default();
+    state.spawn_table.entries.clear();
+    let settings = state.settings_hash();
+    state.enemy_registry = registry;
+    // Replays recorded with other custom enemies may not play back the same
+    assert_ne!(state.settings_hash(), settings);
+    let enemy = state
+        .enemy_registry
+        .spawn("zigzag", [FWIDTH / 2., FHEIGHT / 4.], [1., 0.5])
+        .unwrap();
+    state.enemies.insert(enemy);
+    for _ in 0..15 {
+        state.step(&InputFrame::default());
+    }
+    let pos = |state: &ShooterState| state.enemies.iter().next().unwrap().pos;
+    // Went right for 9 frames and left for 6
+    assert_eq!(pos(&state)[0], FWIDTH / 2. + 3.);
+
+    // Restored from a snapshot with the frame count, once registered again
+    let mut loaded = ShooterState::load_snapshot(&state.save_snapshot()).unwrap();
+    let mut unregistered = ShooterState::load_snapshot(&state.save_snapshot()).unwrap();
+    loaded.enemy_registry = std::mem::take(&mut state.enemy_registry);
+    for _ in 0..10 {
+        loaded.step(&InputFrame::default());
+    }
+    assert_eq!(pos(&loaded)[0], FWIDTH / 2. + 5.);
+    unregistered.step(&InputFrame::default());
+    assert_eq!(unregistered.enemies.len(), 0);
+
+    loaded.use_bomb();
+    loaded.step(&InputFrame::default());
+    assert_eq!(loaded.enemies.len(), 0);
+    assert!(loaded.events.iter().any(|event| matches!(
+        event,
+        GameEvent::EnemyKilled {
+            kind: EnemyKind::Custom,
+            ..
+        }
+    )));
+    assert!(7 <= loaded.player.score);
+
+    // Named in a stage, which needs the name for a custom enemy
+    let stage = |custom: &str| {
+        stage::Stage::from_json(&format!(
+            r#"{{"name": "", "commands": [{{"Spawn":
+                {{"enemy": "Custom", {}"pos": [100, 100], "velo": [0, 0]}}}}]}}"#,
+            custom
+        ))
+    };
+    assert!(matches!(stage(""), Err(stage::StageError::Invalid(_))));
+    loaded.stage = Some(StageRunner::new(stage(r#""custom": "zigzag", "#).unwrap()));
+    loaded.step(&InputFrame::default());
+    assert!(loaded.enemies.iter().any(|enemy| enemy.name() == "zigzag"));
+}
+
 #[cfg(feature = "webgl")]
 /// Initialize a texture and load an image.
 /// When the image finished loading copy it into the texture.
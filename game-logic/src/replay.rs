@@ -0,0 +1,165 @@
+//! Recording and playback of the player's input.
+//!
+//! Since the game is driven by a seeded random number generator, a game is fully
+//! described by the seed, the settings and the input in each frame. A replay file holds
+//! the seed, the input and a hash of the settings to check them against, so it can be
+//! played back frame-exact in any frontend with the same settings.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{input::InputFrame, ShooterState};
+
+/// The version of the game logic, recorded in replay files.
+/// A replay recorded with a different version may not play back the same.
+pub const GAME_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The layout of replay files, bumped whenever the recorded data changes, like the bits
+/// of an input frame. Files in other formats cannot be decoded, so they are refused.
+pub const REPLAY_FORMAT: u32 = 3;
+
+const REPLAY_MAGIC: &[u8; 4] = b"SHRF";
+
+/// The signature of the replay files saved before the format was recorded, which are in
+/// the first format with 8-bit input frames.
+const LEGACY_REPLAY_MAGIC: &[u8; 4] = b"SHRP";
+
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The data does not start with the replay file signature.
+    BadMagic,
+    /// The file is in another format than [`REPLAY_FORMAT`].
+    Format(u32),
+    Decode(bincode::Error),
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::BadMagic => write!(f, "Not a replay file"),
+            ReplayError::Format(format) => write!(
+                f,
+                "Replay file is in format {}, but this version only plays format {}",
+                format, REPLAY_FORMAT
+            ),
+            ReplayError::Decode(e) => write!(f, "Replay file is broken: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: String,
+    /// The seed the game was (re)started with.
+    pub seed: u32,
+    /// [`ShooterState::settings_hash`] of the game
+    pub settings: u64,
+    pub frames: Vec<InputFrame>,
+}
+
+impl Replay {
+    /// Start recording the game (re)started in `state`.
+    pub fn new(state: &ShooterState) -> Self {
+        Self {
+            version: GAME_VERSION.to_string(),
+            seed: state.seed,
+            settings: state.settings_hash(),
+            frames: vec![],
+        }
+    }
+
+    pub fn record(&mut self, input: &InputFrame) {
+        self.frames.push(*input);
+    }
+
+    /// Returns true if the replay was recorded with this version of the game.
+    pub fn is_compatible(&self) -> bool {
+        self.version == GAME_VERSION
+    }
+
+    /// Returns true if the replay was recorded with the same settings as `state` has.
+    pub fn matches_settings(&self, state: &ShooterState) -> bool {
+        self.settings == state.settings_hash()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = REPLAY_MAGIC.to_vec();
+        // Serializing into a Vec cannot fail
+        bincode::serialize_into(&mut ret, &REPLAY_FORMAT).unwrap();
+        bincode::serialize_into(&mut ret, self).unwrap();
+        ret
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReplayError> {
+        if data.starts_with(LEGACY_REPLAY_MAGIC) {
+            return Err(ReplayError::Format(1));
+        }
+        let mut body = data
+            .strip_prefix(REPLAY_MAGIC.as_ref())
+            .ok_or(ReplayError::BadMagic)?;
+        let format: u32 = bincode::deserialize_from(&mut body).map_err(ReplayError::Decode)?;
+        if format != REPLAY_FORMAT {
+            return Err(ReplayError::Format(format));
+        }
+        bincode::deserialize(body).map_err(ReplayError::Decode)
+    }
+}
+
+impl ShooterState {
+    /// A hash of the settings which affect the gameplay besides the seed: the spawn
+    /// table, the stage, the bullet patterns with the BulletML documents, HP mode and the
+    /// names of the registered custom enemies.
+    pub fn settings_hash(&self) -> u64 {
+        let stage = self.stage.as_ref().map(|stage| &stage.stage);
+        let custom_enemies: Vec<_> = self.enemy_registry.names().collect();
+        let settings = (
+            &self.spawn_table,
+            stage,
+            &self.bullet_patterns,
+            self.hp_mode,
+            custom_enemies,
+        );
+        // FNV-1a, which unlike the hashers in std stays the same across builds
+        bincode::serialize(&settings)
+            .unwrap()
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+}
+
+/// Feeds recorded input frames to the game one by one.
+pub struct ReplayPlayer {
+    replay: Replay,
+    cursor: usize,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        Self { replay, cursor: 0 }
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    /// The number of frames played so far.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+}
+
+impl Iterator for ReplayPlayer {
+    type Item = InputFrame;
+
+    /// Returns the input of the next frame, or None if the replay has ended.
+    fn next(&mut self) -> Option<InputFrame> {
+        let ret = self.replay.frames.get(self.cursor).copied();
+        if ret.is_some() {
+            self.cursor += 1;
+        }
+        ret
+    }
+}
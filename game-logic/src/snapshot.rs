@@ -0,0 +1,81 @@
+//! Saving and restoring the whole game state.
+//!
+//! Unlike a replay, a snapshot captures everything needed to resume the game at an
+//! arbitrary frame, including the random number generator state and the generations
+//! of entity ids, so that the game continues bit-identically after loading.
+
+use crate::{replay::GAME_VERSION, ShooterState};
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"SHSS";
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The data does not start with the snapshot file signature.
+    BadMagic,
+    /// The snapshot was saved by another version of the game, whose state layout may differ.
+    Version(String),
+    Decode(bincode::Error),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "Not a snapshot file"),
+            SnapshotError::Version(version) => write!(
+                f,
+                "Snapshot was saved with version {}, but this is version {}",
+                version, GAME_VERSION
+            ),
+            SnapshotError::Decode(e) => write!(f, "Snapshot file is broken: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl ShooterState {
+    /// Serialize the whole game state.
+    pub fn save_snapshot(&self) -> Vec<u8> {
+        let mut ret = SNAPSHOT_MAGIC.to_vec();
+        // Serializing into a Vec cannot fail
+        bincode::serialize_into(&mut ret, GAME_VERSION).unwrap();
+        bincode::serialize_into(&mut ret, self).unwrap();
+        ret
+    }
+
+    /// Restore a game state saved by [`ShooterState::save_snapshot`].
+    pub fn load_snapshot(data: &[u8]) -> Result<Self, SnapshotError> {
+        let mut body = data
+            .strip_prefix(SNAPSHOT_MAGIC.as_ref())
+            .ok_or(SnapshotError::BadMagic)?;
+        let version: String =
+            bincode::deserialize_from(&mut body).map_err(SnapshotError::Decode)?;
+        if version != GAME_VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+        bincode::deserialize(body).map_err(SnapshotError::Decode)
+    }
+}
+
+#[test]
+fn snapshot_resume() {
+    use crate::{input::InputFrame, xor128::Xor128};
+
+    let mut input_rng = Xor128::new(7);
+    let mut inputs = std::iter::repeat_with(|| InputFrame::from(input_rng.nexti() as u16 & 0x17f));
+
+    let mut state = ShooterState::default();
+    for input in inputs.by_ref().take(3000) {
+        state.step(&input);
+    }
+
+    let snapshot = state.save_snapshot();
+    let mut restored = ShooterState::load_snapshot(&snapshot).unwrap();
+    assert_eq!(restored.save_snapshot(), snapshot);
+
+    for input in inputs.take(3000) {
+        state.step(&input);
+        restored.step(&input);
+    }
+    assert_eq!(restored.save_snapshot(), state.save_snapshot());
+}
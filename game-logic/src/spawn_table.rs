@@ -0,0 +1,194 @@
+//! The definition of which enemies spawn in a wave and how often.
+//!
+//! A spawn table can be loaded from JSON at runtime, so that the balance can be tuned
+//! without recompiling the game. [`SpawnTable::default`] is the built-in table, which is
+//! also found in `spawn_tables/default.json` as a starting point for new tables.
+
+use serde::{Deserialize, Serialize};
+
+use crate::entity::{Enemy, EnemyKind, Player};
+
+#[derive(Debug)]
+pub enum SpawnTableError {
+    Parse(serde_json::Error),
+    /// The table parsed, but its values would break the game.
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for SpawnTableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpawnTableError::Parse(e) => write!(f, "Spawn table is broken: {}", e),
+            SpawnTableError::Invalid(e) => write!(f, "Spawn table is invalid: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SpawnTableError {}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpawnTable {
+    /// The length of an enemy wave in frames.
+    pub wave_period: usize,
+    /// Enemies spawn in the first `spawn_frames` of a wave, and the rest is a break.
+    pub spawn_frames: usize,
+    /// The spawn chances per frame are `gen_amount` out of `dice`.
+    pub dice: u32,
+    pub gen_amount: u32,
+    /// `gen_amount` is increased by this for every difficulty level.
+    pub gen_amount_per_difficulty: u32,
+    /// A boss appears at the start of every this many waves, pausing the regular spawns
+    /// until it is defeated. 0 means no boss.
+    #[serde(default)]
+    pub boss_interval: usize,
+    pub entries: Vec<SpawnEntry>,
+}
+
+/// The chance of an enemy kind to be chosen for a spawn.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpawnEntry {
+    pub enemy: EnemyKind,
+    /// The name of the registered enemy to spawn, if `enemy` is `Custom`
+    #[serde(default)]
+    pub custom: Option<String>,
+    /// The enemy does not spawn while this many of its kind are alive.
+    pub max_count: usize,
+    /// The weight relative to the other entries.
+    pub weight: u32,
+    /// The weight after the score reached some value, replacing `weight`.
+    /// The last entry whose `min_score` is reached applies.
+    #[serde(default)]
+    pub score_weights: Vec<ScoreWeight>,
+    /// Added to the weight for every difficulty level.
+    #[serde(default)]
+    pub weight_per_difficulty: u32,
+    #[serde(default)]
+    pub max_weight: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScoreWeight {
+    pub min_score: u32,
+    pub weight: u32,
+}
+
+impl Default for SpawnTable {
+    fn default() -> Self {
+        let entry = |enemy, max_count, weight| SpawnEntry {
+            enemy,
+            custom: None,
+            max_count,
+            weight,
+            score_weights: vec![],
+            weight_per_difficulty: 0,
+            max_weight: None,
+        };
+        Self {
+            wave_period: 1024,
+            spawn_frames: 768,
+            dice: 256,
+            gen_amount: 8,
+            gen_amount_per_difficulty: 4,
+            boss_interval: 4,
+            entries: vec![
+                SpawnEntry {
+                    score_weights: vec![ScoreWeight {
+                        min_score: 1024,
+                        weight: 16,
+                    }],
+                    ..entry(EnemyKind::Enemy1, 128, 64)
+                },
+                entry(EnemyKind::Boss, 32, 4),
+                SpawnEntry {
+                    weight_per_difficulty: 1,
+                    max_weight: Some(4),
+                    ..entry(EnemyKind::ShieldedBoss, 32, 0)
+                },
+                entry(EnemyKind::SpiralEnemy, 4, 4),
+                entry(EnemyKind::Centipede, 4, 4),
+                SpawnEntry {
+                    weight_per_difficulty: 2,
+                    max_weight: Some(8),
+                    ..entry(EnemyKind::Kamikaze, 8, 0)
+                },
+                entry(EnemyKind::Turret, 4, 2),
+                entry(EnemyKind::Splitter, 4, 4),
+                entry(EnemyKind::MineLayer, 2, 2),
+            ],
+        }
+    }
+}
+
+impl SpawnTable {
+    pub fn from_json(json: &str) -> Result<Self, SpawnTableError> {
+        let ret: Self = serde_json::from_str(json).map_err(SpawnTableError::Parse)?;
+        if ret.wave_period == 0 {
+            return Err(SpawnTableError::Invalid("wave_period must be positive"));
+        }
+        if ret.dice == 0 {
+            return Err(SpawnTableError::Invalid("dice must be positive"));
+        }
+        for entry in &ret.entries {
+            entry
+                .enemy
+                .check_custom(entry.custom.as_deref())
+                .map_err(SpawnTableError::Invalid)?;
+        }
+        Ok(ret)
+    }
+
+    pub fn to_json(&self) -> String {
+        // A struct with string keys always serializes
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// The spawn chances per frame out of `dice` for the player's progress.
+    pub fn gen_amount(&self, player: &Player) -> u32 {
+        self.gen_amount + self.gen_amount_per_difficulty * player.difficulty_level()
+    }
+}
+
+impl SpawnEntry {
+    /// The weight for the player's progress, without regard to `max_count`.
+    pub fn weight(&self, player: &Player) -> u32 {
+        let base = self
+            .score_weights
+            .iter()
+            .rev()
+            .find(|w| w.min_score <= player.score)
+            .map_or(self.weight, |w| w.weight);
+        let weight = base + self.weight_per_difficulty * player.difficulty_level();
+        self.max_weight.map_or(weight, |max| weight.min(max))
+    }
+
+    /// Whether `enemy` is of this entry, which counts toward `max_count`.
+    pub fn matches(&self, enemy: &Enemy) -> bool {
+        enemy.kind() == self.enemy
+            && self
+                .custom
+                .as_ref()
+                .map_or(true, |name| enemy.name() == name)
+    }
+}
+
+#[test]
+fn default_spawn_table_file() {
+    let table = SpawnTable::from_json(include_str!("../spawn_tables/default.json")).unwrap();
+    assert_eq!(table, SpawnTable::default());
+    assert_eq!(SpawnTable::from_json(&table.to_json()).unwrap(), table);
+
+    let custom = |entry: &str| {
+        SpawnTable::from_json(&format!(
+            r#"{{"wave_period": 1, "spawn_frames": 1, "dice": 1, "gen_amount": 1,
+                "gen_amount_per_difficulty": 0, "entries": [{}]}}"#,
+            entry
+        ))
+    };
+    assert!(custom(r#"{"enemy": "Custom", "custom": "a", "max_count": 1, "weight": 1}"#).is_ok());
+    for entry in [
+        r#"{"enemy": "Custom", "max_count": 1, "weight": 1}"#,
+        r#"{"enemy": "Boss", "custom": "a", "max_count": 1, "weight": 1}"#,
+    ] {
+        assert!(matches!(custom(entry), Err(SpawnTableError::Invalid(_))));
+    }
+}
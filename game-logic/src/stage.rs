@@ -0,0 +1,521 @@
+//! Authored stages, which spawn enemies on a timeline instead of (or in addition to)
+//! the random waves of the spawn table.
+//!
+//! A stage is a list of commands run in order. Spawn commands run immediately, while
+//! wait commands hold the rest of the timeline until some frames have passed or all the
+//! enemies spawned so far are gone.
+//!
+//! Enemies can move along the paths named in the stage instead of going straight, and be
+//! driven by the behaviors named in it.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    entity::{
+        Behavior, BehaviorRunner, Enemy, EnemyKind, EnemyRegistry, EntityId, EntitySet, Leg, Path,
+        Route,
+    },
+    event::GameEvent,
+};
+
+/// The speed of the enemies in a formation moving to their slots and diving at the player
+const FORMATION_SPEED: f64 = 2.;
+
+#[derive(Debug)]
+pub enum StageError {
+    Parse(serde_json::Error),
+    UnknownPath(String),
+    UnknownBehavior(String),
+    /// A transition of the behavior goes to a state which does not exist
+    UnknownState {
+        behavior: String,
+        state: String,
+    },
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for StageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StageError::Parse(e) => write!(f, "Stage file is broken: {}", e),
+            StageError::UnknownPath(name) => write!(f, "Path \"{}\" is not defined", name),
+            StageError::UnknownBehavior(name) => {
+                write!(f, "Behavior \"{}\" is not defined", name)
+            }
+            StageError::UnknownState { behavior, state } => write!(
+                f,
+                "State \"{}\" is not defined in behavior \"{}\"",
+                state, behavior
+            ),
+            StageError::Invalid(reason) => write!(f, "Stage is invalid: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StageError {}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stage {
+    pub name: String,
+    /// Keep spawning the random waves of the spawn table while the stage is running.
+    /// They always resume after the stage is cleared.
+    #[serde(default)]
+    pub random_spawns: bool,
+    /// The paths for the enemies to move along, by name
+    #[serde(default)]
+    pub paths: BTreeMap<String, Path>,
+    /// The behaviors to drive the enemies, by name
+    #[serde(default)]
+    pub behaviors: BTreeMap<String, Behavior>,
+    pub commands: Vec<StageCommand>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StageCommand {
+    Spawn(StageSpawn),
+    /// Spawn `count` enemies, each `interval` frames after and `spacing` away from the
+    /// previous one.
+    Group {
+        spawn: StageSpawn,
+        count: u32,
+        #[serde(default)]
+        spacing: [f64; 2],
+        #[serde(default)]
+        interval: usize,
+    },
+    Formation(StageFormation),
+    /// Wait for the given number of frames.
+    Wait(usize),
+    /// Wait until the given frame since the start of the stage.
+    WaitFrame(usize),
+    /// Wait until all the enemies spawned by the stage are killed or have left the screen.
+    WaitUntilKilled,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StageSpawn {
+    pub enemy: EnemyKind,
+    /// The name of the registered enemy, if `enemy` is `Custom`
+    #[serde(default)]
+    pub custom: Option<String>,
+    pub pos: [f64; 2],
+    pub velo: [f64; 2],
+    /// The path to move along instead of going straight with `velo`
+    #[serde(default)]
+    pub path: Option<String>,
+    /// The behavior to drive the enemy
+    #[serde(default)]
+    pub behavior: Option<String>,
+}
+
+/// Enemies entering one after another to hold the slots of a formation, and then peeling
+/// off one by one to attack.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StageFormation {
+    pub enemy: EnemyKind,
+    /// The name of the registered enemy, if `enemy` is `Custom`
+    #[serde(default)]
+    pub custom: Option<String>,
+    /// Where the enemies enter from
+    pub pos: [f64; 2],
+    /// The path to enter along before moving to the slot, which should be a curve that ends
+    #[serde(default)]
+    pub entry: Option<String>,
+    /// The positions to hold, one for each enemy
+    pub slots: Vec<[f64; 2]>,
+    /// Frames between the enemies entering
+    #[serde(default)]
+    pub interval: usize,
+    /// Frames since the first enemy entered until the first one peels off
+    pub hold: u32,
+    /// Frames between the enemies peeling off
+    #[serde(default)]
+    pub peel_interval: u32,
+    /// The path to attack along after peeling off, instead of diving at the player
+    #[serde(default)]
+    pub attack: Option<String>,
+}
+
+impl Stage {
+    pub fn from_json(json: &str) -> Result<Self, StageError> {
+        let ret: Self = serde_json::from_str(json).map_err(StageError::Parse)?;
+        ret.check()?;
+        Ok(ret)
+    }
+
+    /// Make sure that all the referred paths, behaviors and states exist, that the paths
+    /// are valid, and that only `Custom` enemies name a registered enemy.
+    fn check(&self) -> Result<(), StageError> {
+        for path in self.paths.values() {
+            path.check().map_err(StageError::Invalid)?;
+        }
+        for (name, behavior) in &self.behaviors {
+            if let Some(state) = behavior.unknown_state() {
+                return Err(StageError::UnknownState {
+                    behavior: name.clone(),
+                    state: state.to_string(),
+                });
+            }
+            behavior.check_spawns().map_err(StageError::Invalid)?;
+            behavior.check_paths().map_err(StageError::Invalid)?;
+        }
+        for command in &self.commands {
+            match command {
+                StageCommand::Spawn(spawn) | StageCommand::Group { spawn, .. } => {
+                    spawn.enemy.check_custom(spawn.custom.as_deref())
+                }
+                StageCommand::Formation(formation) => {
+                    formation.enemy.check_custom(formation.custom.as_deref())
+                }
+                _ => Ok(()),
+            }
+            .map_err(StageError::Invalid)?;
+            if let StageCommand::Spawn(spawn) | StageCommand::Group { spawn, .. } = command {
+                if let Some(name) = &spawn.behavior {
+                    if !self.behaviors.contains_key(name) {
+                        return Err(StageError::UnknownBehavior(name.clone()));
+                    }
+                }
+            }
+            let names = match command {
+                StageCommand::Spawn(spawn) | StageCommand::Group { spawn, .. } => {
+                    vec![&spawn.path]
+                }
+                StageCommand::Formation(formation) => vec![&formation.entry, &formation.attack],
+                _ => vec![],
+            };
+            for name in names.into_iter().flatten() {
+                if !self.paths.contains_key(name) {
+                    return Err(StageError::UnknownPath(name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A leg following the named path. A path that is not defined is skipped.
+    fn follow(&self, name: &Option<String>) -> Option<Leg> {
+        Some(Leg::Follow(self.paths.get(name.as_ref()?)?.clone()))
+    }
+
+    fn spawn(&self, spawn: &StageSpawn, pos: [f64; 2], registry: &EnemyRegistry) -> Enemy {
+        let mut enemy = registry.spawn_kind(spawn.enemy, spawn.custom.as_deref(), pos, spawn.velo);
+        if let Some(leg) = self.follow(&spawn.path) {
+            enemy.route = Some(Route::new(vec![leg], pos));
+        }
+        if let Some(behavior) = spawn
+            .behavior
+            .as_ref()
+            .and_then(|name| self.behaviors.get(name))
+        {
+            enemy.behavior = Some(BehaviorRunner::new(behavior.clone(), enemy.health));
+        }
+        enemy
+    }
+}
+
+impl StageFormation {
+    /// The route of the `i`-th enemy, from the entry to the attack.
+    fn route(&self, stage: &Stage, i: u32) -> Option<Route> {
+        let slot = *self.slots.get(i as usize)?;
+        let peel_frame = self.hold as usize + (self.peel_interval * i) as usize;
+        let entered_frame = self.interval * i as usize;
+        let legs = stage
+            .follow(&self.entry)
+            .into_iter()
+            .chain([
+                Leg::MoveTo {
+                    target: slot,
+                    speed: FORMATION_SPEED,
+                },
+                Leg::HoldUntil(peel_frame.saturating_sub(entered_frame) as u32),
+                stage.follow(&self.attack).unwrap_or(Leg::Dive {
+                    speed: FORMATION_SPEED,
+                }),
+            ])
+            .collect();
+        Some(Route::new(legs, self.pos))
+    }
+}
+
+/// The progress of a stage in a game.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StageRunner {
+    pub stage: Stage,
+    /// Frames since the start of the stage
+    frame: usize,
+    /// The index of the command to run next
+    cursor: usize,
+    /// Frames to wait before running the next command
+    wait: usize,
+    /// The number of enemies spawned so far by the current group command
+    group_spawned: u32,
+    /// The enemies spawned by the stage that may be still alive
+    spawned: Vec<EntityId<Enemy>>,
+    /// All the commands have run, including the last wait
+    cleared: bool,
+}
+
+impl StageRunner {
+    pub fn new(stage: Stage) -> Self {
+        Self {
+            stage,
+            frame: 0,
+            cursor: 0,
+            wait: 0,
+            group_spawned: 0,
+            spawned: vec![],
+            cleared: false,
+        }
+    }
+
+    /// Start the stage over.
+    pub fn reset(&mut self) {
+        *self = Self::new(std::mem::take(&mut self.stage));
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.cleared
+    }
+
+    /// The random waves should spawn in this frame.
+    pub fn random_spawns(&self) -> bool {
+        self.stage.random_spawns || self.is_finished()
+    }
+
+    /// Advance the timeline by a frame, spawning enemies into `enemies`.
+    pub fn advance(
+        &mut self,
+        enemies: &mut EntitySet<Enemy>,
+        registry: &EnemyRegistry,
+        events: &mut Vec<GameEvent>,
+    ) {
+        if self.is_finished() {
+            return;
+        }
+        self.frame += 1;
+        loop {
+            if 0 < self.wait {
+                self.wait -= 1;
+                return;
+            }
+            let Some(command) = self.stage.commands.get(self.cursor) else {
+                self.cleared = true;
+                events.push(GameEvent::StageCleared);
+                return;
+            };
+            match command {
+                StageCommand::Spawn(spawn) => {
+                    let id = enemies.insert(self.stage.spawn(spawn, spawn.pos, registry));
+                    self.spawned.push(id);
+                    self.cursor += 1;
+                }
+                StageCommand::Group {
+                    spawn,
+                    count,
+                    spacing,
+                    interval,
+                } => {
+                    if self.group_spawned < *count {
+                        let offset = self.group_spawned as f64;
+                        let pos = [
+                            spawn.pos[0] + spacing[0] * offset,
+                            spawn.pos[1] + spacing[1] * offset,
+                        ];
+                        let id = enemies.insert(self.stage.spawn(spawn, pos, registry));
+                        self.spawned.push(id);
+                        self.group_spawned += 1;
+                    }
+                    if *count <= self.group_spawned {
+                        self.group_spawned = 0;
+                        self.cursor += 1;
+                    } else {
+                        self.wait = *interval;
+                    }
+                }
+                StageCommand::Formation(formation) => {
+                    if let Some(route) = formation.route(&self.stage, self.group_spawned) {
+                        let mut enemy = registry.spawn_kind(
+                            formation.enemy,
+                            formation.custom.as_deref(),
+                            formation.pos,
+                            [0.; 2],
+                        );
+                        enemy.route = Some(route);
+                        self.spawned.push(enemies.insert(enemy));
+                        self.group_spawned += 1;
+                    }
+                    if formation.slots.len() <= self.group_spawned as usize {
+                        self.group_spawned = 0;
+                        self.cursor += 1;
+                    } else {
+                        self.wait = formation.interval;
+                    }
+                }
+                StageCommand::Wait(frames) => {
+                    self.wait = *frames;
+                    self.cursor += 1;
+                }
+                StageCommand::WaitFrame(frame) => {
+                    if self.frame < *frame {
+                        return;
+                    }
+                    self.cursor += 1;
+                }
+                StageCommand::WaitUntilKilled => {
+                    self.spawned.retain(|id| enemies.get(*id).is_some());
+                    if !self.spawned.is_empty() {
+                        return;
+                    }
+                    self.cursor += 1;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn example_stage() {
+    use crate::{input::InputFrame, ShooterState};
+
+    let stage = Stage::from_json(include_str!("../stages/example.json")).unwrap();
+    let mut state = ShooterState {
+        stage: Some(StageRunner::new(stage)),
+        ..ShooterState::default()
+    };
+
+    let input = InputFrame {
+        shoot: true,
+        ..InputFrame::default()
+    };
+    state.step(&input);
+    // The first command spawns right away, and no random enemies spawn
+    assert_eq!(state.enemies.len(), 1);
+
+    let mut cleared = false;
+    for _ in 0..20000 {
+        state.step(&input);
+        if state.events.contains(&GameEvent::StageCleared) {
+            cleared = true;
+            break;
+        }
+    }
+    assert!(cleared);
+    assert!(state.stage.as_ref().unwrap().is_finished());
+
+    let still =
+        include_str!("../stages/example.json").replace(r#""period": 120"#, r#""period": 0"#);
+    assert!(matches!(
+        Stage::from_json(&still),
+        Err(StageError::Invalid(_))
+    ));
+}
+
+#[test]
+fn formation() {
+    use crate::{input::InputFrame, ShooterState};
+
+    let stage = Stage {
+        commands: vec![StageCommand::Formation(StageFormation {
+            enemy: EnemyKind::Enemy1,
+            custom: None,
+            pos: [240., 0.],
+            entry: None,
+            slots: vec![[200., 100.], [280., 100.]],
+            interval: 10,
+            hold: 200,
+            peel_interval: 50,
+            attack: None,
+        })],
+        ..Stage::default()
+    };
+    let mut state = ShooterState {
+        stage: Some(StageRunner::new(stage)),
+        ..ShooterState::default()
+    };
+    // The random waves resume as soon as the formation has entered
+    state.spawn_table.entries.clear();
+    state.bullet_patterns.enemies.clear();
+    let positions =
+        |state: &ShooterState| -> Vec<_> { state.enemies.iter().map(|e| e.pos).collect() };
+
+    for _ in 0..150 {
+        state.step(&InputFrame::default());
+    }
+    assert_eq!(positions(&state), [[200., 100.], [280., 100.]]);
+
+    // Peel off one by one
+    for _ in 150..230 {
+        state.step(&InputFrame::default());
+    }
+    let pos = positions(&state);
+    assert_ne!(pos[0], [200., 100.]);
+    assert_eq!(pos[1], [280., 100.]);
+
+    let json = r#"{ "name": "", "commands": [{ "Formation": {
+        "enemy": "Enemy1", "pos": [0, 0], "slots": [[0, 0]], "hold": 0, "attack": "loop"
+    } }] }"#;
+    assert!(matches!(
+        Stage::from_json(json),
+        Err(StageError::UnknownPath(name)) if name == "loop"
+    ));
+}
+
+#[test]
+fn behaviors() {
+    use crate::{input::InputFrame, ShooterState};
+
+    let json = r#"{
+        "name": "Behaviors",
+        "behaviors": {
+            "hit_and_run": { "states": [
+                {
+                    "name": "approach",
+                    "enter": [{ "Velocity": [0, 2] }],
+                    "transitions": [{ "when": { "PlayerWithin": 200 }, "to": ["retreat"] }]
+                },
+                {
+                    "name": "retreat",
+                    "enter": ["HoldFire", { "Spawn": { "enemy": "Enemy1", "velo": [0, 1] } }],
+                    "each_frame": [{ "Velocity": [0, -1] }]
+                }
+            ] }
+        },
+        "commands": [{ "Spawn": {
+            "enemy": "Boss", "pos": [240, 0], "velo": [0, 0], "behavior": "hit_and_run"
+        } }]
+    }"#;
+    let mut state = ShooterState {
+        stage: Some(StageRunner::new(Stage::from_json(json).unwrap())),
+        ..ShooterState::default()
+    };
+    state.spawn_table.entries.clear();
+
+    let boss_state = |state: &ShooterState| {
+        let boss = state.enemies.iter().find(|e| e.is_boss()).unwrap();
+        let behavior = boss.behavior.as_ref().unwrap().state().unwrap().to_string();
+        (behavior, boss.velo, boss.fire.is_some())
+    };
+    for _ in 0..10 {
+        state.step(&InputFrame::default());
+    }
+    assert_eq!(boss_state(&state), ("approach".to_string(), [0., 2.], true));
+    assert_eq!(state.enemies.len(), 1);
+
+    for _ in 10..150 {
+        state.step(&InputFrame::default());
+    }
+    assert_eq!(
+        boss_state(&state),
+        ("retreat".to_string(), [0., -1.], false)
+    );
+    assert_eq!(state.enemies.len(), 2);
+
+    let broken = json.replace(r#""to": ["retreat"]"#, r#""to": ["flee"]"#);
+    assert!(matches!(
+        Stage::from_json(&broken),
+        Err(StageError::UnknownState { state, .. }) if state == "flee"
+    ));
+}
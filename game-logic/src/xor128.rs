@@ -1,12 +1,22 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
 pub struct Xor128 {
     x: u32,
 }
 
+const INITIAL_STATE: u32 = 2463534242;
+
 impl Xor128 {
     pub fn new(seed: u32) -> Self {
-        let mut ret = Xor128 { x: 2463534242 };
+        let mut ret = Xor128 { x: INITIAL_STATE };
         if 0 < seed {
             ret.x ^= seed;
+            // Xorshift never leaves the zero state, so the seed which cancels out the
+            // initial state starts from the initial state instead
+            if ret.x == 0 {
+                ret.x = INITIAL_STATE;
+            }
             ret.nexti();
         }
         ret.nexti();
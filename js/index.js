@@ -19,6 +19,13 @@ import beam from "../assets/beam.png";
 import back from "../assets/back2.jpg";
 import power from "../assets/power.png";
 import power2 from "../assets/power2.png";
+import bomb from "../assets/bomb.png";
+import hitbox from "../assets/hitbox.png";
+import kamikaze from "../assets/kamikaze.png";
+import turret from "../assets/turret.png";
+import splitter from "../assets/splitter.png";
+import mineLayer from "../assets/mine-layer.png";
+import mine from "../assets/mine.png";
 import sphere from "../assets/sphere.png";
 import weapons from "../assets/weapons.png";
 
@@ -95,6 +102,13 @@ rust
       ["back", back],
       ["power", power],
       ["power2", power2],
+      ["bomb", bomb],
+      ["hitbox", hitbox],
+      ["kamikaze", kamikaze],
+      ["turret", turret],
+      ["splitter", splitter],
+      ["mineLayer", mineLayer],
+      ["mine", mine],
       ["sphere", sphere],
       ["weapons", weapons],
     ];
@@ -102,17 +116,124 @@ rust
 
     state.start();
 
-    document.addEventListener("keydown", (event) => {
-      const weapon = state.key_down(event);
-      updateWeapons(weapon);
-    });
+    document.addEventListener("keydown", (event) => state.key_down(event));
     document.addEventListener("keyup", (event) => state.key_up(event));
 
     const restartButton = document.getElementById("restart");
     restartButton.addEventListener("click", () => state.restart());
 
+    const seedInput = document.getElementById("seedInput");
+    const startSeedButton = document.getElementById("startSeed");
+    startSeedButton.addEventListener("click", () => {
+      const seed = Number(seedInput.value);
+      if (seedInput.value === "" || !Number.isInteger(seed) || seed < 0 || 0xffffffff < seed) {
+        alert("Seed must be an integer from 0 to 4294967295");
+        return;
+      }
+      state.restart_with_seed(seed);
+      // Don't let the space key (shoot) click the button again
+      startSeedButton.blur();
+      seedInput.blur();
+    });
+
+    const dailyChallengeButton = document.getElementById("dailyChallenge");
+    dailyChallengeButton.addEventListener("click", () => {
+      state.daily_challenge();
+      dailyChallengeButton.blur();
+    });
+
+    const hpModeInput = document.getElementById("hpMode");
+    hpModeInput.addEventListener("change", () => {
+      state.set_hp_mode(hpModeInput.checked);
+      hpModeInput.blur();
+    });
+
+    const saveReplayButton = document.getElementById("saveReplay");
+    saveReplayButton.addEventListener("click", () => {
+      const blob = new Blob([state.save_replay()], {type: "application/octet-stream"});
+      const link = document.createElement("a");
+      link.href = URL.createObjectURL(blob);
+      link.download = "shooter-replay.bin";
+      link.click();
+      URL.revokeObjectURL(link.href);
+      // Don't let the space key (shoot) click the button again
+      saveReplayButton.blur();
+    });
+
+    const loadReplayInput = document.getElementById("loadReplay");
+    loadReplayInput.addEventListener("change", async () => {
+      const file = loadReplayInput.files[0];
+      if (!file) return;
+      try {
+        state.load_replay(new Uint8Array(await file.arrayBuffer()));
+      } catch (e) {
+        alert(e);
+      }
+      loadReplayInput.value = "";
+      loadReplayInput.blur();
+    });
+
+    const loadSpawnTableInput = document.getElementById("loadSpawnTable");
+    loadSpawnTableInput.addEventListener("change", async () => {
+      const file = loadSpawnTableInput.files[0];
+      if (!file) return;
+      try {
+        state.load_spawn_table(await file.text());
+      } catch (e) {
+        alert(e);
+      }
+      loadSpawnTableInput.value = "";
+      loadSpawnTableInput.blur();
+    });
+
+    const loadStageInput = document.getElementById("loadStage");
+    loadStageInput.addEventListener("change", async () => {
+      const file = loadStageInput.files[0];
+      if (!file) return;
+      try {
+        state.load_stage(await file.text());
+      } catch (e) {
+        alert(e);
+      }
+      loadStageInput.value = "";
+      loadStageInput.blur();
+    });
+
+    const loadBulletPatternsInput = document.getElementById("loadBulletPatterns");
+    loadBulletPatternsInput.addEventListener("change", async () => {
+      const file = loadBulletPatternsInput.files[0];
+      if (!file) return;
+      try {
+        state.load_bullet_patterns(await file.text());
+      } catch (e) {
+        alert(e);
+      }
+      loadBulletPatternsInput.value = "";
+      loadBulletPatternsInput.blur();
+    });
+
+    const loadBulletMLInput = document.getElementById("loadBulletML");
+    loadBulletMLInput.addEventListener("change", async () => {
+      const file = loadBulletMLInput.files[0];
+      if (!file) return;
+      try {
+        // Patterns refer to the document by the file name without the extension
+        state.load_bulletml(file.name.replace(/\.[^.]*$/, ""), await file.text());
+      } catch (e) {
+        alert(e);
+      }
+      loadBulletMLInput.value = "";
+      loadBulletMLInput.blur();
+    });
+
+    let lastWeapon = "Bullet";
     function render() {
       state.render();
+      const weapon = state.weapon();
+      if (weapon !== lastWeapon) {
+        updateWeapons(weapon);
+        lastWeapon = weapon;
+      }
       requestAnimationFrame(render);
     }
     requestAnimationFrame(render);
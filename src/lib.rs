@@ -10,9 +10,15 @@ use game_logic::{
     assets_webgl::{Assets, ShaderBundle},
     console_log,
     consts::*,
-    enable_buffer,
-    entity::{Enemy, Entity, TempEntity, TempEntityType, Weapon},
-    js_str, vertex_buffer_data,
+    daily_seed, enable_buffer,
+    entity::{PatternLibrary, Weapon},
+    event::GameEvent,
+    input::InputFrame,
+    js_str,
+    replay::{Replay, ReplayPlayer},
+    spawn_table::SpawnTable,
+    stage::{Stage, StageRunner},
+    vertex_buffer_data,
 };
 
 #[wasm_bindgen]
@@ -43,6 +49,11 @@ fn get_context() -> GL {
         .unwrap()
 }
 
+/// A seed for a new game, different in each run
+fn random_seed() -> u32 {
+    (js_sys::Math::random() * u32::MAX as f64) as u32
+}
+
 #[derive(Default)]
 struct InputState {
     pub shoot_pressed: bool,
@@ -50,6 +61,34 @@ struct InputState {
     pub right_pressed: bool,
     pub up_pressed: bool,
     pub down_pressed: bool,
+    pub focus_pressed: bool,
+    /// One-shot actions, cleared after they are passed to the game
+    pub next_weapon: bool,
+    pub prev_weapon: bool,
+    pub pause: bool,
+    pub bomb: bool,
+}
+
+impl InputState {
+    fn take_frame(&mut self) -> InputFrame {
+        let ret = InputFrame {
+            up: self.up_pressed,
+            down: self.down_pressed,
+            left: self.left_pressed,
+            right: self.right_pressed,
+            shoot: self.shoot_pressed,
+            focus: self.focus_pressed,
+            next_weapon: self.next_weapon,
+            prev_weapon: self.prev_weapon,
+            pause: self.pause,
+            bomb: self.bomb,
+        };
+        self.next_weapon = false;
+        self.prev_weapon = false;
+        self.pause = false;
+        self.bomb = false;
+        ret
+    }
 }
 
 #[wasm_bindgen]
@@ -57,6 +96,11 @@ pub struct ShooterState {
     state: game_logic::ShooterState,
     input_state: InputState,
     assets: Assets,
+    /// The input of the current game, recorded from the last restart
+    replay: Replay,
+    replay_player: Option<ReplayPlayer>,
+    /// The snapshot and the replay recorded up to it, saved by quick save
+    quick_save: Option<(Vec<u8>, Replay)>,
 }
 
 #[wasm_bindgen]
@@ -65,60 +109,56 @@ impl ShooterState {
     pub fn new(image_assets: js_sys::Array) -> Result<ShooterState, JsValue> {
         let context = get_context();
 
+        let state = game_logic::ShooterState::with_seed(random_seed());
+        let replay = Replay::new(&state);
+
         Ok(Self {
-            state: game_logic::ShooterState::default(),
+            state,
             input_state: InputState::default(),
             assets: Assets::new(&document(), &context, image_assets)?,
+            replay,
+            replay_player: None,
+            quick_save: None,
         })
     }
 
-    pub fn key_down(&mut self, event: web_sys::KeyboardEvent) -> Result<JsString, JsValue> {
+    pub fn key_down(&mut self, event: web_sys::KeyboardEvent) -> Result<(), JsValue> {
         println!("key: {}", event.key_code());
         match event.key_code() {
             32 => self.input_state.shoot_pressed = true,
+            16 => self.input_state.focus_pressed = true, // Shift
             65 | 37 => self.input_state.left_pressed = true,
             68 | 39 => self.input_state.right_pressed = true,
-            80 => {
-                // P
-                self.state.paused = !self.state.paused;
-                let paused_element = document().get_element_by_id("paused").unwrap();
-                paused_element.set_class_name(if self.state.paused {
-                    "noselect"
-                } else {
-                    "noselect hidden"
-                })
-            }
+            66 => self.input_state.bomb = true,  // B
+            80 => self.input_state.pause = true, // P
             87 | 38 => self.input_state.up_pressed = true,
             83 | 40 => self.input_state.down_pressed = true,
             88 | 90 => {
                 // Z or X
-                let is_x = event.key_code() == 88;
-                self.state.player.weapon = if is_x {
-                    self.state.player.weapon.next()
+                if event.key_code() == 88 {
+                    self.input_state.next_weapon = true;
                 } else {
-                    self.state.player.weapon.prev()
-                };
-                console_log!("Weapon switched: {}", self.state.player.weapon);
-            }
-            78 => {
-                // N
-                self.state.restart()?;
-                for (name, class_name) in &[("gameOver", "hidden"), ("paused", "hidden noselect")] {
-                    let elem = document()
-                        .get_element_by_id(name)
-                        .ok_or_else(|| js_str!("HTML element not found"))?;
-                    elem.set_class_name(class_name);
+                    self.input_state.prev_weapon = true;
                 }
             }
+            78 => self.restart()?,    // N
+            81 => self.quick_save(),  // Q
+            76 => self.quick_load()?, // L
             _ => (),
         }
-        Ok(JsString::from(self.state.player.weapon.to_string()))
+        Ok(())
+    }
+
+    /// The name of the current weapon, to highlight it in the weapon icons
+    pub fn weapon(&self) -> JsString {
+        JsString::from(self.state.player.weapon.to_string())
     }
 
     pub fn key_up(&mut self, event: web_sys::KeyboardEvent) {
         console_log!("key: {}", event.key_code());
         match event.key_code() {
             32 => self.input_state.shoot_pressed = false,
+            16 => self.input_state.focus_pressed = false,
             65 | 37 => self.input_state.left_pressed = false,
             68 | 39 => self.input_state.right_pressed = false,
             87 | 38 => self.input_state.up_pressed = false,
@@ -127,8 +167,21 @@ impl ShooterState {
         }
     }
 
+    /// Start a new game with a random seed
     pub fn restart(&mut self) -> Result<(), JsValue> {
-        self.state.restart()?;
+        self.restart_with_seed(random_seed())
+    }
+
+    /// Start a new game with the daily challenge seed of today
+    pub fn daily_challenge(&mut self) -> Result<(), JsValue> {
+        let days_since_epoch = (js_sys::Date::now() / 86_400_000.) as u32;
+        self.restart_with_seed(daily_seed(days_since_epoch))
+    }
+
+    pub fn restart_with_seed(&mut self, seed: u32) -> Result<(), JsValue> {
+        self.state.restart_with_seed(seed)?;
+        self.replay = Replay::new(&self.state);
+        self.replay_player = None;
 
         for icon in &self.assets.player_live_icons {
             icon.set_class_name("");
@@ -141,6 +194,90 @@ impl ShooterState {
         Ok(())
     }
 
+    /// Returns the recorded input of the current game as a replay file
+    pub fn save_replay(&self) -> Vec<u8> {
+        self.replay.to_bytes()
+    }
+
+    /// Restart the game and play back the given replay file
+    pub fn load_replay(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        let replay = Replay::from_bytes(data).map_err(|e| js_str!("{}", e))?;
+        if !replay.is_compatible() {
+            console_log!(
+                "Warning: the replay was recorded with version {}, which may not play back the same",
+                replay.version
+            );
+        }
+        if !replay.matches_settings(&self.state) {
+            console_log!(
+                "Warning: the replay was recorded with another spawn table, stage, bullet patterns, HP mode or custom enemies, which may not play back the same"
+            );
+        }
+        self.restart_with_seed(replay.seed)?;
+        self.replay_player = Some(ReplayPlayer::new(replay));
+        Ok(())
+    }
+
+    /// Replace the spawn table with the given JSON and restart the game with it
+    pub fn load_spawn_table(&mut self, json: &str) -> Result<(), JsValue> {
+        self.state.spawn_table = SpawnTable::from_json(json).map_err(|e| js_str!("{}", e))?;
+        self.restart()
+    }
+
+    /// Replace the bullet patterns with the given JSON and restart the game with them
+    pub fn load_bullet_patterns(&mut self, json: &str) -> Result<(), JsValue> {
+        self.state.bullet_patterns =
+            PatternLibrary::from_json(json).map_err(|e| js_str!("{}", e))?;
+        self.restart()
+    }
+
+    /// Add the BulletML document in the given XML under `name` and restart the game
+    pub fn load_bulletml(&mut self, name: &str, xml: &str) -> Result<(), JsValue> {
+        self.state
+            .bullet_patterns
+            .load_bulletml(name, xml)
+            .map_err(|e| js_str!("{}", e))?;
+        self.restart()
+    }
+
+    /// Switch HP mode, in which hits drain a health bar, and start a new game.
+    pub fn set_hp_mode(&mut self, hp_mode: bool) -> Result<(), JsValue> {
+        self.state.hp_mode = hp_mode;
+        self.restart()
+    }
+
+    /// Play the stage in the given JSON from the start
+    pub fn load_stage(&mut self, json: &str) -> Result<(), JsValue> {
+        let stage = Stage::from_json(json).map_err(|e| js_str!("{}", e))?;
+        self.state.stage = Some(StageRunner::new(stage));
+        self.restart()
+    }
+
+    /// Save the current game state in memory
+    pub fn quick_save(&mut self) {
+        self.quick_save = Some((self.state.save_snapshot(), self.replay.clone()));
+        console_log!("Quick saved at frame {}", self.state.time);
+    }
+
+    /// Restore the game state saved by quick save, if any
+    pub fn quick_load(&mut self) -> Result<(), JsValue> {
+        let Some((snapshot, replay)) = &self.quick_save else {
+            return Ok(());
+        };
+        self.state =
+            game_logic::ShooterState::load_snapshot(snapshot).map_err(|e| js_str!("{}", e))?;
+        // Keep recording from the saved frame, so that the replay stays consistent
+        self.replay = replay.clone();
+        self.replay_player = None;
+
+        let game_over_element = document()
+            .get_element_by_id("gameOver")
+            .ok_or_else(|| js_str!("Game over element was not found"))?;
+        game_over_element.set_class_name(if self.state.game_over { "" } else { "hidden" });
+        console_log!("Quick loaded frame {}", self.state.time);
+        Ok(())
+    }
+
     pub fn start(&mut self) -> Result<(), JsValue> {
         let context = get_context();
 
@@ -251,59 +388,37 @@ impl ShooterState {
     pub fn render(&mut self) -> Result<(), JsValue> {
         let context = get_context();
 
-        if !self.state.paused {
-            self.state.time += 1;
+        let mut input = self.input_state.take_frame();
+        if let Some(replay_player) = &mut self.replay_player {
+            if let Some(recorded) = replay_player.next() {
+                input = recorded;
+            } else {
+                console_log!("Replay finished at frame {}", replay_player.cursor());
+                self.replay_player = None;
+            }
         }
+        self.replay.record(&input);
+
+        self.state.step(&input);
         self.state.disptime += 1;
 
-        let assets = &self.assets;
+        let paused_element = document()
+            .get_element_by_id("paused")
+            .ok_or_else(|| js_str!("paused elem not found"))?;
+        paused_element.set_class_name(if self.state.paused {
+            "noselect"
+        } else {
+            "noselect hidden"
+        });
 
-        type TT = TempEntityType;
-
-        // state must be passed as arguments since they are mutable
-        // borrows and needs to be released for each iteration.
-        // These variables are used in between multiple invocation of this closure.
-        let add_tent =
-            |ty: TT, pos: &[f64; 2], velo: &[f64; 2], state: &mut game_logic::ShooterState| {
-                let mut ent = Entity::new(
-                    [
-                        pos[0] + 4. * (state.rng.gen() - 0.5),
-                        pos[1] + 4. * (state.rng.gen() - 0.5),
-                    ],
-                    *velo,
-                )
-                .rotation(state.rng.gen() as f32 * 2. * std::f32::consts::PI);
-                let (playback_rate, max_frames, repeats, width, shrink) = match ty {
-                    TT::Explode => (2, 8, 1, 16, 0.),
-                    TT::Explode2 => (4, 6, 1, 32, 0.),
-                    TT::Blood => (2, 4, state.rng.gen_range(2, 5), 16, 1.),
-                };
-                ent = ent.health((max_frames * playback_rate * repeats) as i32);
-
-                state.tent.insert(TempEntity {
-                    base: ent,
-                    texture: match ty {
-                        TT::Explode => assets.explode_tex.clone(),
-                        TT::Explode2 => assets.explode2_tex.clone(),
-                        TT::Blood => assets.blood_tex.clone(),
-                    },
-                    max_frames,
-                    width,
-                    playback_rate,
-                    image_width: match ty {
-                        TT::Explode => 128,
-                        TT::Explode2 => 256,
-                        TT::Blood => 64,
-                    },
-                    size: match ty {
-                        TT::Explode | TT::Blood => EXPLODE_SIZE,
-                        _ => EXPLODE2_SIZE,
-                    },
-                    shrink_rate: shrink,
-                });
-            };
-
-        let wave_period = self.state.gen_enemies();
+        if self.state.events.contains(&GameEvent::GameOver) {
+            let game_over_elem = document()
+                .get_element_by_id("gameOver")
+                .ok_or_else(|| js_str!("game over elem not found"))?;
+            game_over_elem.set_class_name("");
+        }
+
+        let assets = &self.assets;
 
         context.clear(GL::COLOR_BUFFER_BIT);
 
@@ -320,194 +435,103 @@ impl ShooterState {
         context.bind_texture(GL::TEXTURE_2D, Some(&self.assets.back_tex));
         context.draw_arrays(GL::TRIANGLE_FAN, 0, 4);
 
-        if !self.state.game_over && !self.state.paused {
-            if self.input_state.up_pressed {
-                self.state.player.move_up()
-            }
-            if self.input_state.down_pressed {
-                self.state.player.move_down()
-            }
-            if self.input_state.left_pressed {
-                self.state.player.move_left()
-            }
-            if self.input_state.right_pressed {
-                self.state.player.move_right()
-            }
+        if !self.state.game_over
+            && !self.state.paused
+            && self.input_state.shoot_pressed
+            && Weapon::Light == self.state.player.weapon
+        {
+            let gl = &context;
+            let player = &self.state.player;
+            let level = player.power_level() as i32;
 
-            if self.input_state.shoot_pressed && self.state.player.cooldown == 0 {
-                let weapon = self.state.player.weapon;
+            gl.use_program(Some(&self.assets.trail_shader.as_ref().unwrap().program));
+            let shader = assets.trail_shader.as_ref().unwrap();
 
-                // Use the same seed twice to reproduce random sequence
-                let seed = self.state.rng.nexti();
+            gl.uniform1i(shader.texture_loc.as_ref(), 0);
+            gl.bind_texture(GL::TEXTURE_2D, Some(&assets.beam_tex));
 
-                self.state.try_shoot(
-                    self.input_state.shoot_pressed,
-                    seed,
-                    &mut |ty, pos, state| add_tent(ty, pos, &[0.; 2], state),
-                );
+            enable_buffer(gl, &assets.trail_buffer, 4, shader.vertex_position);
+
+            let left = player.base.pos[0] as f32 - level as f32 - 3.;
+            let right = player.base.pos[0] as f32 + level as f32 + 3.;
+            let vertices = [
+                [left, player.base.pos[1] as f32, 0., 0.],
+                [right, player.base.pos[1] as f32, 0., 1.],
+                [left, 0., 1., 0.],
+                [right, 0., 1., 1.],
+            ];
+
+            vertex_buffer_data(gl, vertices.flat());
+
+            gl.uniform_matrix4fv_with_f32_array(
+                shader.transform_loc.as_ref(),
+                false,
+                <Matrix4<f32> as AsRef<[f32; 16]>>::as_ref(&assets.world_transform.cast().unwrap()),
+            );
+
+            gl.uniform_matrix3fv_with_f32_array(
+                shader.tex_transform_loc.as_ref(),
+                false,
+                <Matrix3<f32> as AsRef<[f32; 9]>>::as_ref(&Matrix3::from_scale(1.)),
+            );
+
+            gl.draw_arrays(GL::TRIANGLE_STRIP, 0, vertices.len() as i32);
+
+            enable_buffer(
+                gl,
+                &assets.rect_buffer,
+                2,
+                assets.sprite_shader.as_ref().unwrap().vertex_position,
+            );
+        }
+
+        if !self.state.lightning_branches.is_empty() {
+            let gl = &context;
+
+            gl.use_program(Some(&assets.trail_shader.as_ref().unwrap().program));
+            let shader = assets.trail_shader.as_ref().unwrap();
+
+            gl.uniform1i(shader.texture_loc.as_ref(), 0);
+            gl.bind_texture(GL::TEXTURE_2D, Some(&assets.beam_tex));
+
+            enable_buffer(gl, &assets.trail_buffer, 4, shader.vertex_position);
+
+            gl.uniform_matrix4fv_with_f32_array(
+                shader.transform_loc.as_ref(),
+                false,
+                <Matrix4<f32> as AsRef<[f32; 16]>>::as_ref(&assets.world_transform.cast().unwrap()),
+            );
+
+            gl.uniform_matrix3fv_with_f32_array(
+                shader.tex_transform_loc.as_ref(),
+                false,
+                <Matrix3<f32> as AsRef<[f32; 9]>>::as_ref(&Matrix3::from_scale(1.)),
+            );
 
-                if Weapon::Light == weapon {
-                    let gl = &context;
-                    let assets = &self.assets;
-                    let player = &self.state.player;
-                    let level = player.power_level() as i32;
-
-                    gl.use_program(Some(&self.assets.trail_shader.as_ref().unwrap().program));
-                    let shader = assets.trail_shader.as_ref().unwrap();
-
-                    gl.uniform1i(shader.texture_loc.as_ref(), 0);
-                    gl.bind_texture(GL::TEXTURE_2D, Some(&assets.beam_tex));
-
-                    enable_buffer(gl, &assets.trail_buffer, 4, shader.vertex_position);
-
-                    let left = player.base.pos[0] as f32 - level as f32 - 3.;
-                    let right = player.base.pos[0] as f32 + level as f32 + 3.;
-                    let vertices = [
-                        [left, player.base.pos[1] as f32, 0., 0.],
-                        [right, player.base.pos[1] as f32, 0., 1.],
-                        [left, 0., 1., 0.],
-                        [right, 0., 1., 1.],
-                    ];
-
-                    vertex_buffer_data(gl, &vertices.flat());
-
-                    gl.uniform_matrix4fv_with_f32_array(
-                        shader.transform_loc.as_ref(),
-                        false,
-                        <Matrix4<f32> as AsRef<[f32; 16]>>::as_ref(
-                            &assets.world_transform.cast().unwrap(),
-                        ),
-                    );
-
-                    gl.uniform_matrix3fv_with_f32_array(
-                        shader.tex_transform_loc.as_ref(),
-                        false,
-                        <Matrix3<f32> as AsRef<[f32; 9]>>::as_ref(&Matrix3::from_scale(1.)),
-                    );
-
-                    gl.draw_arrays(GL::TRIANGLE_STRIP, 0, vertices.len() as i32);
-
-                    enable_buffer(
-                        gl,
-                        &assets.rect_buffer,
-                        2,
-                        assets.sprite_shader.as_ref().unwrap().vertex_position,
-                    );
-                } else if Weapon::Lightning == weapon {
-                    let gl = &context;
-
-                    self.state.lightning(
-                        seed,
-                        None,
-                        &mut |state: &mut game_logic::ShooterState, seed| {
-                            let length = state.lightning_branch(
-                                seed,
-                                LIGHTNING_VERTICES,
-                                &mut |state: &mut game_logic::ShooterState, segment: &[f64; 4]| {
-                                    let b = [segment[2], segment[3]];
-                                    let mut res = true;
-                                    for enemy in state.enemies.iter_mut() {
-                                        let ebb = enemy.get_bb();
-                                        if ebb[0] < b[0] + 4.
-                                            && b[0] - 4. <= ebb[2]
-                                            && ebb[1] < b[1] + 4.
-                                            && b[1] - 4. <= ebb[3]
-                                        {
-                                            res = false;
-                                            break;
-                                        }
-                                    }
-                                    if !res {
-                                        add_tent(TempEntityType::Explode2, &b, &[0.; 2], state);
-                                    }
-                                    res
-                                },
-                            );
-                            let hit = length != LIGHTNING_VERTICES;
-
-                            gl.use_program(Some(&assets.trail_shader.as_ref().unwrap().program));
-                            let shader = assets.trail_shader.as_ref().unwrap();
-
-                            gl.uniform1i(shader.texture_loc.as_ref(), 0);
-                            gl.bind_texture(GL::TEXTURE_2D, Some(&assets.beam_tex));
-
-                            enable_buffer(gl, &assets.trail_buffer, 4, shader.vertex_position);
-
-                            let mut vertices = vec![];
-                            let mut prev_node_opt = None;
-
-                            state.lightning_branch(
-                                seed,
-                                length,
-                                &mut |_state, segment: &[f64; 4]| {
-                                    // line(if hit { col } else { col2 }, if hit { 2. } else { 1. }, *segment, context.transform, graphics);
-                                    let prev_node = if let Some(node) = prev_node_opt {
-                                        node
-                                    } else {
-                                        prev_node_opt = Some([segment[0], segment[1]]);
-                                        return true;
-                                    };
-                                    let width = if hit { 5. } else { 1. };
-                                    let this_node = [segment[0], segment[1]];
-                                    let delta = vec2_normalized(vec2_sub(this_node, prev_node));
-                                    let perp = vec2_scale([delta[1], -delta[0]], width);
-                                    let top = vec2_add(prev_node, perp);
-                                    let bottom = vec2_sub(prev_node, perp);
-                                    vertices.extend_from_slice(&[
-                                        top[0] as f32,
-                                        top[1] as f32,
-                                        0.,
-                                        -0.1,
-                                    ]);
-                                    vertices.extend_from_slice(&[
-                                        bottom[0] as f32,
-                                        bottom[1] as f32,
-                                        0.,
-                                        1.1,
-                                    ]);
-                                    prev_node_opt = Some([segment[0], segment[1]]);
-                                    true
-                                },
-                            );
-
-                            vertex_buffer_data(gl, &vertices);
-
-                            let shader = assets.trail_shader.as_ref().unwrap();
-                            gl.uniform_matrix4fv_with_f32_array(
-                                shader.transform_loc.as_ref(),
-                                false,
-                                <Matrix4<f32> as AsRef<[f32; 16]>>::as_ref(
-                                    &assets.world_transform.cast().unwrap(),
-                                ),
-                            );
-
-                            gl.uniform_matrix3fv_with_f32_array(
-                                shader.tex_transform_loc.as_ref(),
-                                false,
-                                <Matrix3<f32> as AsRef<[f32; 9]>>::as_ref(&Matrix3::from_scale(1.)),
-                            );
-
-                            gl.draw_arrays(GL::TRIANGLE_STRIP, 0, (vertices.len() / 4) as i32);
-
-                            enable_buffer(
-                                gl,
-                                &assets.rect_buffer,
-                                2,
-                                assets.sprite_shader.as_ref().unwrap().vertex_position,
-                            );
-                        },
-                    );
+            for branch in &self.state.lightning_branches {
+                let width = if branch.hit { 5. } else { 1. };
+                let mut vertices = vec![];
+                for nodes in branch.vertices.windows(2) {
+                    let (prev_node, this_node) = (nodes[0], nodes[1]);
+                    let delta = vec2_normalized(vec2_sub(this_node, prev_node));
+                    let perp = vec2_scale([delta[1], -delta[0]], width);
+                    let top = vec2_add(prev_node, perp);
+                    let bottom = vec2_sub(prev_node, perp);
+                    vertices.extend_from_slice(&[top[0] as f32, top[1] as f32, 0., -0.1]);
+                    vertices.extend_from_slice(&[bottom[0] as f32, bottom[1] as f32, 0., 1.1]);
                 }
-            }
-            if self.state.player.cooldown < 1 {
-                self.state.player.cooldown = 0;
-            } else {
-                self.state.player.cooldown -= 1;
-            }
 
-            if 0 < self.state.player.invtime {
-                self.state.player.invtime -= 1;
+                vertex_buffer_data(gl, &vertices);
+
+                gl.draw_arrays(GL::TRIANGLE_STRIP, 0, (vertices.len() / 4) as i32);
             }
+
+            enable_buffer(
+                gl,
+                &assets.rect_buffer,
+                2,
+                assets.sprite_shader.as_ref().unwrap().vertex_position,
+            );
         }
 
         context.use_program(Some(&self.assets.sprite_shader.as_ref().unwrap().program));
@@ -537,42 +561,12 @@ impl ShooterState {
 
         self.state.draw_items(&context, &self.assets);
 
-        self.state.animate_items();
-
         self.state.draw_enemies(&context, &self.assets);
 
-        const BLOOD_SPEED: f64 = 2.;
-
-        self.state.animate_enemies(&mut |enemy, state| {
-            let blood_count = match enemy {
-                Enemy::Boss(_) | Enemy::SpiralEnemy(_) => 15,
-                _ => 5,
-            };
-            for _i in 0..blood_count {
-                let velo = [
-                    state.rng.gen_rangef(-BLOOD_SPEED, BLOOD_SPEED),
-                    state.rng.gen_rangef(-BLOOD_SPEED, BLOOD_SPEED),
-                ];
-                add_tent(TT::Blood, &enemy.pos, &velo, state);
-            }
-        });
-
         self.state.draw_bullets(&context, &self.assets);
 
-        if self
-            .state
-            .animate_bullets(&mut |ty, pos, state| add_tent(ty, pos, &[0.; 2], state))
-        {
-            let game_over_elem = document()
-                .get_element_by_id("gameOver")
-                .ok_or_else(|| js_str!("game over elem not found"))?;
-            game_over_elem.set_class_name("");
-        };
-
         self.state.draw_tents(&context, &self.assets);
 
-        self.state.animate_tents();
-
         load_identity(self);
 
         if !self.state.game_over && (self.state.player.invtime == 0 || self.state.disptime % 2 == 0)
@@ -583,6 +577,14 @@ impl ShooterState {
                 &self.assets.player_texture,
                 Some([PLAYER_SIZE; 2]),
             );
+            if self.state.player.focus {
+                self.state.player.base.draw_tex(
+                    &self.assets,
+                    &context,
+                    &self.assets.hitbox_tex,
+                    Some([4.; 2]),
+                );
+            }
         }
 
         fn set_text(id: &str, text: &str) {
@@ -590,6 +592,7 @@ impl ShooterState {
             frame_element.set_inner_html(text);
         }
 
+        set_text("seed", &format!("Seed: {}", self.state.seed));
         set_text("frame", &format!("Frame: {}", self.state.time));
         set_text("score", &format!("Score: {}", self.state.player.score));
         set_text("kills", &format!("Kills: {}", self.state.player.kills));
@@ -601,11 +604,21 @@ impl ShooterState {
                 self.state.player.power_level()
             ),
         );
+        set_text("bombs", &format!("Bombs: {}", self.state.player.bombs));
+        set_text(
+            "chain",
+            &format!(
+                "Multiplier: x{} Chain: {} Graze: {}",
+                self.state.player.multiplier(),
+                self.state.player.chain,
+                self.state.player.graze
+            ),
+        );
         set_text(
             "waves",
             &format!(
                 "Wave: {} Level: {}",
-                self.state.time / wave_period,
+                self.state.time / self.state.wave_period(),
                 self.state.player.difficulty_level()
             ),
         );
@@ -621,6 +634,35 @@ impl ShooterState {
             &format!("Weapon: {:#?}", self.state.player.weapon),
         );
 
+        let player_health_element = document()
+            .get_element_by_id("playerHealth")
+            .ok_or_else(|| js_str!("playerHealth elem not found"))?;
+        if self.state.hp_mode {
+            player_health_element.set_class_name("");
+            document()
+                .get_element_by_id("playerHealthBar")
+                .ok_or_else(|| js_str!("playerHealthBar elem not found"))?
+                .set_attribute(
+                    "style",
+                    &format!("width: {}%", self.state.player.health_ratio() * 100.),
+                )?;
+        } else {
+            player_health_element.set_class_name("hidden");
+        }
+
+        let boss_health_element = document()
+            .get_element_by_id("bossHealth")
+            .ok_or_else(|| js_str!("bossHealth elem not found"))?;
+        if let Some(boss_health) = self.state.boss_health() {
+            boss_health_element.set_class_name("");
+            document()
+                .get_element_by_id("bossHealthBar")
+                .ok_or_else(|| js_str!("bossHealthBar elem not found"))?
+                .set_attribute("style", &format!("width: {}%", boss_health * 100.))?;
+        } else {
+            boss_health_element.set_class_name("hidden");
+        }
+
         Ok(())
     }
 }